@@ -70,6 +70,8 @@ pub struct Enigo {
     xdo: Xdo,
     delay: u64,
     tx: mpsc::Sender<(PyMsg, bool)>,
+    custom_keyboard: Option<Box<dyn KeyboardControllable + Send>>,
+    custom_mouse: Option<Box<dyn MouseControllable + Send>>,
 }
 // This is safe, we have a unique pointer.
 // TODO: use Unique<c_char> once stable.
@@ -84,6 +86,8 @@ impl Default for Enigo {
             xdo: unsafe { xdo_new(ptr::null()) },
             delay: DEFAULT_DELAY,
             tx,
+            custom_keyboard: None,
+            custom_mouse: None,
         }
     }
 }
@@ -99,6 +103,16 @@ impl Enigo {
     pub fn set_delay(&mut self, delay: u64) {
         self.delay = delay;
     }
+    /// Inject the keyboard input with another backend than xdo, e.g. on Wayland.
+    /// This is Linux-specific.
+    pub fn set_custom_keyboard(&mut self, custom: Box<dyn KeyboardControllable + Send>) {
+        self.custom_keyboard = Some(custom);
+    }
+    /// Inject the mouse input with another backend than xdo, e.g. on Wayland.
+    /// This is Linux-specific.
+    pub fn set_custom_mouse(&mut self, custom: Box<dyn MouseControllable + Send>) {
+        self.custom_mouse = Some(custom);
+    }
     ///
     pub fn reset(&mut self) {
         self.tx.send((PyMsg::Char('\0'), true)).ok();
@@ -205,6 +219,9 @@ impl Drop for Enigo {
 }
 impl MouseControllable for Enigo {
     fn mouse_move_to(&mut self, x: i32, y: i32) {
+        if let Some(custom) = self.custom_mouse.as_mut() {
+            return custom.mouse_move_to(x, y);
+        }
         if self.xdo.is_null() {
             return;
         }
@@ -213,6 +230,9 @@ impl MouseControllable for Enigo {
         }
     }
     fn mouse_move_relative(&mut self, x: i32, y: i32) {
+        if let Some(custom) = self.custom_mouse.as_mut() {
+            return custom.mouse_move_relative(x, y);
+        }
         if self.xdo.is_null() {
             return;
         }
@@ -221,6 +241,9 @@ impl MouseControllable for Enigo {
         }
     }
     fn mouse_down(&mut self, button: MouseButton) -> crate::ResultType {
+        if let Some(custom) = self.custom_mouse.as_mut() {
+            return custom.mouse_down(button);
+        }
         if self.xdo.is_null() {
             return Ok(());
         }
@@ -230,6 +253,9 @@ impl MouseControllable for Enigo {
         Ok(())
     }
     fn mouse_up(&mut self, button: MouseButton) {
+        if let Some(custom) = self.custom_mouse.as_mut() {
+            return custom.mouse_up(button);
+        }
         if self.xdo.is_null() {
             return;
         }
@@ -238,6 +264,9 @@ impl MouseControllable for Enigo {
         }
     }
     fn mouse_click(&mut self, button: MouseButton) {
+        if let Some(custom) = self.custom_mouse.as_mut() {
+            return custom.mouse_click(button);
+        }
         if self.xdo.is_null() {
             return;
         }
@@ -246,6 +275,9 @@ impl MouseControllable for Enigo {
         }
     }
     fn mouse_scroll_x(&mut self, length: i32) {
+        if let Some(custom) = self.custom_mouse.as_mut() {
+            return custom.mouse_scroll_x(length);
+        }
         let button;
         let mut length = length;
 
@@ -264,6 +296,9 @@ impl MouseControllable for Enigo {
         }
     }
     fn mouse_scroll_y(&mut self, length: i32) {
+        if let Some(custom) = self.custom_mouse.as_mut() {
+            return custom.mouse_scroll_y(length);
+        }
         let button;
         let mut length = length;
 
@@ -380,6 +415,9 @@ fn keysequence<'a>(key: Key) -> Cow<'a, str> {
 }
 impl KeyboardControllable for Enigo {
     fn get_key_state(&mut self, key: Key) -> bool {
+        if let Some(custom) = self.custom_keyboard.as_mut() {
+            return custom.get_key_state(key);
+        }
         if self.xdo.is_null() {
             return false;
         }
@@ -413,6 +451,9 @@ impl KeyboardControllable for Enigo {
     }
 
     fn key_sequence(&mut self, sequence: &str) {
+        if let Some(custom) = self.custom_keyboard.as_mut() {
+            return custom.key_sequence(sequence);
+        }
         if self.xdo.is_null() {
             return;
         }
@@ -428,6 +469,9 @@ impl KeyboardControllable for Enigo {
         }
     }
     fn key_down(&mut self, key: Key) -> crate::ResultType {
+        if let Some(custom) = self.custom_keyboard.as_mut() {
+            return custom.key_down(key);
+        }
         if self.xdo.is_null() {
             return Ok(());
         }
@@ -446,6 +490,9 @@ impl KeyboardControllable for Enigo {
         Ok(())
     }
     fn key_up(&mut self, key: Key) {
+        if let Some(custom) = self.custom_keyboard.as_mut() {
+            return custom.key_up(key);
+        }
         if self.xdo.is_null() {
             return;
         }
@@ -464,6 +511,9 @@ impl KeyboardControllable for Enigo {
         }
     }
     fn key_click(&mut self, key: Key) {
+        if let Some(custom) = self.custom_keyboard.as_mut() {
+            return custom.key_click(key);
+        }
         if self.xdo.is_null() {
             return;
         }
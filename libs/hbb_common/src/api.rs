@@ -16,7 +16,12 @@ pub async fn call_api() -> Result<serde_json::Value, ApiError> {
     lazy_static! {
         static ref RESPONSE: AsyncOnce<Result<serde_json::Value, ApiError>> =
             AsyncOnce::new(async {
-                let body = reqwest::get(API_URI).await?.text().await?;
+                let body = crate::socket_client::new_reqwest_client(API_URI)?
+                    .get(API_URI)
+                    .send()
+                    .await?
+                    .text()
+                    .await?;
                 let body = serde_json::from_str(&body)?;
 
                 Ok(body)
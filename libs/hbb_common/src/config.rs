@@ -55,6 +55,7 @@ pub const RENDEZVOUS_PORT: i32 = 21116;
 pub enum NetworkType {
     Direct,
     ProxySocks,
+    ProxyHttp,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
@@ -71,6 +72,65 @@ pub struct Config {
     key_confirmed: bool,
     #[serde(default)]
     keys_confirmed: HashMap<String, bool>,
+    #[serde(default)]
+    password_profiles: Vec<PasswordProfile>,
+    #[serde(default)]
+    one_time_passwords: Vec<OneTimePassword>,
+}
+
+// An extra named password, granting only the permissions set here
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PasswordProfile {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub keyboard: bool,
+    #[serde(default)]
+    pub clipboard: bool,
+    #[serde(default)]
+    pub audio: bool,
+    #[serde(default)]
+    pub file: bool,
+    #[serde(default)]
+    pub tunnel: bool,
+    #[serde(default)]
+    pub restart: bool,
+    #[serde(default)]
+    pub view_only: bool,
+    // unix timestamp in seconds, 0 for never
+    #[serde(default)]
+    pub expire: i64,
+}
+
+impl PasswordProfile {
+    pub fn is_expired(&self) -> bool {
+        self.expire > 0 && get_time_secs() >= self.expire
+    }
+}
+
+// Gone after its first use
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct OneTimePassword {
+    #[serde(default)]
+    pub password: String,
+    // unix timestamp in seconds, 0 for never
+    #[serde(default)]
+    pub expire: i64,
+}
+
+impl OneTimePassword {
+    pub fn is_expired(&self) -> bool {
+        self.expire > 0 && get_time_secs() >= self.expire
+    }
+}
+
+fn get_time_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0)
 }
 
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
@@ -83,6 +143,17 @@ pub struct Socks5Server {
     pub password: String,
 }
 
+// HTTP proxy tunneling tcp with CONNECT, proxy is host:port or http://host:port
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct HttpProxyServer {
+    #[serde(default)]
+    pub proxy: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+}
+
 // more variable configs
 #[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Config2 {
@@ -99,6 +170,8 @@ pub struct Config2 {
 
     #[serde(default)]
     socks: Option<Socks5Server>,
+    #[serde(default)]
+    http_proxy: Option<HttpProxyServer>,
 
     // the other scalar value must before this
     #[serde(default)]
@@ -657,6 +730,67 @@ impl Config {
         password
     }
 
+    pub fn set_password_profiles(profiles: Vec<PasswordProfile>) {
+        let mut config = CONFIG.write().unwrap();
+        if profiles == config.password_profiles {
+            return;
+        }
+        config.password_profiles = profiles;
+        config.store();
+    }
+
+    pub fn get_password_profiles() -> Vec<PasswordProfile> {
+        CONFIG.read().unwrap().password_profiles.clone()
+    }
+
+    /// New one-time password, valid for `minutes` if not 0
+    pub fn add_one_time_password(minutes: u32) -> String {
+        let password = Config::get_auto_password();
+        let expire = if minutes > 0 {
+            get_time_secs() + minutes as i64 * 60
+        } else {
+            0
+        };
+        let mut config = CONFIG.write().unwrap();
+        config.one_time_passwords.retain(|x| !x.is_expired());
+        config.one_time_passwords.push(OneTimePassword {
+            password: password.clone(),
+            expire,
+        });
+        config.store();
+        password
+    }
+
+    /// The first valid one-time password accepted by `f`, without consuming it.
+    pub fn find_one_time_password(f: impl Fn(&str) -> bool) -> Option<String> {
+        CONFIG
+            .read()
+            .unwrap()
+            .one_time_passwords
+            .iter()
+            .find(|x| !x.is_expired() && f(&x.password))
+            .map(|x| x.password.clone())
+    }
+
+    /// Consume the first valid one-time password accepted by `f`, done under the config lock
+    /// so that it can not be used twice.
+    pub fn take_one_time_password(f: impl Fn(&str) -> bool) -> bool {
+        let mut config = CONFIG.write().unwrap();
+        let n = config.one_time_passwords.len();
+        config.one_time_passwords.retain(|x| !x.is_expired());
+        let found = config
+            .one_time_passwords
+            .iter()
+            .position(|x| f(&x.password));
+        if let Some(i) = found {
+            config.one_time_passwords.remove(i);
+        }
+        if found.is_some() || n != config.one_time_passwords.len() {
+            config.store();
+        }
+        found.is_some()
+    }
+
     pub fn set_salt(salt: &str) {
         let mut config = CONFIG.write().unwrap();
         if salt == config.salt {
@@ -688,10 +822,27 @@ impl Config {
         CONFIG2.read().unwrap().socks.clone()
     }
 
+    pub fn set_http_proxy(http_proxy: Option<HttpProxyServer>) {
+        let mut config = CONFIG2.write().unwrap();
+        if config.http_proxy == http_proxy {
+            return;
+        }
+        config.http_proxy = http_proxy;
+        config.store();
+    }
+
+    pub fn get_http_proxy() -> Option<HttpProxyServer> {
+        CONFIG2.read().unwrap().http_proxy.clone()
+    }
+
     pub fn get_network_type() -> NetworkType {
-        match &CONFIG2.read().unwrap().socks {
-            None => NetworkType::Direct,
-            Some(_) => NetworkType::ProxySocks,
+        let config = CONFIG2.read().unwrap();
+        if config.socks.is_some() {
+            NetworkType::ProxySocks
+        } else if config.http_proxy.is_some() {
+            NetworkType::ProxyHttp
+        } else {
+            NetworkType::Direct
         }
     }
 
@@ -902,6 +1053,45 @@ impl LanPeers {
     }
 }
 
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LoginFailure {
+    // wrong passwords since the last lockout
+    #[serde(default)]
+    pub failures: u32,
+    #[serde(default)]
+    pub lockouts: u32,
+    // in ms
+    #[serde(default)]
+    pub last_failure: i64,
+    #[serde(default)]
+    pub locked_until: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct LoginFailures {
+    #[serde(default)]
+    pub failures: HashMap<String, LoginFailure>,
+}
+
+impl LoginFailures {
+    pub fn load() -> LoginFailures {
+        let _ = CONFIG.read().unwrap(); // for lock
+        match confy::load_path(&Config::file_("_login_failures")) {
+            Ok(failures) => failures,
+            Err(err) => {
+                log::error!("Failed to load login failures: {}", err);
+                Default::default()
+            }
+        }
+    }
+
+    pub fn store(&self) {
+        if let Err(err) = confy::store_path(Config::file_("_login_failures"), self) {
+            log::error!("Failed to store login failures: {}", err);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -914,4 +1104,17 @@ mod tests {
         let res = toml::to_string_pretty(&cfg);
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn test_password_profile() {
+        // a permission not set is not granted, restart included
+        let p: PasswordProfile =
+            serde_json::from_str(r#"{"name":"helpdesk","password":"x","keyboard":true}"#).unwrap();
+        assert!(p.keyboard);
+        assert!(!p.clipboard && !p.audio && !p.file && !p.tunnel && !p.restart);
+        assert!(!p.is_expired());
+        let p: PasswordProfile = toml::from_str("name = 'admin'\nrestart = true").unwrap();
+        assert!(p.restart);
+        assert!(!p.keyboard);
+    }
 }
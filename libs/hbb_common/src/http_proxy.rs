@@ -0,0 +1,127 @@
+// https://datatracker.ietf.org/doc/html/rfc7231#section-4.3.6
+// https://datatracker.ietf.org/doc/html/rfc7617
+
+use crate::{bail, config::HttpProxyServer, ResultType};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_socks::TargetAddr;
+
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+pub fn target_to_string(target: &TargetAddr) -> String {
+    match target {
+        TargetAddr::Ip(addr) => addr.to_string(),
+        TargetAddr::Domain(host, port) => format!("{}:{}", host, port),
+    }
+}
+
+// host:port of the proxy, port 80 if not given
+pub fn get_proxy_addr(proxy: &str) -> ResultType<String> {
+    let proxy = proxy.trim();
+    if proxy.starts_with("https://") {
+        bail!("TLS connection to the proxy is not supported, please use http://");
+    }
+    let proxy = proxy.trim_start_matches("http://").trim_end_matches('/');
+    if proxy.is_empty() {
+        bail!("Empty proxy address");
+    }
+    if proxy.rsplit(':').next().map(|x| x.parse::<u16>().is_ok()) == Some(true)
+        && !proxy.ends_with(']')
+    {
+        Ok(proxy.to_owned())
+    } else {
+        Ok(format!("{}:80", proxy))
+    }
+}
+
+/// Connect to `target` (host:port) through the HTTP proxy.
+pub async fn connect(
+    conf: &HttpProxyServer,
+    target: &str,
+    ms_timeout: u64,
+) -> ResultType<TcpStream> {
+    let stream = super::timeout(ms_timeout, TcpStream::connect(get_proxy_addr(&conf.proxy)?))
+        .await??;
+    stream.set_nodelay(true).ok();
+    handshake(stream, conf, target, ms_timeout).await
+}
+
+/// Send CONNECT on an established connection to the proxy.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    conf: &HttpProxyServer,
+    target: &str,
+    ms_timeout: u64,
+) -> ResultType<S> {
+    let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if !conf.username.is_empty() {
+        let auth = base64::encode(format!("{}:{}", conf.username, conf.password));
+        req += &format!("Proxy-Authorization: Basic {}\r\n", auth);
+    }
+    req += "\r\n";
+    super::timeout(ms_timeout, stream.write_all(req.as_bytes())).await??;
+    let head = super::timeout(ms_timeout, read_response_head(&mut stream)).await??;
+    let status = head.lines().next().unwrap_or_default().to_owned();
+    // HTTP/1.1 200 Connection established
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("HTTP proxy failed to connect {}: {}", target, status);
+    }
+    Ok(stream)
+}
+
+// read byte by byte, so that nothing of the tunneled data is consumed
+async fn read_response_head<S: AsyncRead + Unpin>(stream: &mut S) -> ResultType<String> {
+    let mut buf = Vec::new();
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() > MAX_RESPONSE_SIZE {
+            bail!("HTTP proxy response too large");
+        }
+        let b = stream.read_u8().await?;
+        buf.push(b);
+    }
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_proxy_addr() {
+        assert_eq!(get_proxy_addr("proxy.local:3128").unwrap(), "proxy.local:3128");
+        assert_eq!(get_proxy_addr("http://proxy.local:8080/").unwrap(), "proxy.local:8080");
+        assert_eq!(get_proxy_addr("proxy.local").unwrap(), "proxy.local:80");
+        assert_eq!(get_proxy_addr("[::1]").unwrap(), "[::1]:80");
+        assert_eq!(get_proxy_addr("[::1]:3128").unwrap(), "[::1]:3128");
+        assert!(get_proxy_addr("https://proxy.local").is_err());
+        assert!(get_proxy_addr("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let conf = HttpProxyServer {
+            proxy: "proxy.local".to_owned(),
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+        };
+        let proxy = tokio::spawn(async move {
+            let head = read_response_head(&mut server).await.unwrap();
+            assert!(head.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+            assert!(head.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\ndata")
+                .await
+                .unwrap();
+        });
+        let mut stream = handshake(client, &conf, "example.com:443", 1000)
+            .await
+            .unwrap();
+        proxy.await.unwrap();
+        let mut data = [0u8; 4];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"data");
+    }
+}
@@ -17,6 +17,7 @@ use std::{
 pub use tokio;
 pub use tokio_util;
 pub mod socket_client;
+pub mod http_proxy;
 pub mod tcp;
 pub mod udp;
 pub use env_logger;
@@ -25,6 +25,7 @@ pub struct FramedStream(
     SocketAddr,
     Option<(Key, u64, u64)>,
     u64,
+    (u64, u64), // bytes sent and received
 );
 
 impl Deref for FramedStream {
@@ -92,6 +93,7 @@ impl FramedStream {
                     addr,
                     None,
                     0,
+                    (0, 0),
                 ));
             }
         }
@@ -137,6 +139,7 @@ impl FramedStream {
                     addr,
                     None,
                     0,
+                    (0, 0),
                 ));
             };
         };
@@ -151,12 +154,25 @@ impl FramedStream {
         self.3 = ms;
     }
 
+    /// Payload bytes sent so far, after encryption
+    #[inline]
+    pub fn bytes_sent(&self) -> u64 {
+        (self.4).0
+    }
+
+    /// Payload bytes received so far, before decryption
+    #[inline]
+    pub fn bytes_received(&self) -> u64 {
+        (self.4).1
+    }
+
     pub fn from(stream: impl TcpStreamTrait + Send + Sync + 'static, addr: SocketAddr) -> Self {
         Self(
             Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
             addr,
             None,
             0,
+            (0, 0),
         )
     }
 
@@ -188,6 +204,7 @@ impl FramedStream {
 
     #[inline]
     pub async fn send_bytes(&mut self, bytes: Bytes) -> ResultType<()> {
+        (self.4).0 += bytes.len() as u64;
         if self.3 > 0 {
             super::timeout(self.3, self.0.send(bytes)).await??;
         } else {
@@ -199,6 +216,9 @@ impl FramedStream {
     #[inline]
     pub async fn next(&mut self) -> Option<Result<BytesMut, Error>> {
         let mut res = self.0.next().await;
+        if let Some(Ok(bytes)) = res.as_ref() {
+            (self.4).1 += bytes.len() as u64;
+        }
         if let Some(key) = self.2.as_mut() {
             if let Some(Ok(bytes)) = res.as_mut() {
                 key.2 += 1;
@@ -10,6 +10,10 @@ use std::{ptr, slice};
 pub enum VideoCodecId {
     VP8,
     VP9,
+    // negotiated, but no encoder or decoder is built for it yet
+    AV1,
+    // negotiated, but no encoder or decoder is built for it yet
+    H264,
 }
 
 impl Default for VideoCodecId {
@@ -20,8 +24,13 @@ impl Default for VideoCodecId {
 
 pub struct Encoder {
     ctx: vpx_codec_ctx_t,
+    // kept for live changes, libvpx does not own it
+    cfg: vpx_codec_enc_cfg_t,
     width: usize,
     height: usize,
+    force_key_frame: bool,
+    yuv444: bool,
+    lossless: bool,
 }
 
 pub struct Decoder {
@@ -88,7 +97,7 @@ impl Encoder {
         if cfg!(feature = "VP8") {
             i = match config.codec {
                 VideoCodecId::VP8 => call_vpx_ptr!(vpx_codec_vp8_cx()),
-                VideoCodecId::VP9 => call_vpx_ptr!(vpx_codec_vp9_cx()),
+                _ => call_vpx_ptr!(vpx_codec_vp9_cx()),
             };
         } else {
             i = call_vpx_ptr!(vpx_codec_vp9_cx());
@@ -102,6 +111,10 @@ impl Encoder {
 
         c.g_w = config.width;
         c.g_h = config.height;
+        if config.yuv444 {
+            // profile 1 is 8 bit 4:4:4
+            c.g_profile = 1;
+        }
         c.g_timebase.num = config.timebase[0];
         c.g_timebase.den = config.timebase[1];
         c.rc_target_bitrate = config.bitrate;
@@ -146,7 +159,7 @@ impl Encoder {
             VPX_ENCODER_ABI_VERSION as _
         ));
 
-        if config.codec == VideoCodecId::VP9 {
+        if config.codec != VideoCodecId::VP8 {
             // set encoder internal speed settings
             // in ffmpeg, it is --speed option
             /*
@@ -189,30 +202,123 @@ impl Encoder {
 
         Ok(Self {
             ctx,
+            cfg: c,
             width: config.width as _,
             height: config.height as _,
+            force_key_frame: false,
+            yuv444: config.yuv444,
+            lossless: false,
         })
     }
 
+    /// Change the target bitrate (kbps) and the quantizer range without recreating the encoder,
+    /// a quantizer of 0 keeps the current one
+    pub fn set_bitrate(
+        &mut self,
+        bitrate: u32,
+        rc_min_quantizer: u32,
+        rc_max_quantizer: u32,
+    ) -> Result<()> {
+        let mut cfg = self.cfg;
+        cfg.rc_target_bitrate = bitrate;
+        if rc_min_quantizer > 0 {
+            cfg.rc_min_quantizer = rc_min_quantizer;
+        }
+        if rc_max_quantizer > 0 {
+            cfg.rc_max_quantizer = rc_max_quantizer;
+        }
+        if cfg.rc_min_quantizer > cfg.rc_max_quantizer {
+            cfg.rc_min_quantizer = cfg.rc_max_quantizer;
+        }
+        call_vpx!(vpx_codec_enc_config_set(&mut self.ctx, &cfg));
+        self.cfg = cfg;
+        Ok(())
+    }
+
+    /// Make the next encoded frame a keyframe, e.g. after frames were dropped
+    #[inline]
+    pub fn set_key_frame(&mut self) {
+        self.force_key_frame = true;
+    }
+
+    /// Encode the next frames without loss, VP9 only
+    pub fn set_lossless(&mut self, lossless: bool) -> Result<()> {
+        if lossless != self.lossless {
+            call_vpx!(vpx_codec_control_(
+                &mut self.ctx,
+                VP9E_SET_LOSSLESS as _,
+                lossless as c_uint
+            ));
+            self.lossless = lossless;
+        }
+        Ok(())
+    }
+
+    /// Encode only the macroblocks in `rects` from the next frame on, the others are kept
+    /// from the previous frame. None to encode them all again.
+    pub fn set_active_rects(&mut self, rects: Option<&[super::Rect]>) -> Result<()> {
+        let rows = (self.height + 15) / 16;
+        let cols = (self.width + 15) / 16;
+        let mut map = Vec::new();
+        if let Some(rects) = rects {
+            map.resize(rows * cols, 0u8);
+            for r in rects.iter().filter(|r| r.w > 0 && r.h > 0) {
+                for row in r.y / 16..=std::cmp::min((r.y + r.h - 1) / 16, rows - 1) {
+                    for col in r.x / 16..=std::cmp::min((r.x + r.w - 1) / 16, cols - 1) {
+                        map[row * cols + col] = 1;
+                    }
+                }
+            }
+        }
+        let mut active_map = vpx_active_map_t {
+            // null turns the map off
+            active_map: if rects.is_some() {
+                map.as_mut_ptr()
+            } else {
+                ptr::null_mut()
+            },
+            rows: rows as _,
+            cols: cols as _,
+        };
+        call_vpx!(vpx_codec_control_(
+            &mut self.ctx,
+            VP8E_SET_ACTIVEMAP as _,
+            &mut active_map as *mut vpx_active_map_t,
+        ));
+        Ok(())
+    }
+
     pub fn encode(&mut self, pts: i64, data: &[u8], stride_align: usize) -> Result<EncodeFrames> {
-        assert!(2 * data.len() >= 3 * self.width * self.height);
+        let fmt = if self.yuv444 {
+            assert!(data.len() >= 3 * self.width * self.height);
+            vpx_img_fmt::VPX_IMG_FMT_I444
+        } else {
+            assert!(2 * data.len() >= 3 * self.width * self.height);
+            vpx_img_fmt::VPX_IMG_FMT_I420
+        };
 
         let mut image = Default::default();
         call_vpx_ptr!(vpx_img_wrap(
             &mut image,
-            vpx_img_fmt::VPX_IMG_FMT_I420,
+            fmt,
             self.width as _,
             self.height as _,
             stride_align as _,
             data.as_ptr() as _,
         ));
 
+        let flags = if self.force_key_frame {
+            self.force_key_frame = false;
+            VPX_EFLAG_FORCE_KF
+        } else {
+            0
+        };
         call_vpx!(vpx_codec_encode(
             &mut self.ctx,
             &image,
             pts as _,
-            1, // Duration
-            0, // Flags
+            1,          // Duration
+            flags as _, // Flags
             VPX_DL_REALTIME as _,
         ));
 
@@ -276,6 +382,8 @@ pub struct Config {
     pub rc_min_quantizer: u32,
     pub rc_max_quantizer: u32,
     pub speed: i32,
+    /// I444 input made by `bgra_to_i444` instead of I420, VP9 only
+    pub yuv444: bool,
 }
 
 pub struct EncodeFrames<'a> {
@@ -320,7 +428,7 @@ impl Decoder {
         if cfg!(feature = "VP8") {
             i = match codec {
                 VideoCodecId::VP8 => call_vpx_ptr!(vpx_codec_vp8_dx()),
-                VideoCodecId::VP9 => call_vpx_ptr!(vpx_codec_vp9_dx()),
+                _ => call_vpx_ptr!(vpx_codec_vp9_dx()),
             };
         } else {
             i = call_vpx_ptr!(vpx_codec_vp9_dx());
@@ -458,7 +566,7 @@ impl Image {
 
     #[inline]
     pub fn format(&self) -> vpx_img_fmt_t {
-        // VPX_IMG_FMT_I420
+        // VPX_IMG_FMT_I420, or VPX_IMG_FMT_I444 from profile 1
         self.inner().fmt
     }
 
@@ -479,6 +587,10 @@ impl Image {
         w = (w + stride_align - 1) & !(stride_align - 1);
         dst.resize(h * w * bps, 0);
         let img = self.inner();
+        if self.format() == vpx_img_fmt::VPX_IMG_FMT_I444 {
+            self.rgb_444(w, rgba, dst);
+            return;
+        }
         unsafe {
             if rgba {
                 super::I420ToARGB(
@@ -510,6 +622,38 @@ impl Image {
         }
     }
 
+    fn rgb_444(&self, w: usize, rgba: bool, dst: &mut Vec<u8>) {
+        let img = self.inner();
+        let mut argb = Vec::new();
+        // libyuv has no I444ToRAW, go through ARGB
+        let out = if rgba { &mut *dst } else { &mut argb };
+        out.resize(self.height() * w * 4, 0);
+        unsafe {
+            super::I444ToARGB(
+                img.planes[0],
+                img.stride[0],
+                img.planes[1],
+                img.stride[1],
+                img.planes[2],
+                img.stride[2],
+                out.as_mut_ptr(),
+                (w * 4) as _,
+                self.width() as _,
+                self.height() as _,
+            );
+            if !rgba {
+                super::ARGBToRAW(
+                    argb.as_ptr(),
+                    (w * 4) as _,
+                    dst.as_mut_ptr(),
+                    (w * 3) as _,
+                    self.width() as _,
+                    self.height() as _,
+                );
+            }
+        }
+    }
+
     #[inline]
     pub fn data(&self) -> (&[u8], &[u8], &[u8]) {
         unsafe {
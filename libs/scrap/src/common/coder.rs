@@ -0,0 +1,163 @@
+// The video codecs behind one encoder and one decoder, only libvpx for now.
+// AV1 and H.264 are already negotiated, but no build supports them yet.
+
+use super::codec::{Config, Decoder, Encoder, Image, Result, VideoCodecId};
+use super::Rect;
+
+/// An encoded frame of any codec
+#[derive(Debug, Clone)]
+pub struct EncodedFrame {
+    pub data: Vec<u8>,
+    pub key: bool,
+    pub pts: i64,
+}
+
+/// Whether this build can encode and decode `codec`
+pub fn is_codec_supported(codec: VideoCodecId) -> bool {
+    match codec {
+        VideoCodecId::VP9 => true,
+        VideoCodecId::VP8 => cfg!(feature = "VP8"),
+        VideoCodecId::AV1 | VideoCodecId::H264 => false,
+    }
+}
+
+pub enum VideoEncoder {
+    VPX(Encoder, VideoCodecId),
+}
+
+impl VideoEncoder {
+    /// `config.codec` falls back to VP9 if not supported, or for `config.yuv444`
+    pub fn new(config: &Config, num_threads: u32) -> Result<Self> {
+        Ok(match config.codec {
+            VideoCodecId::VP8 if cfg!(feature = "VP8") && !config.yuv444 => {
+                Self::VPX(Encoder::new(config, num_threads)?, VideoCodecId::VP8)
+            }
+            _ => {
+                let mut config = *config;
+                config.codec = VideoCodecId::VP9;
+                Self::VPX(Encoder::new(&config, num_threads)?, VideoCodecId::VP9)
+            }
+        })
+    }
+
+    pub fn codec(&self) -> VideoCodecId {
+        match self {
+            Self::VPX(_, codec) => *codec,
+        }
+    }
+
+    pub fn set_key_frame(&mut self) {
+        match self {
+            Self::VPX(e, _) => e.set_key_frame(),
+        }
+    }
+
+    /// The quantizers are in the vpx range, 0 to 63, 0 keeps the current one
+    pub fn set_bitrate(
+        &mut self,
+        bitrate: u32,
+        rc_min_quantizer: u32,
+        rc_max_quantizer: u32,
+    ) -> Result<()> {
+        match self {
+            Self::VPX(e, _) => e.set_bitrate(bitrate, rc_min_quantizer, rc_max_quantizer)?,
+        }
+        Ok(())
+    }
+
+    /// Only VP9 has a lossless mode, the others stay lossy
+    pub fn set_lossless(&mut self, lossless: bool) -> Result<()> {
+        match self {
+            Self::VPX(e, VideoCodecId::VP9) => e.set_lossless(lossless),
+            _ => Ok(()),
+        }
+    }
+
+    /// Only libvpx can skip the unchanged macroblocks, the others encode the whole frame
+    pub fn set_active_rects(&mut self, rects: Option<&[Rect]>) -> Result<()> {
+        match self {
+            Self::VPX(e, _) => e.set_active_rects(rects),
+            #[allow(unreachable_patterns)]
+            _ => Ok(()),
+        }
+    }
+
+    /// Encode an I420 frame laid out by `bgra_to_i420`, the output of libvpx flushed
+    pub fn encode(
+        &mut self,
+        pts: i64,
+        data: &[u8],
+        stride_align: usize,
+    ) -> Result<Vec<EncodedFrame>> {
+        match self {
+            Self::VPX(e, _) => {
+                let mut frames = Vec::new();
+                for frame in e.encode(pts, data, stride_align)? {
+                    frames.push(EncodedFrame {
+                        data: frame.data.to_vec(),
+                        key: frame.key,
+                        pts: frame.pts,
+                    });
+                }
+                for frame in e.flush()? {
+                    frames.push(EncodedFrame {
+                        data: frame.data.to_vec(),
+                        key: frame.key,
+                        pts: frame.pts,
+                    });
+                }
+                Ok(frames)
+            }
+        }
+    }
+}
+
+pub enum VideoDecoder {
+    VPX(Decoder, VideoCodecId),
+}
+
+impl VideoDecoder {
+    /// `codec` falls back to VP9 if not supported
+    pub fn new(codec: VideoCodecId, num_threads: u32) -> Result<Self> {
+        Ok(match codec {
+            VideoCodecId::VP8 if cfg!(feature = "VP8") => {
+                Self::VPX(Decoder::new(codec, num_threads)?, codec)
+            }
+            _ => Self::VPX(
+                Decoder::new(VideoCodecId::VP9, num_threads)?,
+                VideoCodecId::VP9,
+            ),
+        })
+    }
+
+    pub fn codec(&self) -> VideoCodecId {
+        match self {
+            Self::VPX(_, codec) => *codec,
+        }
+    }
+
+    /// Decode `frames` into `rgb`, 4 bytes per pixel, returns false if no picture came out
+    pub fn decode_to_argb(&mut self, frames: &[&[u8]], rgb: &mut Vec<u8>) -> Result<bool> {
+        match self {
+            Self::VPX(d, _) => {
+                let mut last_frame = Image::new();
+                for data in frames {
+                    for frame in d.decode(data)? {
+                        drop(last_frame);
+                        last_frame = frame;
+                    }
+                }
+                for frame in d.flush()? {
+                    drop(last_frame);
+                    last_frame = frame;
+                }
+                if last_frame.is_null() {
+                    Ok(false)
+                } else {
+                    last_frame.rgb(1, true, rgb);
+                    Ok(true)
+                }
+            }
+        }
+    }
+}
@@ -36,6 +36,19 @@ extern "C" {
         height: c_int,
     ) -> c_int;
 
+    pub fn ARGBToI444(
+        src_bgra: *const u8,
+        src_stride_bgra: c_int,
+        dst_y: *mut u8,
+        dst_stride_y: c_int,
+        dst_u: *mut u8,
+        dst_stride_u: c_int,
+        dst_v: *mut u8,
+        dst_stride_v: c_int,
+        width: c_int,
+        height: c_int,
+    ) -> c_int;
+
     pub fn ABGRToI420(
         src_rgba: *const u8,
         src_stride_rgba: c_int,
@@ -91,20 +104,67 @@ extern "C" {
         width: c_int,
         height: c_int,
     ) -> c_int;
+
+    pub fn I444ToARGB(
+        src_y: *const u8,
+        src_stride_y: c_int,
+        src_u: *const u8,
+        src_stride_u: c_int,
+        src_v: *const u8,
+        src_stride_v: c_int,
+        dst_rgba: *mut u8,
+        dst_stride_rgba: c_int,
+        width: c_int,
+        height: c_int,
+    ) -> c_int;
+
+    pub fn ARGBToRAW(
+        src_argb: *const u8,
+        src_stride_argb: c_int,
+        dst_raw: *mut u8,
+        dst_stride_raw: c_int,
+        width: c_int,
+        height: c_int,
+    ) -> c_int;
+
+    pub fn ARGBScale(
+        src_argb: *const u8,
+        src_stride_argb: c_int,
+        src_width: c_int,
+        src_height: c_int,
+        dst_argb: *mut u8,
+        dst_stride_argb: c_int,
+        dst_width: c_int,
+        dst_height: c_int,
+        filtering: c_int,
+    ) -> c_int;
 }
 
+// libyuv FilterMode
+const FILTER_BOX: c_int = 3;
+
 // https://github.com/webmproject/libvpx/blob/master/vpx/src/vpx_image.c
 #[inline]
 fn get_vpx_i420_stride(
     width: usize,
     height: usize,
     stride_align: usize,
+) -> (usize, usize, usize, usize, usize, usize) {
+    get_vpx_stride(vpx_img_fmt::VPX_IMG_FMT_I420, width, height, stride_align)
+}
+
+#[inline]
+fn get_vpx_stride(
+    fmt: vpx_img_fmt,
+    width: usize,
+    height: usize,
+    stride_align: usize,
 ) -> (usize, usize, usize, usize, usize, usize) {
     let mut img = Default::default();
     unsafe {
         vpx_img_wrap(
             &mut img,
-            vpx_img_fmt::VPX_IMG_FMT_I420,
+            fmt,
             width as _,
             height as _,
             stride_align as _,
@@ -167,6 +227,179 @@ pub fn bgra_to_i420(width: usize, height: usize, src: &[u8], dst: &mut Vec<u8>)
     }
 }
 
+/// Like `bgra_to_i420`, but only the `rects` of `src` if `dst` holds the previous frame already
+pub fn bgra_to_i420_rects(
+    width: usize,
+    height: usize,
+    src: &[u8],
+    dst: &mut Vec<u8>,
+    rects: &[super::Rect],
+) {
+    let (_, h, dst_stride_y, dst_stride_uv, u, v) =
+        get_vpx_i420_stride(width, height, super::STRIDE_ALIGN);
+    if dst.len() != h * dst_stride_y * 2 {
+        bgra_to_i420(width, height, src, dst);
+        return;
+    }
+    let src_stride = src.len() / height;
+    let dst_y = dst.as_mut_ptr();
+    for r in rects {
+        // tiles start on even pixels, the chroma has half the resolution
+        unsafe {
+            ARGBToI420(
+                src[r.y * src_stride + r.x * 4..].as_ptr(),
+                src_stride as _,
+                dst_y.add(r.y * dst_stride_y + r.x),
+                dst_stride_y as _,
+                dst_y.add(u + r.y / 2 * dst_stride_uv + r.x / 2),
+                dst_stride_uv as _,
+                dst_y.add(v + r.y / 2 * dst_stride_uv + r.x / 2),
+                dst_stride_uv as _,
+                r.w as _,
+                r.h as _,
+            );
+        }
+    }
+}
+
+/// The changed tiles of an I420 frame made by `bgra_to_i420`, see `diff_tiles`
+pub fn i420_diff_tiles(
+    width: usize,
+    height: usize,
+    old: &mut Vec<u8>,
+    new: &[u8],
+) -> Vec<super::Rect> {
+    let (_, _, stride_y, stride_uv, u, v) =
+        get_vpx_i420_stride(width, height, super::STRIDE_ALIGN);
+    let plane = |offset, stride, shift| super::Plane {
+        offset,
+        stride,
+        bpp: 1,
+        shift,
+    };
+    super::diff_planes(
+        old,
+        new,
+        width,
+        height,
+        &[plane(0, stride_y, 0), plane(u, stride_uv, 1), plane(v, stride_uv, 1)],
+    )
+}
+
+/// Copy the changed `rects` of an I420 frame made by `bgra_to_i420`, see `copy_tiles`
+pub fn i420_copy_tiles(
+    width: usize,
+    height: usize,
+    old: &mut Vec<u8>,
+    new: &[u8],
+    rects: &[super::Rect],
+) -> Vec<super::Rect> {
+    let (_, _, stride_y, stride_uv, u, v) =
+        get_vpx_i420_stride(width, height, super::STRIDE_ALIGN);
+    let plane = |offset, stride, shift| super::Plane {
+        offset,
+        stride,
+        bpp: 1,
+        shift,
+    };
+    super::copy_planes(
+        old,
+        new,
+        width,
+        height,
+        &[
+            plane(0, stride_y, 0),
+            plane(u, stride_uv, 1),
+            plane(v, stride_uv, 1),
+        ],
+        rects,
+    )
+}
+
+/// Full chroma resolution for sharp coloured text, VP9 profile 1 encodes it
+pub fn bgra_to_i444(width: usize, height: usize, src: &[u8], dst: &mut Vec<u8>) {
+    let (_, h, dst_stride_y, dst_stride_uv, u, v) =
+        get_vpx_stride(vpx_img_fmt::VPX_IMG_FMT_I444, width, height, super::STRIDE_ALIGN);
+    dst.resize(h * dst_stride_y * 3, 0);
+    let dst_y = dst.as_mut_ptr();
+    let dst_u = dst[u..].as_mut_ptr();
+    let dst_v = dst[v..].as_mut_ptr();
+    unsafe {
+        ARGBToI444(
+            src.as_ptr(),
+            (src.len() / height) as _,
+            dst_y,
+            dst_stride_y as _,
+            dst_u,
+            dst_stride_uv as _,
+            dst_v,
+            dst_stride_uv as _,
+            width as _,
+            height as _,
+        );
+    }
+}
+
+/// Like `bgra_to_i444`, but only the `rects` of `src` if `dst` holds the previous frame already
+pub fn bgra_to_i444_rects(
+    width: usize,
+    height: usize,
+    src: &[u8],
+    dst: &mut Vec<u8>,
+    rects: &[super::Rect],
+) {
+    let (_, h, dst_stride_y, dst_stride_uv, u, v) =
+        get_vpx_stride(vpx_img_fmt::VPX_IMG_FMT_I444, width, height, super::STRIDE_ALIGN);
+    if dst.len() != h * dst_stride_y * 3 {
+        bgra_to_i444(width, height, src, dst);
+        return;
+    }
+    let src_stride = src.len() / height;
+    let dst_y = dst.as_mut_ptr();
+    for r in rects {
+        unsafe {
+            ARGBToI444(
+                src[r.y * src_stride + r.x * 4..].as_ptr(),
+                src_stride as _,
+                dst_y.add(r.y * dst_stride_y + r.x),
+                dst_stride_y as _,
+                dst_y.add(u + r.y * dst_stride_uv + r.x),
+                dst_stride_uv as _,
+                dst_y.add(v + r.y * dst_stride_uv + r.x),
+                dst_stride_uv as _,
+                r.w as _,
+                r.h as _,
+            );
+        }
+    }
+}
+
+/// Scale a BGRA frame of `src_height` rows to `width` x `height`, averaging the pixels
+/// so that text stays readable when shrunk
+pub fn bgra_scale(
+    src_width: usize,
+    src_height: usize,
+    src: &[u8],
+    width: usize,
+    height: usize,
+    dst: &mut Vec<u8>,
+) {
+    dst.resize(width * height * 4, 0);
+    unsafe {
+        ARGBScale(
+            src.as_ptr(),
+            (src.len() / src_height) as _,
+            src_width as _,
+            src_height as _,
+            dst.as_mut_ptr(),
+            (width * 4) as _,
+            width as _,
+            height as _,
+            FILTER_BOX,
+        );
+    }
+}
+
 pub fn rgba_to_i420(width: usize, height: usize, src: &[u8], dst: &mut Vec<u8>) {
     let (_, h, dst_stride_y, dst_stride_uv, u, v) =
         get_vpx_i420_stride(width, height, super::STRIDE_ALIGN);
@@ -45,7 +45,7 @@ pub enum Display {
 }
 
 #[inline]
-fn is_wayland() -> bool {
+pub fn is_wayland() -> bool {
     std::env::var("IS_WAYLAND").is_ok()
         || std::env::var("XDG_SESSION_TYPE") == Ok("wayland".to_owned())
 }
@@ -28,12 +28,168 @@ cfg_if! {
 }
 
 pub mod codec;
+pub mod coder;
+pub use self::coder::*;
 mod convert;
 pub use self::convert::*;
 pub const STRIDE_ALIGN: usize = 64; // commonly used in libvpx vpx_img_alloc caller
 
 mod vpx;
 
+/// Side of the square tiles compared for changes, a multiple of the encoder's 16 pixel macroblocks
+pub const TILE_SIZE: usize = 64;
+
+/// A rectangle of a frame, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+// A plane of a frame in a buffer: the offset of its first row, bytes per row,
+// bytes per pixel and log2 of its subsampling
+pub(crate) struct Plane {
+    pub offset: usize,
+    pub stride: usize,
+    pub bpp: usize,
+    pub shift: usize,
+}
+
+// byte ranges of the rows of a tile in a plane
+fn tile_rows<'a>(
+    p: &'a Plane,
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+) -> impl Iterator<Item = std::ops::Range<usize>> + 'a {
+    // rounded up, so that an odd last column or row is in
+    let w = (w + (1 << p.shift) - 1) >> p.shift;
+    let h = (h + (1 << p.shift) - 1) >> p.shift;
+    let (x, y) = (x >> p.shift, y >> p.shift);
+    (y..y + h).map(move |row| {
+        let start = p.offset + row * p.stride + x * p.bpp;
+        start..start + w * p.bpp
+    })
+}
+
+// Compare `new` with `old` tile by tile and copy the changed tiles into `old`.
+// Returns the changed tiles, the adjacent ones of a row merged, or the whole frame
+// if `old` was of another size.
+pub(crate) fn diff_planes(
+    old: &mut Vec<u8>,
+    new: &[u8],
+    width: usize,
+    height: usize,
+    planes: &[Plane],
+) -> Vec<Rect> {
+    if old.len() != new.len() {
+        return copy_whole(old, new, width, height);
+    }
+    let mut rects = Vec::new();
+    for y in (0..height).step_by(TILE_SIZE) {
+        let h = std::cmp::min(TILE_SIZE, height - y);
+        let mut run: Option<Rect> = None;
+        for x in (0..width).step_by(TILE_SIZE) {
+            let w = std::cmp::min(TILE_SIZE, width - x);
+            let changed = planes.iter().any(|p| {
+                tile_rows(p, x, y, w, h).any(|r| r.end <= new.len() && old[r.clone()] != new[r])
+            });
+            if changed {
+                for p in planes {
+                    for r in tile_rows(p, x, y, w, h).filter(|r| r.end <= new.len()) {
+                        old[r.clone()].copy_from_slice(&new[r]);
+                    }
+                }
+                match run.as_mut() {
+                    Some(run) => run.w += w,
+                    None => run = Some(Rect { x, y, w, h }),
+                }
+            } else if let Some(run) = run.take() {
+                rects.push(run);
+            }
+        }
+        if let Some(run) = run.take() {
+            rects.push(run);
+        }
+    }
+    rects
+}
+
+// Copy the `rects` of `new`, already known to be the changed ones, into `old`.
+// Returns them, or the whole frame if `old` was of another size.
+pub(crate) fn copy_planes(
+    old: &mut Vec<u8>,
+    new: &[u8],
+    width: usize,
+    height: usize,
+    planes: &[Plane],
+    rects: &[Rect],
+) -> Vec<Rect> {
+    if old.len() != new.len() {
+        return copy_whole(old, new, width, height);
+    }
+    for rect in rects {
+        for p in planes {
+            for r in tile_rows(p, rect.x, rect.y, rect.w, rect.h).filter(|r| r.end <= new.len()) {
+                old[r.clone()].copy_from_slice(&new[r]);
+            }
+        }
+    }
+    rects.to_vec()
+}
+
+fn copy_whole(old: &mut Vec<u8>, new: &[u8], width: usize, height: usize) -> Vec<Rect> {
+    old.clear();
+    old.extend_from_slice(new);
+    if width == 0 || height == 0 {
+        return Vec::new();
+    }
+    vec![Rect {
+        x: 0,
+        y: 0,
+        w: width,
+        h: height,
+    }]
+}
+
+/// The changed tiles of a frame of 4 bytes pixels and `stride` bytes per row, see `diff_planes`.
+pub fn diff_tiles(
+    old: &mut Vec<u8>,
+    new: &[u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+) -> Vec<Rect> {
+    let plane = Plane {
+        offset: 0,
+        stride,
+        bpp: 4,
+        shift: 0,
+    };
+    diff_planes(old, new, width, height, &[plane])
+}
+
+/// Copy the changed `rects` given by the capturer, see `copy_planes`.
+pub fn copy_tiles(
+    old: &mut Vec<u8>,
+    new: &[u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    rects: &[Rect],
+) -> Vec<Rect> {
+    let plane = Plane {
+        offset: 0,
+        stride,
+        bpp: 4,
+        shift: 0,
+    };
+    copy_planes(old, new, width, height, &[plane], rects)
+}
+
 #[inline]
 pub fn would_block_if_equal(old: &mut Vec<u128>, b: &[u8]) -> std::io::Result<()> {
     let b = unsafe { std::slice::from_raw_parts::<u128>(b.as_ptr() as _, b.len() / 16) };
@@ -44,3 +200,54 @@ pub fn would_block_if_equal(old: &mut Vec<u128>, b: &[u8]) -> std::io::Result<()
     old.copy_from_slice(b);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_tiles() {
+        let (w, h) = (150, 70);
+        let mut frame = vec![0u8; w * h * 4];
+        let mut old = Vec::new();
+        assert_eq!(diff_tiles(&mut old, &frame, w, h, w * 4), vec![Rect { x: 0, y: 0, w, h }]);
+        assert!(diff_tiles(&mut old, &frame, w, h, w * 4).is_empty());
+        // a pixel in the second and third tiles of the first row, one in the last corner
+        frame[(10 * w + 70) * 4] = 1;
+        frame[(20 * w + 130) * 4 + 3] = 1;
+        frame[((h - 1) * w + w - 1) * 4] = 1;
+        assert_eq!(
+            diff_tiles(&mut old, &frame, w, h, w * 4),
+            vec![
+                Rect { x: 64, y: 0, w: 86, h: 64 },
+                Rect { x: 128, y: 64, w: 22, h: 6 },
+            ]
+        );
+        assert_eq!(old, frame);
+        assert!(diff_tiles(&mut old, &frame, w, h, w * 4).is_empty());
+    }
+
+    #[test]
+    fn test_copy_tiles() {
+        let (w, h) = (150, 70);
+        let mut frame = vec![0u8; w * h * 4];
+        let mut old = Vec::new();
+        let whole = vec![Rect { x: 0, y: 0, w, h }];
+        assert_eq!(copy_tiles(&mut old, &frame, w, h, w * 4, &[]), whole);
+        frame[(10 * w + 70) * 4] = 1;
+        frame[(h - 1) * w * 4] = 1;
+        let rect = Rect {
+            x: 64,
+            y: 0,
+            w: 64,
+            h: 64,
+        };
+        assert_eq!(
+            copy_tiles(&mut old, &frame, w, h, w * 4, &[rect]),
+            vec![rect]
+        );
+        // only the given rects are copied
+        assert_eq!(old[(10 * w + 70) * 4], 1);
+        assert_eq!(old[(h - 1) * w * 4], 0);
+    }
+}
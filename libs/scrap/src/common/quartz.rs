@@ -79,6 +79,13 @@ impl Capturer {
 
 pub struct Frame<'a>(quartz::Frame, PhantomData<&'a [u8]>);
 
+impl<'a> Frame<'a> {
+    /// Unknown, the whole frame is compared
+    pub fn changed_rects(&self) -> Option<&[crate::Rect]> {
+        None
+    }
+}
+
 impl<'a> ops::Deref for Frame<'a> {
     type Target = [u8];
     fn deref(&self) -> &[u8] {
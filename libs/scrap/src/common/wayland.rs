@@ -10,7 +10,10 @@ fn map_err<E: ToString>(err: E) -> io::Error {
 
 impl Capturer {
     pub fn new(display: Display, yuv: bool) -> io::Result<Capturer> {
-        let r = display.0.recorder(false).map_err(map_err)?;
+        let r = display.0.recorder(false).map_err(|err| {
+            pipewire::close_session();
+            map_err(err)
+        })?;
         Ok(Capturer(display, r, yuv, Default::default()))
     }
 
@@ -23,13 +26,24 @@ impl Capturer {
     }
 
     pub fn frame<'a>(&'a mut self, timeout_ms: u32) -> io::Result<Frame<'a>> {
-        match self.1.capture(timeout_ms as _).map_err(map_err)? {
-            PixelProvider::BGR0(w, h, x) => Ok(Frame(if self.2 {
-                crate::common::bgra_to_i420(w as _, h as _, &x, &mut self.3);
-                &self.3[..]
-            } else {
-                x
-            })),
+        let pixels = match self.1.capture(timeout_ms as _) {
+            Ok(pixels) => pixels,
+            Err(err) => {
+                // e.g. the session was stopped by the user, ask for a new one next time
+                pipewire::close_session();
+                return Err(map_err(err));
+            }
+        };
+        match pixels {
+            PixelProvider::BGR0(w, h, x) => Ok(Frame(
+                if self.2 {
+                    crate::common::bgra_to_i420(w as _, h as _, &x, &mut self.3);
+                    &self.3[..]
+                } else {
+                    x
+                },
+                None,
+            )),
             PixelProvider::NONE => Err(std::io::ErrorKind::WouldBlock.into()),
             _ => Err(map_err("Invalid data")),
         }
@@ -48,7 +62,9 @@ impl Display {
     }
 
     pub fn all() -> io::Result<Vec<Display>> {
-        Ok(pipewire::get_capturables(false)
+        // the cursor as metadata for the cursor services, or drawn into the frames if the portal
+        // can not
+        Ok(pipewire::get_capturables(true)
             .map_err(map_err)?
             .drain(..)
             .map(|x| Display(x))
@@ -1,4 +1,4 @@
-use crate::x11;
+use crate::{x11, Rect};
 use std::{io, ops};
 
 pub struct Capturer(x11::Capturer);
@@ -17,11 +17,19 @@ impl Capturer {
     }
 
     pub fn frame<'a>(&'a mut self, _timeout_ms: u32) -> io::Result<Frame<'a>> {
-        Ok(Frame(self.0.frame()?))
+        let (data, rects) = self.0.frame()?;
+        Ok(Frame(data, Some(rects)))
     }
 }
 
-pub struct Frame<'a>(pub(crate) &'a [u8]);
+pub struct Frame<'a>(pub(crate) &'a [u8], pub(crate) Option<&'a [Rect]>);
+
+impl<'a> Frame<'a> {
+    /// The tiles changed since the last frame, None if the capturer does not know
+    pub fn changed_rects(&self) -> Option<&[Rect]> {
+        self.1
+    }
+}
 
 impl<'a> ops::Deref for Frame<'a> {
     type Target = [u8];
@@ -1,3 +1,4 @@
 pub mod pipewire;
 mod pipewire_dbus;
 pub mod capturable;
+pub mod cursor;
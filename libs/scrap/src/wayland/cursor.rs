@@ -0,0 +1,342 @@
+// The cursor of the portal session when the portal sends it as metadata of the streams
+// (cursor mode 4) instead of drawing it into the frames. pipewiresrc drops the metadata, so the
+// streams are also read here, by pipewire streams of our own that only look at the cursor.
+
+use pipewire as pw;
+use pw::spa;
+use spa::pod::{serialize::PodSerializer, Object, Pod, Property, Value};
+use spa::sys as spa_sys;
+use spa::utils::{Choice, ChoiceEnum, ChoiceFlags, Id};
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use tracing::{debug, warn};
+
+// large enough for the cursor themes, even scaled
+const MAX_CURSOR_SIZE: usize = 256;
+// the position is polled every 33 ms, more frames would be wasted
+const MAX_FPS: i32 = 30;
+
+#[derive(Clone, Debug, Default)]
+pub struct CursorImage {
+    // hash of the image, the same cursor always gets the same id
+    pub id: u64,
+    pub hotx: i32,
+    pub hoty: i32,
+    pub width: i32,
+    pub height: i32,
+    // RGBA
+    pub colors: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    position: Option<(i32, i32)>,
+    image: Option<CursorImage>,
+}
+
+lazy_static::lazy_static! {
+    static ref STATE: Mutex<State> = Default::default();
+}
+
+/// Position of the cursor on the desktop, None until the portal sent it
+pub fn get_position() -> Option<(i32, i32)> {
+    STATE.lock().unwrap().position
+}
+
+pub fn get_image_id() -> Option<u64> {
+    STATE.lock().unwrap().image.as_ref().map(|x| x.id)
+}
+
+pub fn get_image() -> Option<CursorImage> {
+    STATE.lock().unwrap().image.clone()
+}
+
+/// Reads the cursor metadata of the streams of a session, until dropped
+pub struct CursorReader {
+    quit: pw::channel::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CursorReader {
+    /// `streams` are the node ids of the streams with their position on the desktop
+    pub fn new(fd: RawFd, streams: Vec<(u32, (i32, i32))>) -> Result<Self, Box<dyn Error>> {
+        // the capturers keep using the fd of the session
+        let fd = unsafe { libc::dup(fd) };
+        if fd < 0 {
+            return Err(Box::new(std::io::Error::last_os_error()));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let (quit, rx) = pw::channel::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            if let Err(err) = run(fd, streams, rx) {
+                warn!("Failed to read the cursor metadata: {}", err);
+            }
+            *STATE.lock().unwrap() = Default::default();
+        });
+        Ok(Self {
+            quit,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for CursorReader {
+    fn drop(&mut self) {
+        self.quit.send(()).ok();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn serialize(obj: Object) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(
+        PodSerializer::serialize(std::io::Cursor::new(Vec::new()), &Value::Object(obj))
+            .map_err(|err| format!("{:?}", err))?
+            .0
+            .into_inner(),
+    )
+}
+
+fn format_param() -> Result<Vec<u8>, Box<dyn Error>> {
+    use spa::param::{format::*, video::VideoFormat, ParamType};
+    serialize(spa::pod::object!(
+        spa::utils::SpaTypes::ObjectParamFormat,
+        ParamType::EnumFormat,
+        spa::pod::property!(FormatProperties::MediaType, Id, MediaType::Video),
+        spa::pod::property!(FormatProperties::MediaSubtype, Id, MediaSubtype::Raw),
+        spa::pod::property!(
+            FormatProperties::VideoFormat,
+            Choice,
+            Enum,
+            Id,
+            VideoFormat::BGRx,
+            VideoFormat::BGRx,
+            VideoFormat::BGRA,
+            VideoFormat::RGBx,
+            VideoFormat::RGBA
+        ),
+        spa::pod::property!(
+            FormatProperties::VideoSize,
+            Choice,
+            Range,
+            Rectangle,
+            spa::utils::Rectangle {
+                width: 1920,
+                height: 1080
+            },
+            spa::utils::Rectangle {
+                width: 1,
+                height: 1
+            },
+            spa::utils::Rectangle {
+                width: 16384,
+                height: 16384
+            }
+        ),
+        spa::pod::property!(
+            FormatProperties::VideoFramerate,
+            Choice,
+            Range,
+            Fraction,
+            spa::utils::Fraction {
+                num: MAX_FPS as _,
+                denom: 1
+            },
+            spa::utils::Fraction { num: 0, denom: 1 },
+            spa::utils::Fraction {
+                num: MAX_FPS as _,
+                denom: 1
+            }
+        ),
+    ))
+}
+
+fn cursor_meta_size(size: usize) -> i32 {
+    (std::mem::size_of::<spa_sys::spa_meta_cursor>()
+        + std::mem::size_of::<spa_sys::spa_meta_bitmap>()
+        + size * size * 4) as _
+}
+
+fn meta_param() -> Result<Vec<u8>, Box<dyn Error>> {
+    serialize(Object {
+        type_: spa_sys::SPA_TYPE_OBJECT_ParamMeta,
+        id: spa_sys::SPA_PARAM_Meta,
+        properties: vec![
+            Property::new(
+                spa_sys::SPA_PARAM_META_type,
+                Value::Id(Id(spa_sys::SPA_META_Cursor)),
+            ),
+            Property::new(
+                spa_sys::SPA_PARAM_META_size,
+                Value::Choice(spa::pod::ChoiceValue::Int(Choice(
+                    ChoiceFlags::empty(),
+                    ChoiceEnum::Range {
+                        default: cursor_meta_size(64),
+                        min: cursor_meta_size(1),
+                        max: cursor_meta_size(MAX_CURSOR_SIZE),
+                    },
+                ))),
+            ),
+        ],
+    })
+}
+
+fn run(
+    fd: OwnedFd,
+    streams: Vec<(u32, (i32, i32))>,
+    quit: pw::channel::Receiver<()>,
+) -> Result<(), Box<dyn Error>> {
+    let mainloop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&mainloop)?;
+    let core = context.connect_fd(fd, None)?;
+    let _quit = quit.attach(mainloop.loop_(), {
+        let mainloop = mainloop.clone();
+        move |_| mainloop.quit()
+    });
+    let format = format_param()?;
+    let meta = meta_param()?;
+    let mut keep = Vec::new();
+    for (node, origin) in streams {
+        let stream = pw::stream::Stream::new(
+            &core,
+            "hoptodesk-cursor",
+            pw::properties::properties! {
+                *pw::keys::MEDIA_TYPE => "Video",
+                *pw::keys::MEDIA_CATEGORY => "Capture",
+                *pw::keys::MEDIA_ROLE => "Screen",
+            },
+        )?;
+        let meta = meta.clone();
+        let listener = stream
+            .add_local_listener_with_user_data(origin)
+            .param_changed(move |stream, _, id, param| {
+                // the metadata is asked once the format is known, like the buffers
+                if id != spa::param::ParamType::Format.as_raw() || param.is_none() {
+                    return;
+                }
+                if let Some(pod) = Pod::from_bytes(&meta) {
+                    if let Err(err) = stream.update_params(&mut [pod]) {
+                        warn!("Failed to ask the cursor metadata: {}", err);
+                    }
+                }
+            })
+            .process(|stream, origin| process(stream, *origin))
+            .register()?;
+        let mut params = [Pod::from_bytes(&format).ok_or("Invalid format")?];
+        stream.connect(
+            spa::utils::Direction::Input,
+            Some(node),
+            pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )?;
+        keep.push((stream, listener));
+    }
+    debug!("Reading the cursor metadata of {} streams", keep.len());
+    mainloop.run();
+    Ok(())
+}
+
+fn process(stream: &pw::stream::StreamRef, origin: (i32, i32)) {
+    unsafe {
+        // only the latest buffer matters
+        let mut buffer = stream.dequeue_raw_buffer();
+        loop {
+            let next = stream.dequeue_raw_buffer();
+            if next.is_null() {
+                break;
+            }
+            stream.queue_raw_buffer(buffer);
+            buffer = next;
+        }
+        if buffer.is_null() {
+            return;
+        }
+        read_cursor((*buffer).buffer, origin);
+        stream.queue_raw_buffer(buffer);
+    }
+}
+
+unsafe fn read_cursor(buffer: *mut spa_sys::spa_buffer, origin: (i32, i32)) {
+    if buffer.is_null() {
+        return;
+    }
+    let cursor = spa_sys::spa_buffer_find_meta_data(
+        buffer,
+        spa_sys::SPA_META_Cursor,
+        std::mem::size_of::<spa_sys::spa_meta_cursor>(),
+    ) as *const spa_sys::spa_meta_cursor;
+    // id 0 when the cursor is not on this stream
+    if cursor.is_null() || (*cursor).id == 0 {
+        return;
+    }
+    let position = (
+        origin.0 + (*cursor).position.x,
+        origin.1 + (*cursor).position.y,
+    );
+    let image =
+        if (*cursor).bitmap_offset as usize >= std::mem::size_of::<spa_sys::spa_meta_cursor>() {
+            let bitmap = (cursor as *const u8).add((*cursor).bitmap_offset as _)
+                as *const spa_sys::spa_meta_bitmap;
+            read_bitmap(bitmap, (*cursor).hotspot.x, (*cursor).hotspot.y)
+        } else {
+            // unchanged
+            None
+        };
+    let mut state = STATE.lock().unwrap();
+    state.position = Some(position);
+    if let Some(image) = image {
+        if state.image.as_ref().map(|x| x.id) != Some(image.id) {
+            state.image = Some(image);
+        }
+    }
+}
+
+unsafe fn read_bitmap(
+    bitmap: *const spa_sys::spa_meta_bitmap,
+    hotx: i32,
+    hoty: i32,
+) -> Option<CursorImage> {
+    let width = (*bitmap).size.width as usize;
+    let height = (*bitmap).size.height as usize;
+    let stride = (*bitmap).stride as usize;
+    if width == 0
+        || height == 0
+        || width > MAX_CURSOR_SIZE
+        || height > MAX_CURSOR_SIZE
+        || stride < width * 4
+        || ((*bitmap).offset as usize) < std::mem::size_of::<spa_sys::spa_meta_bitmap>()
+    {
+        return None;
+    }
+    // index of r, g, b and a in a pixel
+    let order = match (*bitmap).format {
+        spa_sys::SPA_VIDEO_FORMAT_RGBA => [0, 1, 2, 3],
+        spa_sys::SPA_VIDEO_FORMAT_BGRA => [2, 1, 0, 3],
+        spa_sys::SPA_VIDEO_FORMAT_ARGB => [1, 2, 3, 0],
+        spa_sys::SPA_VIDEO_FORMAT_ABGR => [3, 2, 1, 0],
+        _ => return None,
+    };
+    let data = (bitmap as *const u8).add((*bitmap).offset as _);
+    let mut colors = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        let row = std::slice::from_raw_parts(data.add(y * stride), width * 4);
+        for p in row.chunks_exact(4) {
+            colors.extend(order.iter().map(|i| p[*i]));
+        }
+    }
+    let mut hasher = DefaultHasher::new();
+    (hotx, hoty, width, height, &colors).hash(&mut hasher);
+    Some(CursorImage {
+        id: hasher.finish(),
+        hotx,
+        hoty,
+        width: width as _,
+        height: height as _,
+        colors,
+    })
+}
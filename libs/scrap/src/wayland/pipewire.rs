@@ -1,12 +1,15 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::os::unix::io::AsRawFd;
-use std::sync::{atomic::AtomicBool, Arc, Mutex};
-use std::time::Duration;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
 use tracing::{debug, trace, warn};
 
 use dbus::{
-    arg::{OwnedFd, PropMap, RefArg, Variant},
+    arg::{AppendAll, OwnedFd, PropMap, RefArg, Variant},
     blocking::{Proxy, SyncConnection},
     message::{MatchRule, MessageType},
     Message,
@@ -18,8 +21,12 @@ use gstreamer_app::AppSink;
 
 use super::capturable::PixelProvider;
 use super::capturable::{Capturable, Recorder};
+use super::cursor::CursorReader;
 
-use super::pipewire_dbus::{OrgFreedesktopPortalRequestResponse, OrgFreedesktopPortalScreenCast};
+use super::pipewire_dbus::{
+    OrgFreedesktopPortalRemoteDesktop, OrgFreedesktopPortalRequestResponse,
+    OrgFreedesktopPortalScreenCast, OrgFreedesktopPortalSession,
+};
 
 #[derive(Debug, Clone, Copy)]
 struct PwStreamInfo {
@@ -281,18 +288,18 @@ where
             0 => {}
             1 => {
                 warn!("DBus response: User cancelled interaction.");
-                failure_out.store(true, std::sync::atomic::Ordering::Relaxed);
+                failure_out.store(true, Ordering::Relaxed);
                 return true;
             }
             c => {
                 warn!("DBus response: Unknown error, code: {}.", c);
-                failure_out.store(true, std::sync::atomic::Ordering::Relaxed);
+                failure_out.store(true, Ordering::Relaxed);
                 return true;
             }
         }
         if let Err(err) = f(r, c, m) {
             warn!("Error requesting screen capture via dbus: {}", err);
-            failure_out.store(true, std::sync::atomic::Ordering::Relaxed);
+            failure_out.store(true, Ordering::Relaxed);
         }
         true
     })
@@ -385,10 +392,199 @@ fn streams_from_response(response: OrgFreedesktopPortalRequestResponse) -> Vec<P
 
 static mut INIT: bool = false;
 
-// mostly inspired by https://gitlab.gnome.org/snippets/19
-fn request_screen_cast(
+// not to ask the user again and again after a refusal
+const REQUEST_RETRY: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    // only held briefly, the input goes through it while a new session is requested
+    static ref SESSION: Mutex<Option<PortalSession>> = Default::default();
+    static ref REQUEST: Mutex<RequestState> = Default::default();
+    static ref RESTORE_TOKEN: Mutex<String> = Default::default();
+}
+
+// A session is requested on a thread of its own, the portal may wait minutes for the user
+#[derive(Default)]
+struct RequestState {
+    running: bool,
+    last_failure: Option<Instant>,
+}
+
+// A portal session, kept for the capturers created later and for the input, as asking for a
+// new one may prompt the user.
+struct PortalSession {
+    conn: Arc<SyncConnection>,
+    session: dbus::Path<'static>,
+    fd: OwnedFd,
+    streams: Vec<PwStreamInfo>,
+    capture_cursor: bool,
+    remote_desktop: bool,
+    // with the cursor sent as metadata
+    cursor: Option<CursorReader>,
+}
+
+// The state of a session being requested, filled by the responses of the portal
+#[derive(Clone)]
+struct Request {
     capture_cursor: bool,
-) -> Result<(SyncConnection, OwnedFd, Vec<PwStreamInfo>), Box<dyn Error>> {
+    remote_desktop: bool,
+    // whether the portal can restore the session later without asking the user
+    persist: bool,
+    session: Arc<Mutex<Option<dbus::Path<'static>>>>,
+    fd: Arc<Mutex<Option<OwnedFd>>>,
+    streams: Arc<Mutex<Vec<PwStreamInfo>>>,
+    restore_token: Arc<Mutex<Option<String>>>,
+    cursor_mode: Arc<AtomicU32>,
+    failure: Arc<AtomicBool>,
+}
+
+const CURSOR_MODE_HIDDEN: u32 = 1;
+const CURSOR_MODE_EMBEDDED: u32 = 2;
+const CURSOR_MODE_METADATA: u32 = 4;
+
+/// Metadata for the cursor services if the portal can, else drawn into the frames, but never on
+/// KDE Plasma where this can crash kwin_wayland and tear down the desktop, see
+/// https://bugs.kde.org/show_bug.cgi?id=435042
+fn choose_cursor_mode(capture_cursor: bool, available: u32, plasma: bool) -> u32 {
+    if !capture_cursor {
+        CURSOR_MODE_HIDDEN
+    } else if available & CURSOR_MODE_METADATA != 0 {
+        CURSOR_MODE_METADATA
+    } else if available & CURSOR_MODE_EMBEDDED != 0 && !plasma {
+        CURSOR_MODE_EMBEDDED
+    } else {
+        CURSOR_MODE_HIDDEN
+    }
+}
+
+/// The token given by the portal with the last session, to start the next one without asking the
+/// user again. Supported by the ScreenCast portal from version 4, RemoteDesktop from version 2.
+pub fn get_restore_token() -> String {
+    RESTORE_TOKEN.lock().unwrap().clone()
+}
+
+pub fn set_restore_token(token: String) {
+    *RESTORE_TOKEN.lock().unwrap() = token;
+}
+
+fn insert_persist_args(args: &mut PropMap) {
+    // persistent until revoked
+    args.insert("persist_mode".into(), Variant(Box::new(2u32)));
+    let token = get_restore_token();
+    if !token.is_empty() {
+        args.insert("restore_token".into(), Variant(Box::new(token)));
+    }
+}
+
+fn select_devices(
+    c: &SyncConnection,
+    session: dbus::Path<'static>,
+    r: Request,
+) -> Result<(), Box<dyn Error>> {
+    let portal = get_portal(c);
+    let mut args: PropMap = HashMap::new();
+    args.insert(
+        "handle_token".to_string(),
+        Variant(Box::new("u4".to_string())),
+    );
+    // keyboard | pointer
+    args.insert("types".into(), Variant(Box::new(3u32)));
+    if r.persist {
+        insert_persist_args(&mut args);
+    }
+    let path = portal.select_devices(session.clone(), args)?;
+    let failure_out = r.failure.clone();
+    handle_response(
+        c,
+        path,
+        move |_: OrgFreedesktopPortalRequestResponse, c, _| {
+            select_sources(c, session.clone(), r.clone())
+        },
+        failure_out,
+    )?;
+    Ok(())
+}
+
+fn select_sources(
+    c: &SyncConnection,
+    session: dbus::Path<'static>,
+    r: Request,
+) -> Result<(), Box<dyn Error>> {
+    let portal = get_portal(c);
+    let mut args: PropMap = HashMap::new();
+    args.insert(
+        "handle_token".to_string(),
+        Variant(Box::new("u2".to_string())),
+    );
+    // https://flatpak.github.io/xdg-desktop-portal/portal-docs.html#gdbus-method-org-freedesktop-portal-ScreenCast.SelectSources
+    args.insert("multiple".into(), Variant(Box::new(true)));
+    args.insert("types".into(), Variant(Box::new(1u32))); //| 2u32)));
+
+    let plasma = std::env::var("DESKTOP_SESSION").map_or(false, |s| s.contains("plasma"));
+    let cursor_mode = choose_cursor_mode(
+        r.capture_cursor,
+        portal.available_cursor_modes().unwrap_or(0),
+        plasma,
+    );
+    debug!("cursor mode: {}", cursor_mode);
+    r.cursor_mode.store(cursor_mode, Ordering::Relaxed);
+    args.insert("cursor_mode".into(), Variant(Box::new(cursor_mode)));
+    // a remote desktop session is persisted with its devices
+    if r.persist && !r.remote_desktop {
+        insert_persist_args(&mut args);
+    }
+    let path = portal.select_sources(session.clone(), args)?;
+    let failure_out = r.failure.clone();
+    handle_response(
+        c,
+        path,
+        move |_: OrgFreedesktopPortalRequestResponse, c, _| start(c, session.clone(), r.clone()),
+        failure_out,
+    )?;
+    Ok(())
+}
+
+fn start(
+    c: &SyncConnection,
+    session: dbus::Path<'static>,
+    r: Request,
+) -> Result<(), Box<dyn Error>> {
+    let portal = get_portal(c);
+    let mut args: PropMap = HashMap::new();
+    args.insert(
+        "handle_token".to_string(),
+        Variant(Box::new("u3".to_string())),
+    );
+    let path = if r.remote_desktop {
+        OrgFreedesktopPortalRemoteDesktop::start(&portal, session.clone(), "", args)?
+    } else {
+        OrgFreedesktopPortalScreenCast::start(&portal, session.clone(), "", args)?
+    };
+    let failure_out = r.failure.clone();
+    handle_response(
+        c,
+        path,
+        move |res: OrgFreedesktopPortalRequestResponse, c, _| {
+            if let Some(token) = res.results.get("restore_token").and_then(|x| x.as_str()) {
+                r.restore_token.lock().unwrap().replace(token.to_owned());
+            }
+            r.streams
+                .lock()
+                .unwrap()
+                .append(&mut streams_from_response(res));
+            let portal = get_portal(c);
+            r.session.lock().unwrap().replace(session.clone());
+            r.fd.lock()
+                .unwrap()
+                .replace(portal.open_pipe_wire_remote(session.clone(), HashMap::new())?);
+            Ok(())
+        },
+        failure_out,
+    )?;
+    Ok(())
+}
+
+// mostly inspired by https://gitlab.gnome.org/snippets/19
+fn request_session(capture_cursor: bool) -> Result<PortalSession, Box<dyn Error>> {
     unsafe {
         if !INIT {
             gstreamer::init()?;
@@ -397,13 +593,24 @@ fn request_screen_cast(
     }
     let conn = SyncConnection::new_session()?;
     let portal = get_portal(&conn);
+    // a remote desktop session also casts the screen, with the input on top
+    let remote_desktop = OrgFreedesktopPortalRemoteDesktop::version(&portal).ok();
+    let persist = match remote_desktop {
+        Some(version) => version >= 2,
+        None => OrgFreedesktopPortalScreenCast::version(&portal).map_or(false, |v| v >= 4),
+    };
+    let r = Request {
+        capture_cursor,
+        remote_desktop: remote_desktop.is_some(),
+        persist,
+        session: Default::default(),
+        fd: Default::default(),
+        streams: Default::default(),
+        restore_token: Default::default(),
+        cursor_mode: Default::default(),
+        failure: Default::default(),
+    };
     let mut args: PropMap = HashMap::new();
-    let fd: Arc<Mutex<Option<OwnedFd>>> = Arc::new(Mutex::new(None));
-    let fd_res = fd.clone();
-    let streams: Arc<Mutex<Vec<PwStreamInfo>>> = Arc::new(Mutex::new(Vec::new()));
-    let streams_res = streams.clone();
-    let failure = Arc::new(AtomicBool::new(false));
-    let failure_res = failure.clone();
     args.insert(
         "session_handle_token".to_string(),
         Variant(Box::new("u1".to_string())),
@@ -412,119 +619,259 @@ fn request_screen_cast(
         "handle_token".to_string(),
         Variant(Box::new("u1".to_string())),
     );
-    let path = portal.create_session(args)?;
+    let path = if r.remote_desktop {
+        OrgFreedesktopPortalRemoteDesktop::create_session(&portal, args)?
+    } else {
+        OrgFreedesktopPortalScreenCast::create_session(&portal, args)?
+    };
+    let request = r.clone();
     handle_response(
         &conn,
         path,
-        move |r: OrgFreedesktopPortalRequestResponse, c, _| {
-            let portal = get_portal(c);
-            let mut args: PropMap = HashMap::new();
-            args.insert(
-                "handle_token".to_string(),
-                Variant(Box::new("u2".to_string())),
-            );
-            // https://flatpak.github.io/xdg-desktop-portal/portal-docs.html#gdbus-method-org-freedesktop-portal-ScreenCast.SelectSources
-            args.insert("multiple".into(), Variant(Box::new(true)));
-            args.insert("types".into(), Variant(Box::new(1u32))); //| 2u32)));
-
-            let cursor_mode = if capture_cursor { 2u32 } else { 1u32 };
-            let plasma = std::env::var("DESKTOP_SESSION").map_or(false, |s| s.contains("plasma"));
-            if plasma && capture_cursor {
-                // Warn the user if capturing the cursor is tried on kde as this can crash
-                // kwin_wayland and tear down the plasma desktop, see:
-                // https://bugs.kde.org/show_bug.cgi?id=435042
-                warn!("You are attempting to capture the cursor under KDE Plasma, this may crash your \
-                    desktop, see https://bugs.kde.org/show_bug.cgi?id=435042 for details! \
-                    You have been warned.");
-            }
-            args.insert("cursor_mode".into(), Variant(Box::new(cursor_mode)));
-            let session: dbus::Path = r
+        move |res: OrgFreedesktopPortalRequestResponse, c, _| {
+            let session: dbus::Path = res
                 .results
                 .get("session_handle")
                 .ok_or_else(|| {
                     DBusError(format!(
                         "Failed to obtain session_handle from response: {:?}",
-                        r
+                        res
                     ))
                 })?
                 .as_str()
                 .ok_or_else(|| DBusError("Failed to convert session_handle to string.".into()))?
                 .to_string()
                 .into();
-            let path = portal.select_sources(session.clone(), args)?;
-            let fd = fd.clone();
-            let streams = streams.clone();
-            let failure = failure.clone();
-            let failure_out = failure.clone();
-            handle_response(
-                c,
-                path,
-                move |_: OrgFreedesktopPortalRequestResponse, c, _| {
-                    let portal = get_portal(c);
-                    let mut args: PropMap = HashMap::new();
-                    args.insert(
-                        "handle_token".to_string(),
-                        Variant(Box::new("u3".to_string())),
-                    );
-                    let path = portal.start(session.clone(), "", args)?;
-                    let session = session.clone();
-                    let fd = fd.clone();
-                    let streams = streams.clone();
-                    let failure = failure.clone();
-                    let failure_out = failure.clone();
-                    handle_response(
-                        c,
-                        path,
-                        move |r: OrgFreedesktopPortalRequestResponse, c, _| {
-                            streams
-                                .clone()
-                                .lock()
-                                .unwrap()
-                                .append(&mut streams_from_response(r));
-                            let portal = get_portal(c);
-                            fd.clone().lock().unwrap().replace(
-                                portal.open_pipe_wire_remote(session.clone(), HashMap::new())?,
-                            );
-                            Ok(())
-                        },
-                        failure_out,
-                    )?;
-                    Ok(())
-                },
-                failure_out,
-            )?;
-            Ok(())
+            if request.remote_desktop {
+                select_devices(c, session, request.clone())
+            } else {
+                select_sources(c, session, request.clone())
+            }
         },
-        failure_res.clone(),
+        r.failure.clone(),
     )?;
     // wait 3 minutes for user interaction
     for _ in 0..1800 {
         conn.process(Duration::from_millis(100))?;
         // Once we got a file descriptor we are done!
-        if fd_res.lock().unwrap().is_some() {
+        if r.fd.lock().unwrap().is_some() {
             break;
         }
 
-        if failure_res.load(std::sync::atomic::Ordering::Relaxed) {
+        if r.failure.load(Ordering::Relaxed) {
             break;
         }
     }
-    let fd_res = fd_res.lock().unwrap();
-    let streams_res = streams_res.lock().unwrap();
-    if fd_res.is_some() && !streams_res.is_empty() {
-        Ok((conn, fd_res.clone().unwrap(), streams_res.clone()))
-    } else {
-        Err(Box::new(DBusError(
+    let fd = r.fd.lock().unwrap().clone();
+    let session = r.session.lock().unwrap().clone();
+    let streams = r.streams.lock().unwrap().clone();
+    match (fd, session) {
+        (Some(fd), Some(session)) if !streams.is_empty() => {
+            if let Some(token) = r.restore_token.lock().unwrap().take() {
+                set_restore_token(token);
+            }
+            let cursor = if r.cursor_mode.load(Ordering::Relaxed) == CURSOR_MODE_METADATA {
+                let nodes = streams
+                    .iter()
+                    .map(|s| (s.path as u32, s.position))
+                    .collect();
+                match CursorReader::new(fd.as_raw_fd(), nodes) {
+                    Ok(cursor) => Some(cursor),
+                    Err(err) => {
+                        warn!("Failed to read the cursor metadata: {}", err);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            Ok(PortalSession {
+                conn: Arc::new(conn),
+                session,
+                fd,
+                streams,
+                capture_cursor,
+                remote_desktop: r.remote_desktop,
+                cursor,
+            })
+        }
+        _ => Err(Box::new(DBusError(
             "Failed to obtain screen capture.".into(),
-        )))
+        ))),
     }
 }
 
+/// The streams of the portal session. If there is none yet, it is requested in the background
+/// and this fails until the user accepted, see `is_requesting`.
 pub fn get_capturables(capture_cursor: bool) -> Result<Vec<PipeWireCapturable>, Box<dyn Error>> {
-    let (conn, fd, streams) = request_screen_cast(capture_cursor)?;
-    let conn = Arc::new(conn);
-    Ok(streams
-        .into_iter()
-        .map(|s| PipeWireCapturable::new(conn.clone(), fd.clone(), s))
-        .collect())
+    if let Some(s) = SESSION.lock().unwrap().as_ref() {
+        if s.capture_cursor == capture_cursor {
+            return Ok(s
+                .streams
+                .iter()
+                .map(|x| PipeWireCapturable::new(s.conn.clone(), s.fd.clone(), *x))
+                .collect());
+        }
+    }
+    start_request(capture_cursor)?;
+    Err(Box::new(DBusError(
+        "Waiting for the user to share the screen".into(),
+    )))
+}
+
+/// Whether a portal session is being requested
+pub fn is_requesting() -> bool {
+    REQUEST.lock().unwrap().running
+}
+
+fn start_request(capture_cursor: bool) -> Result<(), Box<dyn Error>> {
+    let mut lock = REQUEST.lock().unwrap();
+    if lock.running {
+        return Ok(());
+    }
+    if lock
+        .last_failure
+        .map_or(false, |t| t.elapsed() < REQUEST_RETRY)
+    {
+        return Err(Box::new(DBusError(
+            "Failed to obtain screen capture.".into(),
+        )));
+    }
+    lock.running = true;
+    std::thread::spawn(move || {
+        let res = request_session(capture_cursor);
+        let failed = res.is_err();
+        match res {
+            Ok(s) => {
+                let old = SESSION.lock().unwrap().replace(s);
+                if let Some(old) = old {
+                    close(&old);
+                }
+            }
+            Err(err) => warn!("Failed to request the portal session: {}", err),
+        }
+        let mut lock = REQUEST.lock().unwrap();
+        lock.running = false;
+        lock.last_failure = if failed { Some(Instant::now()) } else { None };
+    });
+    Ok(())
+}
+
+fn close(s: &PortalSession) {
+    let proxy = s.conn.with_proxy(
+        "org.freedesktop.portal.Desktop",
+        s.session.clone(),
+        Duration::from_millis(1000),
+    );
+    if let Err(err) = proxy.close() {
+        warn!("Failed to close portal session: {}", err);
+    }
+}
+
+/// Close the portal session, e.g. after its streams failed, so that the next capturables are of
+/// a new one.
+pub fn close_session() {
+    let s = SESSION.lock().unwrap().take();
+    if let Some(s) = s {
+        close(&s);
+    }
+}
+
+/// Whether input can be injected through the portal session
+pub fn is_remote_desktop() -> bool {
+    SESSION
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map_or(false, |s| s.remote_desktop)
+}
+
+// Sent without waiting for the portal, the calls have no result and the order is kept
+fn notify<A: AppendAll>(method: &str, args: A) -> Result<(), Box<dyn Error>> {
+    let (conn, session) = match SESSION.lock().unwrap().as_ref() {
+        Some(s) if s.remote_desktop => (s.conn.clone(), s.session.clone()),
+        _ => return Err(Box::new(DBusError("No remote desktop session".into()))),
+    };
+    let mut msg = Message::new_method_call(
+        "org.freedesktop.portal.Desktop",
+        "/org/freedesktop/portal/desktop",
+        "org.freedesktop.portal.RemoteDesktop",
+        method,
+    )?;
+    msg.set_no_reply(true);
+    msg.append_all((session, PropMap::new()));
+    msg.append_all(args);
+    let channel = conn.channel();
+    if channel.send(msg).is_err() {
+        return Err(Box::new(DBusError(format!("Failed to send {}", method))));
+    }
+    // writes what is left in the queue, if anything, without blocking
+    channel.read_write(Some(Duration::from_millis(0))).ok();
+    Ok(())
+}
+
+/// Move the pointer to a point of the desktop, as given by the positions of the streams
+pub fn notify_pointer_motion_absolute(x: i32, y: i32) -> Result<(), Box<dyn Error>> {
+    let stream = {
+        let lock = SESSION.lock().unwrap();
+        let streams = match lock.as_ref() {
+            Some(s) => &s.streams,
+            None => return Err(Box::new(DBusError("No remote desktop session".into()))),
+        };
+        let contains = |s: &&PwStreamInfo| {
+            x >= s.position.0
+                && y >= s.position.1
+                && x < s.position.0 + s.size.0 as i32
+                && y < s.position.1 + s.size.1 as i32
+        };
+        // the streams are never empty
+        *streams.iter().find(contains).unwrap_or(&streams[0])
+    };
+    notify(
+        "NotifyPointerMotionAbsolute",
+        (
+            stream.path as u32,
+            (x - stream.position.0) as f64,
+            (y - stream.position.1) as f64,
+        ),
+    )
+}
+
+pub fn notify_pointer_motion(dx: i32, dy: i32) -> Result<(), Box<dyn Error>> {
+    notify("NotifyPointerMotion", (dx as f64, dy as f64))
+}
+
+/// `button` is an evdev code, e.g. BTN_LEFT
+pub fn notify_pointer_button(button: i32, pressed: bool) -> Result<(), Box<dyn Error>> {
+    notify("NotifyPointerButton", (button, pressed as u32))
+}
+
+/// `axis` is 0 for vertical, 1 for horizontal scrolling, positive `steps` go down or right
+pub fn notify_pointer_axis_discrete(axis: u32, steps: i32) -> Result<(), Box<dyn Error>> {
+    notify("NotifyPointerAxisDiscrete", (axis, steps))
+}
+
+/// `keycode` is an evdev code
+pub fn notify_keyboard_keycode(keycode: i32, pressed: bool) -> Result<(), Box<dyn Error>> {
+    notify("NotifyKeyboardKeycode", (keycode, pressed as u32))
+}
+
+pub fn notify_keyboard_keysym(keysym: i32, pressed: bool) -> Result<(), Box<dyn Error>> {
+    notify("NotifyKeyboardKeysym", (keysym, pressed as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_cursor_mode() {
+        let all = CURSOR_MODE_HIDDEN | CURSOR_MODE_EMBEDDED | CURSOR_MODE_METADATA;
+        assert_eq!(choose_cursor_mode(true, all, false), CURSOR_MODE_METADATA);
+        assert_eq!(choose_cursor_mode(true, all, true), CURSOR_MODE_METADATA);
+        assert_eq!(choose_cursor_mode(false, all, false), CURSOR_MODE_HIDDEN);
+        assert_eq!(choose_cursor_mode(true, 3, false), CURSOR_MODE_EMBEDDED);
+        assert_eq!(choose_cursor_mode(true, 3, true), CURSOR_MODE_HIDDEN);
+        assert_eq!(choose_cursor_mode(true, 0, false), CURSOR_MODE_HIDDEN);
+    }
 }
@@ -4,6 +4,7 @@ use libc;
 
 use super::ffi::*;
 use super::Display;
+use crate::Rect;
 
 pub struct Capturer {
     display: Display,
@@ -14,7 +15,8 @@ pub struct Capturer {
     size: usize,
     use_yuv: bool,
     yuv: Vec<u8>,
-    saved_raw_data: Vec<u128>, // for faster compare and copy
+    saved_raw_data: Vec<u8>, // the last frame, compared tile by tile
+    rects: Vec<Rect>,        // the tiles changed in the last frame
 }
 
 impl Capturer {
@@ -70,6 +72,7 @@ impl Capturer {
             use_yuv,
             yuv: Vec::new(),
             saved_raw_data: Vec::new(),
+            rects: Vec::new(),
         };
         Ok(c)
     }
@@ -99,16 +102,23 @@ impl Capturer {
         }
     }
 
-    pub fn frame<'b>(&'b mut self) -> std::io::Result<&'b [u8]> {
+    /// The frame with its tiles changed since the last one
+    pub fn frame<'b>(&'b mut self) -> std::io::Result<(&'b [u8], &'b [Rect])> {
         self.get_image();
         let result = unsafe { slice::from_raw_parts(self.buffer, self.size) };
-        crate::would_block_if_equal(&mut self.saved_raw_data, result)?;
-        Ok(if self.use_yuv {
-            crate::common::bgra_to_i420(self.display.w(), self.display.h(), &result, &mut self.yuv);
+        // the shm image is always the whole screen, but only its changed tiles are copied and converted
+        let (w, h) = (self.display.w(), self.display.h());
+        self.rects = crate::diff_tiles(&mut self.saved_raw_data, result, w, h, self.size / h);
+        if self.rects.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let data = if self.use_yuv {
+            crate::common::bgra_to_i420_rects(w, h, &result, &mut self.yuv, &self.rects);
             &self.yuv[..]
         } else {
             result
-        })
+        };
+        Ok((data, &self.rects[..]))
     }
 }
 
@@ -10,7 +10,11 @@ use super::{Display, Rect, Server};
 
 pub struct DisplayIter {
     outer: xcb_screen_iterator_t,
-    inner: Option<(xcb_randr_monitor_info_iterator_t, xcb_window_t)>,
+    inner: Option<(
+        xcb_randr_monitor_info_iterator_t,
+        xcb_window_t,
+        Option<Rect>,
+    )>,
     server: Rc<Server>,
 }
 
@@ -28,13 +32,24 @@ impl DisplayIter {
     fn next_screen(
         outer: &mut xcb_screen_iterator_t,
         server: &Server,
-    ) -> Option<(xcb_randr_monitor_info_iterator_t, xcb_window_t)> {
+    ) -> Option<(
+        xcb_randr_monitor_info_iterator_t,
+        xcb_window_t,
+        Option<Rect>,
+    )> {
         if outer.rem == 0 {
             return None;
         }
 
         unsafe {
             let root = (*outer.data).root;
+            // The whole screen, if randr lists no monitor, e.g. on Xvfb.
+            let screen = Rect {
+                x: 0,
+                y: 0,
+                w: (*outer.data).width_in_pixels,
+                h: (*outer.data).height_in_pixels,
+            };
 
             let cookie = xcb_randr_get_monitors_unchecked(
                 server.raw(),
@@ -44,12 +59,21 @@ impl DisplayIter {
 
             let response = xcb_randr_get_monitors_reply(server.raw(), cookie, ptr::null_mut());
 
-            let inner = xcb_randr_get_monitors_monitors_iterator(response);
+            let inner = if response.is_null() {
+                xcb_randr_monitor_info_iterator_t {
+                    data: ptr::null_mut(),
+                    rem: 0,
+                    index: 0,
+                }
+            } else {
+                xcb_randr_get_monitors_monitors_iterator(response)
+            };
+            let screen = if inner.rem == 0 { Some(screen) } else { None };
 
             libc::free(response as *mut _);
             xcb_screen_next(outer);
 
-            Some((inner, root))
+            Some((inner, root, screen))
         }
     }
 }
@@ -59,7 +83,7 @@ impl Iterator for DisplayIter {
 
     fn next(&mut self) -> Option<Display> {
         loop {
-            if let Some((ref mut inner, root)) = self.inner {
+            if let Some((ref mut inner, root, ref mut screen)) = self.inner {
                 // If there is something in the current screen, return that.
                 if inner.rem != 0 {
                     unsafe {
@@ -81,6 +105,9 @@ impl Iterator for DisplayIter {
                         return Some(display);
                     }
                 }
+                if let Some(rect) = screen.take() {
+                    return Some(Display::new(self.server.clone(), true, rect, root));
+                }
             } else {
                 // If there is no current screen, the screen iterator is empty.
                 return None;
@@ -32,7 +32,7 @@ use hbb_common::{
     tokio_util::compat::{Compat, TokioAsyncReadCompatExt},
     AddrMangle, ResultType, Stream,
 };
-use scrap::{Decoder, Image, VideoCodecId};
+use scrap::{VideoCodecId, VideoDecoder};
 
 pub use super::lang::*;
 pub mod file_trait;
@@ -139,7 +139,8 @@ impl Client {
             .unwrap()
             .as_secs()
             .to_string();
-        let socket = TcpStream::connect(&rendezvous_server).await?;
+        let socket =
+            socket_client::connect_tcp_stream(&rendezvous_server, RENDEZVOUS_TIMEOUT).await?;
         let resource = format!("/?user={}", my_peer_id);
         let mut websocket_client =
             soketto::handshake::Client::new(socket.compat(), &rendezvous_server, &resource);
@@ -212,26 +213,16 @@ impl Client {
             {
                 Ok(stream) => stream,
                 Err(_) => {
-                    let (turn_client, relay_addr) =
-                        match turn_client::new_relay_addr(peer_public_addr).await {
-                            Some((turn_client, relay_addr)) => (turn_client, relay_addr),
-                            None => bail!("Failed to get a new relay address"),
-                        };
-                    sender
-                        .send_text(
-                            &rendezvous_messages::RelayConnection::new(peer_id, relay_addr)
-                                .to_json(),
-                        )
-                        .await?;
-                    match turn_client.wait_new_connection().await {
-                        Ok(stream) => {
-                            sender
-                                .send_text(&rendezvous_messages::RelayReady::new(peer_id).to_json())
-                                .await?;
-                            direct = false;
-                            stream
+                    direct = false;
+                    match Self::connect_turn(peer_id, &mut sender, peer_public_addr).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::warn!("{}, try websocket relay", e);
+                            match Self::connect_ws_relay(peer_id, &mut sender).await {
+                                Ok(stream) => stream,
+                                Err(e2) => bail!("{}, {}", e, e2),
+                            }
                         }
-                        Err(e) => bail!("Failed to connect via relay server: {}", e),
                     }
                 }
             };
@@ -240,6 +231,45 @@ impl Client {
         Ok((conn, direct))
     }
 
+    async fn connect_turn(
+        peer_id: &str,
+        sender: &mut soketto::Sender<Compat<TcpStream>>,
+        peer_public_addr: SocketAddr,
+    ) -> ResultType<Stream> {
+        let (turn_client, relay_addr) = match turn_client::new_relay_addr(peer_public_addr).await
+        {
+            Some((turn_client, relay_addr)) => (turn_client, relay_addr),
+            None => bail!("Failed to get a new relay address"),
+        };
+        sender
+            .send_text(&rendezvous_messages::RelayConnection::new(peer_id, relay_addr).to_json())
+            .await?;
+        match turn_client.wait_new_connection().await {
+            Ok(stream) => {
+                sender
+                    .send_text(&rendezvous_messages::RelayReady::new(peer_id).to_json())
+                    .await?;
+                Ok(stream)
+            }
+            Err(e) => bail!("Failed to connect via relay server: {}", e),
+        }
+    }
+
+    // Last resort where only https egress is allowed, the peer joins the same websocket relay.
+    async fn connect_ws_relay(
+        peer_id: &str,
+        sender: &mut soketto::Sender<Compat<TcpStream>>,
+    ) -> ResultType<Stream> {
+        let (uri, stream) = match crate::ws_tunnel::new_tunnel().await {
+            Ok(x) => x,
+            Err(e) => bail!("Failed to connect via websocket relay: {}", e),
+        };
+        sender
+            .send_text(&rendezvous_messages::WsRelayConnection::new(peer_id, &uri).to_json())
+            .await?;
+        Ok(stream)
+    }
+
     pub async fn secure_connection(
         peer_id: &str,
         id_pk: Vec<u8>,
@@ -517,18 +547,62 @@ impl AudioHandler {
     }
 }
 
+/// The rectangle covering all the `displays`, (x, y, width, height)
+pub fn displays_bounds(displays: &[DisplayInfo]) -> (i32, i32, i32, i32) {
+    let x0 = displays.iter().map(|d| d.x).min().unwrap_or(0);
+    let y0 = displays.iter().map(|d| d.y).min().unwrap_or(0);
+    let x1 = displays.iter().map(|d| d.x + d.width).max().unwrap_or(0);
+    let y1 = displays.iter().map(|d| d.y + d.height).max().unwrap_or(0);
+    (x0, y0, x1 - x0, y1 - y0)
+}
+
+// decode `vf` into `rgb`, switching `decoder` to the codec of the frame
+fn decode_frame(
+    decoder: &mut VideoDecoder,
+    vf: &VideoFrame,
+    rgb: &mut Vec<u8>,
+) -> ResultType<bool> {
+    let (codec, frames): (_, Vec<&[u8]>) = match &vf.union {
+        Some(video_frame::Union::vp9s(vp9s)) => (
+            VideoCodecId::VP9,
+            vp9s.frames.iter().map(|x| &x.data[..]).collect(),
+        ),
+        Some(video_frame::Union::av1s(av1s)) => (
+            VideoCodecId::AV1,
+            av1s.frames.iter().map(|x| &x.data[..]).collect(),
+        ),
+        Some(video_frame::Union::h264s(h264s)) => (
+            VideoCodecId::H264,
+            h264s.frames.iter().map(|x| &x.data[..]).collect(),
+        ),
+        _ => return Ok(false),
+    };
+    // the peer picked the codec, it only changes with a new key frame
+    if decoder.codec() != codec {
+        log::info!("Switch video decoder to {:?}", codec);
+        *decoder = VideoDecoder::new(codec, (num_cpus::get() / 2) as _)?;
+    }
+    Ok(decoder.decode_to_argb(&frames, rgb)?)
+}
+
 pub struct VideoHandler {
-    decoder: Decoder,
+    decoder: VideoDecoder,
     latency_controller: Arc<Mutex<LatencyController>>,
     pub rgb: Vec<u8>,
+    // the displays of the peer when showing them all, each decoded on its own
+    // and copied into `rgb` at its place, empty for the current display only
+    displays: Vec<DisplayInfo>,
+    display_decoders: HashMap<usize, (VideoDecoder, Vec<u8>)>,
 }
 
 impl VideoHandler {
     pub fn new(latency_controller: Arc<Mutex<LatencyController>>) -> Self {
         VideoHandler {
-            decoder: Decoder::new(VideoCodecId::VP9, (num_cpus::get() / 2) as _).unwrap(),
+            decoder: VideoDecoder::new(VideoCodecId::VP9, (num_cpus::get() / 2) as _).unwrap(),
             latency_controller,
             rgb: Default::default(),
+            displays: Default::default(),
+            display_decoders: Default::default(),
         }
     }
 
@@ -539,34 +613,54 @@ impl VideoHandler {
                 .unwrap()
                 .update_video(vf.timestamp);
         }
-        match &vf.union {
-            Some(video_frame::Union::vp9s(vp9s)) => self.handle_vp9s(vp9s),
-            _ => Ok(false),
+        if self.displays.is_empty() {
+            return decode_frame(&mut self.decoder, &vf, &mut self.rgb);
         }
-    }
-
-    pub fn handle_vp9s(&mut self, vp9s: &VP9s) -> ResultType<bool> {
-        let mut last_frame = Image::new();
-        for vp9 in vp9s.frames.iter() {
-            for frame in self.decoder.decode(&vp9.data)? {
-                drop(last_frame);
-                last_frame = frame;
-            }
+        let i = vf.display as usize;
+        let d = match self.displays.get(i) {
+            Some(d) => d.clone(),
+            None => return Ok(false),
+        };
+        if !self.display_decoders.contains_key(&i) {
+            let decoder = VideoDecoder::new(VideoCodecId::VP9, (num_cpus::get() / 2) as _)?;
+            self.display_decoders.insert(i, (decoder, Vec::new()));
         }
-        for frame in self.decoder.flush()? {
-            drop(last_frame);
-            last_frame = frame;
+        let (decoder, rgb) = match self.display_decoders.get_mut(&i) {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        if !decode_frame(decoder, &vf, rgb)? {
+            return Ok(false);
         }
-        if last_frame.is_null() {
-            Ok(false)
-        } else {
-            last_frame.rgb(1, true, &mut self.rgb);
-            Ok(true)
+        let (x0, y0, w, h) = displays_bounds(&self.displays);
+        let (w, h) = (w as usize, h as usize);
+        if self.rgb.len() != w * h * 4 {
+            self.rgb = vec![0; w * h * 4];
         }
+        let (dx, dy) = ((d.x - x0) as usize, (d.y - y0) as usize);
+        let (dw, dh) = (d.width as usize, d.height as usize);
+        // the display changed its size, wait for the new list
+        if rgb.len() != dw * dh * 4 || dx + dw > w || dy + dh > h {
+            return Ok(false);
+        }
+        let row = dw * 4;
+        for r in 0..dh {
+            let to = ((dy + r) * w + dx) * 4;
+            self.rgb[to..to + row].copy_from_slice(&rgb[r * row..(r + 1) * row]);
+        }
+        Ok(true)
+    }
+
+    /// Compose all the `displays` from now on, or only show the current one if empty
+    pub fn set_displays(&mut self, displays: Vec<DisplayInfo>) {
+        self.displays = displays;
+        self.display_decoders.clear();
+        self.rgb.clear();
+        self.reset();
     }
 
     pub fn reset(&mut self) {
-        self.decoder = Decoder::new(VideoCodecId::VP9, 1).unwrap();
+        self.decoder = VideoDecoder::new(VideoCodecId::VP9, 1).unwrap();
     }
 }
 
@@ -578,6 +672,7 @@ pub struct LoginConfigHandler {
     hash: Hash,
     password: Vec<u8>, // remember password for reconnect
     pub remember: bool,
+    totp_code: String,
     config: PeerConfig,
     pub port_forward: (String, i32),
     pub version: i64,
@@ -691,6 +786,17 @@ impl LoginConfigHandler {
                 BoolOption::No
             })
             .into();
+        } else if name == "show-all-displays" {
+            let v = config.options.remove(&name).is_some();
+            if !v {
+                config.options.insert(name.clone(), "Y".to_owned());
+            }
+            option.show_all_displays = (if v {
+                BoolOption::No
+            } else {
+                BoolOption::Yes
+            })
+            .into();
         } else if name == "block-input" {
             option.block_input = BoolOption::Yes.into();
         } else if name == "unblock-input" {
@@ -737,6 +843,18 @@ impl LoginConfigHandler {
                 }
             }
         }
+        if let Ok(fps) = self.get_option("custom-fps").parse::<i32>() {
+            if fps > 0 {
+                msg.custom_fps = fps;
+                n += 1;
+            }
+        }
+        if let Ok(scale) = self.get_option("image-scale").parse::<i32>() {
+            if scale > 0 && scale < 100 {
+                msg.image_scale = scale;
+                n += 1;
+            }
+        }
         if self.get_toggle_option("show-remote-cursor") {
             msg.show_remote_cursor = BoolOption::Yes.into();
             n += 1;
@@ -761,6 +879,10 @@ impl LoginConfigHandler {
             msg.disable_clipboard = BoolOption::Yes.into();
             n += 1;
         }
+        if self.get_toggle_option("show-all-displays") {
+            msg.show_all_displays = BoolOption::Yes.into();
+            n += 1;
+        }
         if n > 0 {
             Some(msg)
         } else {
@@ -773,6 +895,8 @@ impl LoginConfigHandler {
             Some(ImageQuality::Low)
         } else if q == "best" {
             Some(ImageQuality::Best)
+        } else if q == "lossless" {
+            Some(ImageQuality::Lossless)
         } else if q == "balanced" {
             if ignore_default {
                 None
@@ -810,6 +934,15 @@ impl LoginConfigHandler {
         }
     }
 
+    // older versions stream the current display only, with 0 as the display of every frame
+    pub fn is_all_displays_supported(&self) -> bool {
+        if let Some(features) = &self.features {
+            features.all_displays
+        } else {
+            false
+        }
+    }
+
     pub fn refresh() -> Message {
         let mut misc = Misc::new();
         misc.set_refresh_video(true);
@@ -833,6 +966,37 @@ impl LoginConfigHandler {
         msg_out
     }
 
+    /// `fps` 0 goes back to the default frame rate
+    pub fn save_custom_fps(&mut self, fps: i32) -> Message {
+        let mut misc = Misc::new();
+        misc.set_option(OptionMessage {
+            custom_fps: if fps > 0 { fps } else { -1 },
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        let value = if fps > 0 {
+            fps.to_string()
+        } else {
+            "".to_owned()
+        };
+        self.set_option("custom-fps".to_owned(), value);
+        msg_out
+    }
+
+    /// `scale` is the percentage of the remote resolution to get, 100 for all of it
+    pub fn save_image_scale(&mut self, scale: i32) -> Message {
+        let mut misc = Misc::new();
+        misc.set_option(OptionMessage {
+            image_scale: scale,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.set_option("image-scale".to_owned(), scale.to_string());
+        msg_out
+    }
+
     pub fn save_image_quality(&mut self, value: String) -> Option<Message> {
         let mut res = None;
         if let Some(q) = self.get_image_quality_enum(&value, false) {
@@ -868,6 +1032,10 @@ impl LoginConfigHandler {
                 &format!("{err} - Do you want to enter again?"),
             );
             true
+        } else if err == "2FA Code Required" || err == "Wrong 2FA Code" {
+            self.totp_code.clear();
+            interface.msgbox("input-2fa-code", err, "");
+            true
         } else {
             interface.msgbox("error", "Login Error", err);
             false
@@ -883,6 +1051,8 @@ impl LoginConfigHandler {
     }
 
     pub fn handle_peer_info(&mut self, username: String, pi: PeerInfo) {
+        // used up, can not be replayed on reconnect
+        self.totp_code.clear();
         if !pi.version.is_empty() {
             self.version = hbb_common::get_version_number(&pi.version);
         }
@@ -944,6 +1114,9 @@ impl LoginConfigHandler {
             my_id,
             my_name: crate::username(),
             option: self.get_option_message(true).into(),
+            view_only: self.get_toggle_option("view-only"),
+            totp_code: self.totp_code.clone(),
+            supported_decoding: Some(crate::common::supported_codecs()).into(),
             ..Default::default()
         };
         if self.is_file_transfer {
@@ -970,6 +1143,8 @@ pub enum MediaData {
     AudioFrame(AudioFrame),
     AudioFormat(AudioFormat),
     Reset,
+    // the displays to show side by side, empty for the current one only
+    AllDisplays(Vec<DisplayInfo>),
 }
 
 pub type MediaSender = mpsc::Sender<MediaData>;
@@ -998,6 +1173,9 @@ where
                     MediaData::Reset => {
                         video_handler.reset();
                     }
+                    MediaData::AllDisplays(displays) => {
+                        video_handler.set_displays(displays);
+                    }
                     _ => {}
                 }
             } else {
@@ -1141,6 +1319,19 @@ async fn send_login(lc: Arc<RwLock<LoginConfigHandler>>, password: Vec<u8>, peer
     allow_err!(peer.send(&msg_out).await);
 }
 
+/// Log in again with the 2FA code from the authenticator app, and the password given before
+pub async fn handle_login_2fa(lc: Arc<RwLock<LoginConfigHandler>>, code: String, peer: &mut Stream) {
+    lc.write().unwrap().totp_code = code;
+    let mut password = lc.read().unwrap().password.clone();
+    if password.is_empty() {
+        password = lc.read().unwrap().config.password.clone();
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(&password);
+    hasher.update(&lc.read().unwrap().hash.challenge);
+    send_login(lc.clone(), hasher.finalize()[..].into(), peer).await;
+}
+
 pub async fn handle_login_from_ui(
     lc: Arc<RwLock<LoginConfigHandler>>,
     password: String,
@@ -1174,6 +1365,7 @@ pub trait Interface: Send + Clone + 'static + Sized {
 pub enum Data {
     Close,
     Login((String, bool)),
+    Login2FA(String),
     Message(Message),
     SendFiles((i32, String, String, i32, bool, bool)),
     RemoveDirAll((i32, String, bool)),
@@ -1186,6 +1378,7 @@ pub enum Data {
     RemovePortForward(i32),
     AddPortForward((i32, String, i32)),
     ToggleClipboardFile,
+    ToggleAllDisplays,
     NewRDP,
     SetConfirmOverrideFile((i32, i32, bool, bool, bool)),
     AddJob((i32, String, String, i32, bool, bool)),
@@ -256,6 +256,15 @@ pub async fn get_nat_type(ms_timeout: u64) -> i32 {
     crate::ipc::get_nat_type(ms_timeout).await
 }
 
+// the video codecs of this build besides VP9, encoders and decoders come together
+pub fn supported_codecs() -> SupportedCodecs {
+    SupportedCodecs {
+        av1: scrap::is_codec_supported(scrap::VideoCodecId::AV1),
+        h264: scrap::is_codec_supported(scrap::VideoCodecId::H264),
+        ..Default::default()
+    }
+}
+
 #[inline]
 pub fn get_time() -> i64 {
     std::time::SystemTime::now()
@@ -264,6 +273,63 @@ pub fn get_time() -> i64 {
         .unwrap_or(0) as _
 }
 
+/// Traffic and quality of one session, refreshed about once a second
+#[derive(Debug, Default, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct SessionStats {
+    // the process id for a session controlling a peer
+    pub conn_id: i32,
+    pub peer_id: String,
+    // true for a session of this machine controlling a peer
+    pub controlling: bool,
+    pub direct: bool,
+    pub codec: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // bits per second
+    pub bitrate_sent: u64,
+    pub bitrate_received: u64,
+    // round trip time in ms
+    pub rtt: u32,
+    pub fps: u32,
+    // video frames sent on the controlled side, received on the controlling side
+    pub frames: u64,
+    pub frames_dropped: u64,
+    #[serde(skip)]
+    last_sample: Option<(std::time::Instant, u64, u64, u64)>,
+}
+
+impl SessionStats {
+    pub fn update(&mut self, bytes_sent: u64, bytes_received: u64) {
+        let now = std::time::Instant::now();
+        if let Some((tm, sent, received, frames)) = self.last_sample {
+            let ms = now.duration_since(tm).as_millis() as u64;
+            if ms > 0 {
+                self.bitrate_sent = bytes_sent.saturating_sub(sent) * 8 * 1000 / ms;
+                self.bitrate_received = bytes_received.saturating_sub(received) * 8 * 1000 / ms;
+                self.fps = (self.frames.saturating_sub(frames) * 1000 / ms) as _;
+            }
+        }
+        self.bytes_sent = bytes_sent;
+        self.bytes_received = bytes_received;
+        self.last_sample = Some((now, bytes_sent, bytes_received, self.frames));
+    }
+
+    pub fn on_video_frame(&mut self, vf: &VideoFrame) {
+        self.frames += 1;
+        let codec = match &vf.union {
+            Some(video_frame::Union::vp9s(_)) => "VP9",
+            Some(video_frame::Union::av1s(_)) => "AV1",
+            Some(video_frame::Union::h264s(_)) => "H264",
+            Some(video_frame::Union::rgb(_)) => "RGB",
+            Some(video_frame::Union::yuv(_)) => "YUV",
+            None => "",
+        };
+        if self.codec != codec {
+            self.codec = codec.to_owned();
+        }
+    }
+}
+
 pub fn run_me<T: AsRef<std::ffi::OsStr>>(args: Vec<T>) -> std::io::Result<std::process::Child> {
     #[cfg(not(feature = "appimage"))]
     {
@@ -338,7 +404,7 @@ async fn _check_software_update() -> hbb_common::ResultType<()> {
     };
     let rendezvous_server = socket_client::get_target_addr(&server)?;
     let mut socket =
-        socket_client::new_udp(Config::get_any_listen_addr(), RENDEZVOUS_TIMEOUT).await?;
+        socket_client::new_udp(Config::get_any_listen_addr(), &server, RENDEZVOUS_TIMEOUT).await?;
 
     let mut msg_out = RendezvousMessage::new();
     msg_out.set_software_update(SoftwareUpdate {
@@ -395,7 +461,7 @@ pub fn get_uuid() -> Vec<u8> {
 pub async fn post_request(url: String, body: String, header: &str) -> ResultType<String> {
     #[cfg(not(target_os = "linux"))]
     {
-        let mut req = reqwest::Client::new().post(url);
+        let mut req = hbb_common::socket_client::new_reqwest_client(&url)?.post(url);
         if !header.is_empty() {
             let tmp: Vec<&str> = header.split(": ").collect();
             if tmp.len() == 2 {
@@ -408,6 +474,7 @@ pub async fn post_request(url: String, body: String, header: &str) -> ResultType
     }
     #[cfg(target_os = "linux")]
     {
+        let proxy = hbb_common::socket_client::get_url_proxy(&url).map(|x| x.to_url());
         let mut data = vec![
             "curl",
             "-sS",
@@ -425,6 +492,13 @@ pub async fn post_request(url: String, body: String, header: &str) -> ResultType
             data.push("-H");
             data.push(header);
         }
+        if let Some(proxy) = proxy.as_ref() {
+            data.push("-x");
+            data.push(proxy);
+        } else {
+            data.push("--noproxy");
+            data.push("*");
+        }
         let output = async_process::Command::new("curl")
             .args(&data)
             .output()
@@ -107,6 +107,8 @@ pub enum Data {
         audio: bool,
         file: bool,
         file_transfer_enabled: bool,
+        #[serde(default)]
+        credential: String,
     },
     ChatMessage {
         text: String,
@@ -128,6 +130,7 @@ pub enum Data {
     ConfirmedKey(Option<(Vec<u8>, Vec<u8>)>),
     RawMessage(Vec<u8>),
     Socks(Option<config::Socks5Server>),
+    HttpProxy(Option<config::HttpProxyServer>),
     FS(FS),
     Test,
     SyncConfig(Option<(Config, Config2)>),
@@ -135,6 +138,14 @@ pub enum Data {
     ClipbaordFile(ClipbaordFile),
     ClipboardFileEnabled(bool),
     PrivacyModeState((i32, PrivacyModeState)),
+    SessionStats(Option<Vec<crate::common::SessionStats>>),
+    ClientSessionStats(crate::common::SessionStats), // every second from the remote windows
+    LoginFailures(Option<HashMap<String, config::LoginFailure>>),
+    ClearLoginFailures(String),
+    // the last lines of the audit log, at most the count, containing the filter if not empty
+    AuditLog((u32, String, Option<Vec<String>>)),
+    OneTimePassword((u32, Option<String>)), // valid minutes, 0 for no time limit
+    RequestControl(Option<bool>), // None from the view-only peer, approved or not by the local user
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -262,6 +273,59 @@ async fn handle(data: Data, stream: &mut Connection) {
                 log::info!("socks updated");
             }
         },
+        Data::HttpProxy(s) => match s {
+            None => {
+                allow_err!(stream.send(&Data::HttpProxy(Config::get_http_proxy())).await);
+            }
+            Some(data) => {
+                if data.proxy.is_empty() {
+                    Config::set_http_proxy(None);
+                } else {
+                    Config::set_http_proxy(Some(data));
+                }
+                RendezvousMediator::restart();
+                log::info!("http proxy updated");
+            }
+        },
+        Data::SessionStats(None) => {
+            allow_err!(
+                stream
+                    .send(&Data::SessionStats(Some(crate::server::get_session_stats())))
+                    .await
+            );
+        }
+        Data::ClientSessionStats(stats) => {
+            crate::server::update_client_session_stats(stats);
+        }
+        Data::LoginFailures(None) => {
+            allow_err!(
+                stream
+                    .send(&Data::LoginFailures(Some(
+                        crate::server::login_guard::get_login_failures()
+                    )))
+                    .await
+            );
+        }
+        Data::ClearLoginFailures(key) => {
+            crate::server::login_guard::clear_login_failures(&key);
+        }
+        Data::AuditLog((count, filter, None)) => {
+            let lines = crate::server::audit::query(count as _, &filter);
+            allow_err!(
+                stream
+                    .send(&Data::AuditLog((count, filter, Some(lines))))
+                    .await
+            );
+        }
+        Data::OneTimePassword((minutes, None)) => {
+            let password = Config::add_one_time_password(minutes);
+            log::info!("one-time password generated, valid minutes: {}", minutes);
+            allow_err!(
+                stream
+                    .send(&Data::OneTimePassword((minutes, Some(password))))
+                    .await
+            );
+        }
         Data::Config((name, value)) => match value {
             None => {
                 let value;
@@ -271,6 +335,8 @@ async fn handle(data: Data, stream: &mut Connection) {
                     value = Some(Config::get_password());
                 } else if name == "salt" {
                     value = Some(Config::get_salt());
+                } else if name == "password_profiles" {
+                    value = serde_json::to_string(&Config::get_password_profiles()).ok();
                 } else if name == "rendezvous_server" {
                     value = Config::get_rendezvous_server().await;
                 } else if name == "rendezvous_servers" {
@@ -288,6 +354,14 @@ async fn handle(data: Data, stream: &mut Connection) {
                     Config::set_password(&value);
                 } else if name == "salt" {
                     Config::set_salt(&value);
+                } else if name == "password_profiles" {
+                    match serde_json::from_str(&value) {
+                        Ok(profiles) => Config::set_password_profiles(profiles),
+                        Err(err) => {
+                            log::error!("Invalid password profiles: {}", err);
+                            return;
+                        }
+                    }
                 } else {
                     return;
                 }
@@ -510,6 +584,22 @@ pub fn get_password() -> String {
     }
 }
 
+pub fn set_password_profiles(v: Vec<config::PasswordProfile>) -> ResultType<()> {
+    let json = serde_json::to_string(&v)?;
+    Config::set_password_profiles(v);
+    set_config("password_profiles", json)
+}
+
+pub fn get_password_profiles() -> Vec<config::PasswordProfile> {
+    if let Ok(Some(v)) = get_config("password_profiles") {
+        if let Ok(v) = serde_json::from_str::<Vec<config::PasswordProfile>>(&v) {
+            Config::set_password_profiles(v.clone());
+            return v;
+        }
+    }
+    Config::get_password_profiles()
+}
+
 pub async fn get_rendezvous_server(ms_timeout: u64) -> Option<String> {
     if let Ok(Some(v)) = get_config_async("rendezvous_server", ms_timeout).await {
         Some(v)
@@ -620,6 +710,104 @@ pub async fn set_socks(value: config::Socks5Server) -> ResultType<()> {
     Ok(())
 }
 
+#[inline]
+async fn get_http_proxy_(ms_timeout: u64) -> ResultType<Option<config::HttpProxyServer>> {
+    let mut c = connect(ms_timeout, "").await?;
+    c.send(&Data::HttpProxy(None)).await?;
+    if let Some(Data::HttpProxy(value)) = c.next_timeout(ms_timeout).await? {
+        Config::set_http_proxy(value.clone());
+        Ok(value)
+    } else {
+        Ok(Config::get_http_proxy())
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_http_proxy() -> Option<config::HttpProxyServer> {
+    get_http_proxy_(1_000)
+        .await
+        .unwrap_or(Config::get_http_proxy())
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn set_http_proxy(value: config::HttpProxyServer) -> ResultType<()> {
+    Config::set_http_proxy(if value.proxy.is_empty() {
+        None
+    } else {
+        Some(value.clone())
+    });
+    connect(1_000, "")
+        .await?
+        .send(&Data::HttpProxy(Some(value)))
+        .await?;
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_session_stats() -> ResultType<Vec<crate::common::SessionStats>> {
+    let ms_timeout = 1_000;
+    let mut c = connect(ms_timeout, "").await?;
+    c.send(&Data::SessionStats(None)).await?;
+    if let Some(Data::SessionStats(Some(value))) = c.next_timeout(ms_timeout).await? {
+        Ok(value)
+    } else {
+        bail!("No response from the server");
+    }
+}
+
+pub async fn send_client_session_stats(stats: crate::common::SessionStats) -> ResultType<()> {
+    connect(1_000, "")
+        .await?
+        .send(&Data::ClientSessionStats(stats))
+        .await?;
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_login_failures() -> ResultType<HashMap<String, config::LoginFailure>> {
+    let ms_timeout = 1_000;
+    let mut c = connect(ms_timeout, "").await?;
+    c.send(&Data::LoginFailures(None)).await?;
+    if let Some(Data::LoginFailures(Some(value))) = c.next_timeout(ms_timeout).await? {
+        Ok(value)
+    } else {
+        bail!("No response from the server");
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_audit_log(count: u32, filter: String) -> ResultType<Vec<String>> {
+    let ms_timeout = 3_000;
+    let mut c = connect(ms_timeout, "").await?;
+    c.send(&Data::AuditLog((count, filter, None))).await?;
+    if let Some(Data::AuditLog((_, _, Some(value)))) = c.next_timeout(ms_timeout).await? {
+        Ok(value)
+    } else {
+        bail!("No response from the server");
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn new_one_time_password(minutes: u32) -> ResultType<String> {
+    let ms_timeout = 1_000;
+    let mut c = connect(ms_timeout, "").await?;
+    c.send(&Data::OneTimePassword((minutes, None))).await?;
+    if let Some(Data::OneTimePassword((_, Some(value)))) = c.next_timeout(ms_timeout).await? {
+        Ok(value)
+    } else {
+        bail!("No response from the server");
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn clear_login_failures(key: String) -> ResultType<()> {
+    connect(1_000, "")
+        .await?
+        .send(&Data::ClearLoginFailures(key))
+        .await?;
+    Ok(())
+}
+
 /*
 static mut SHARED_MEMORY: *mut i64 = std::ptr::null_mut();
 
@@ -12,6 +12,12 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Auto Login", "Auto Login (Only valid if you set \"Lock after session end\")"),
         ("whitelist_tip", "Only whitelisted IP can access me"),
         ("whitelist_sep", "Seperated by comma, semicolon, spaces or new line"),
+        ("totp_tip", "Scan the QR code with an authenticator app, or enter the key below. Connecting peers will be asked for the 6-digit code."),
+        ("request_control_tip", "The view-only peer requests to control your computer"),
+        ("one_time_password_tip", "The password is gone after it is used once. Enter the minutes it stays valid, or 0 for no time limit."),
+        ("access_schedule_tip", "One window per line, like mon-fri 22:00-02:00, in the timezone set by tz local or tz UTC+02:00. Outside the windows logins are refused, except for the IDs after except. Add a hide line to also show offline outside the windows."),
+        ("approval_rules_tip", "Answer logins without password automatically, one rule per line, the first matching wins: approve, view-only, deny or ask, then * , known (in the address book), unknown or an ID, optionally followed by hours like mon-fri 09:00-17:00. Set the timezone with tz UTC+02:00. The ID is claimed by the peer and not verified, so known or an ID can not be approved, only given view-only. With 2FA on, approvals are still asked."),
+        ("access_control_tip", "One rule per line: allow or deny, followed by * , an ID, an IP or an IP range like 10.0.0.0/8. Deny wins, and if there is any allow rule only the allowed can access me. IP rules apply to direct connections only."),
         ("Wrong credentials", "Wrong username or password"),
         ("invalid_http", "must start with http:// or https://"),
         ("install_daemon_tip", "For starting on boot, you need to install system service."),
@@ -39,6 +39,7 @@ mod port_forward;
 mod tray;
 mod turn_client;
 mod rendezvous_messages;
+pub mod ws_tunnel;
 #[cfg(windows)]
 pub mod clipboard_file;
 
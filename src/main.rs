@@ -131,6 +131,71 @@ fn main() {
                 import_config(&filepath);
             }
             return;
+        } else if args[0] == "--ws-relay" {
+            let port = args
+                .get(1)
+                .and_then(|x| x.parse::<u16>().ok())
+                .unwrap_or(ws_tunnel::WS_RELAY_PORT);
+            if let Err(err) = ws_tunnel::run_relay(port) {
+                log::error!("Failed to run websocket relay: {}", err);
+            }
+            return;
+        } else if args[0] == "--session-stats" {
+            match ipc::get_session_stats() {
+                Ok(stats) => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&stats).unwrap_or_default()
+                ),
+                Err(err) => println!("Failed to get session stats: {}", err),
+            }
+            return;
+        } else if args[0] == "--login-failures" {
+            match ipc::get_login_failures() {
+                Ok(failures) => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&failures).unwrap_or_default()
+                ),
+                Err(err) => println!("Failed to get login failures: {}", err),
+            }
+            return;
+        } else if args[0] == "--clear-login-failures" {
+            let key = args.get(1).cloned().unwrap_or_default();
+            if let Err(err) = ipc::clear_login_failures(key) {
+                println!("Failed to clear login failures: {}", err);
+            }
+            return;
+        } else if args[0] == "--audit" {
+            // the last lines of the audit log, 100 by default, optionally only those containing a peer ID or text
+            let count = args.get(1).and_then(|x| x.parse::<u32>().ok()).unwrap_or(100);
+            let filter = args.get(2).cloned().unwrap_or_default();
+            match ipc::get_audit_log(count, filter) {
+                Ok(lines) => lines.iter().for_each(|x| println!("{}", x)),
+                Err(err) => println!("Failed to get audit log: {}", err),
+            }
+            return;
+        } else if args[0] == "--one-time-password" {
+            // optionally valid for the given minutes only
+            let minutes = args.get(1).and_then(|x| x.parse::<u32>().ok()).unwrap_or(0);
+            match ipc::new_one_time_password(minutes) {
+                Ok(password) => println!("{}", password),
+                Err(err) => println!("Failed to generate one-time password: {}", err),
+            }
+            return;
+        } else if args[0] == "--password-profiles" {
+            // print them, or replace them with the json array given
+            if args.len() == 2 {
+                match serde_json::from_str(&args[1]) {
+                    Ok(profiles) => ipc::set_password_profiles(profiles).unwrap(),
+                    Err(err) => println!("Invalid password profiles: {}", err),
+                }
+            } else {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&ipc::get_password_profiles())
+                        .unwrap_or_default()
+                );
+            }
+            return;
         } else if args[0] == "--password" {
             if args.len() == 2 {
                 ipc::set_password(args[1].to_owned()).unwrap();
@@ -1223,6 +1223,7 @@ pub mod connection_manager {
                     audio,
                     file,
                     file_transfer_enabled,
+                    ..
                 }) => {
                     current_id = id;
                     let mut client = Client {
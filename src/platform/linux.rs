@@ -1,4 +1,6 @@
-use super::{CursorData, ResultType};
+use super::{linux_headless, CursorData, ResultType};
+#[cfg(feature = "wayland")]
+use hbb_common::tokio;
 use hbb_common::{allow_err, bail, log};
 use libc::{c_char, c_int, c_void};
 use std::{
@@ -11,6 +13,8 @@ use std::{
 type Xdo = *const c_void;
 
 pub const PA_SAMPLE_RATE: u32 = 48000;
+// seconds before trying again to start a headless X server which failed
+const HEADLESS_RETRY_INTERVAL: u64 = 30;
 static mut UNMODIFIED: bool = true;
 
 thread_local! {
@@ -55,6 +59,10 @@ pub struct xcb_xfixes_get_cursor_image {
 }
 
 pub fn get_cursor_pos() -> Option<(i32, i32)> {
+    #[cfg(feature = "wayland")]
+    if super::linux_wayland::is_wayland() {
+        return super::linux_wayland::get_cursor_pos();
+    }
     let mut res = None;
     XDO.with(|xdo| {
         if let Ok(xdo) = xdo.try_borrow_mut() {
@@ -75,6 +83,10 @@ pub fn get_cursor_pos() -> Option<(i32, i32)> {
 pub fn reset_input_cache() {}
 
 pub fn get_cursor() -> ResultType<Option<u64>> {
+    #[cfg(feature = "wayland")]
+    if super::linux_wayland::is_wayland() {
+        return super::linux_wayland::get_cursor();
+    }
     let mut res = None;
     DISPLAY.with(|conn| {
         if let Ok(d) = conn.try_borrow_mut() {
@@ -93,6 +105,10 @@ pub fn get_cursor() -> ResultType<Option<u64>> {
 }
 
 pub fn get_cursor_data(hcursor: u64) -> ResultType<CursorData> {
+    #[cfg(feature = "wayland")]
+    if super::linux_wayland::is_wayland() {
+        return super::linux_wayland::get_cursor_data(hcursor);
+    }
     let mut res = None;
     DISPLAY.with(|conn| {
         if let Ok(ref mut d) = conn.try_borrow_mut() {
@@ -148,19 +164,69 @@ pub fn start_os_service() {
     let r = running.clone();
     let mut uid = "".to_owned();
     let mut server: Option<std::process::Child> = None;
+    let mut headless: Option<linux_headless::Headless> = None;
+    let mut last_headless_try: Option<std::time::Instant> = None;
+    #[cfg(feature = "wayland")]
+    let mut wayland = false;
     if let Err(err) = ctrlc::set_handler(move || {
         r.store(false, Ordering::SeqCst);
     }) {
         println!("Failed to set Ctrl-C handler: {}", err);
     }
+    #[cfg(feature = "wayland")]
+    std::thread::spawn(start_ipc_service);
 
     let mut cm0 = false;
     let mut last_restart = std::time::Instant::now();
     while running.load(Ordering::SeqCst) {
         let cm = get_cm();
-        let tmp = get_active_userid();
+        // not get_active_userid(), which gives the headless user once its env is set
+        let tmp = get_value_of_seat0(1);
         let mut start_new = false;
-        if tmp != uid && !tmp.is_empty() {
+        let mut restart = false;
+        if tmp.is_empty() && linux_headless::is_enabled() {
+            let alive = headless
+                .as_mut()
+                .map(|h| h.check(HEADLESS_RETRY_INTERVAL))
+                .unwrap_or(false);
+            let retry = last_headless_try
+                .map(|t| t.elapsed().as_secs() > HEADLESS_RETRY_INTERVAL)
+                .unwrap_or(true);
+            if !alive && retry {
+                headless = None;
+                last_headless_try = Some(std::time::Instant::now());
+                match linux_headless::Headless::start() {
+                    Ok(h) => {
+                        h.set_env();
+                        #[cfg(feature = "wayland")]
+                        {
+                            wayland = false;
+                            set_wayland_env("", false);
+                        }
+                        log::info!("DISPLAY: {}", h.display());
+                        log::info!("XAUTHORITY: {}", h.xauthority());
+                        headless = Some(h);
+                        // pick up the seat0 session again once someone logs in
+                        uid = "".to_owned();
+                        restart = true;
+                    }
+                    Err(err) => {
+                        log::error!("Failed to start headless X server: {}", err);
+                    }
+                }
+            }
+        } else if headless.take().is_some() {
+            linux_headless::clear_env();
+            last_headless_try = None;
+            restart = tmp.is_empty();
+        }
+        if restart {
+            if let Some(ps) = server.as_mut() {
+                kill_server(ps);
+                std::thread::sleep(std::time::Duration::from_millis(30));
+                last_restart = std::time::Instant::now();
+            }
+        } else if tmp != uid && !tmp.is_empty() {
             uid = tmp;
             log::info!("uid of seat0: {}", uid);
             let gdm = format!("/run/user/{}/gdm/Xauthority", uid);
@@ -194,8 +260,13 @@ pub fn start_os_service() {
             log::info!("XAUTHORITY: {}", auth);
             std::env::set_var("XAUTHORITY", auth);
             std::env::set_var("DISPLAY", d);
+            #[cfg(feature = "wayland")]
+            {
+                wayland = get_display_server() == "wayland";
+                set_wayland_env(&uid, wayland);
+            }
             if let Some(ps) = server.as_mut() {
-                allow_err!(ps.kill());
+                kill_server(ps);
                 std::thread::sleep(std::time::Duration::from_millis(30));
                 last_restart = std::time::Instant::now();
             }
@@ -207,7 +278,7 @@ pub fn start_os_service() {
             // as a workaround to resolve "SpotUdp" (dns resolve)
             // and x server get displays failure issue
             if let Some(ps) = server.as_mut() {
-                allow_err!(ps.kill());
+                kill_server(ps);
                 std::thread::sleep(std::time::Duration::from_millis(30));
                 last_restart = std::time::Instant::now();
                 log::info!("restart server");
@@ -225,8 +296,19 @@ pub fn start_os_service() {
             start_new = true;
         }
         if start_new {
-            match crate::run_me(vec!["--server"]) {
-                Ok(ps) => server = Some(ps),
+            // the portal only serves the session user on Wayland
+            #[cfg(feature = "wayland")]
+            let res = if wayland {
+                run_as_user("--server")
+            } else {
+                crate::run_me(vec!["--server"])
+                    .map(Some)
+                    .map_err(|err| err.into())
+            };
+            #[cfg(not(feature = "wayland"))]
+            let res = crate::run_me(vec!["--server"]).map(Some);
+            match res {
+                Ok(ps) => server = ps,
                 Err(err) => {
                     log::error!("Failed to start server: {}", err);
                 }
@@ -237,12 +319,53 @@ pub fn start_os_service() {
     }
 
     if let Some(ps) = server.take().as_mut() {
-        allow_err!(ps.kill());
+        kill_server(ps);
     }
+    headless.take();
     log::info!("Exit");
 }
 
+// SIGTERM, which sudo passes on to a server run as the user, unlike SIGKILL
+fn kill_server(ps: &mut std::process::Child) {
+    if unsafe { libc::kill(ps.id() as _, libc::SIGTERM) } != 0 {
+        allow_err!(ps.kill());
+    }
+}
+
+#[cfg(feature = "wayland")]
+fn set_wayland_env(uid: &str, wayland: bool) {
+    if !wayland {
+        std::env::remove_var("WAYLAND_DISPLAY");
+        std::env::remove_var("XDG_SESSION_TYPE");
+        return;
+    }
+    let mut d = get_env("WAYLAND_DISPLAY", uid);
+    if d.is_empty() {
+        d = "wayland-0".to_owned();
+    }
+    let mut bus = get_env("DBUS_SESSION_BUS_ADDRESS", uid);
+    if bus.is_empty() {
+        bus = format!("unix:path=/run/user/{}/bus", uid);
+    }
+    log::info!("WAYLAND_DISPLAY: {}", d);
+    std::env::set_var("WAYLAND_DISPLAY", d);
+    std::env::set_var("DBUS_SESSION_BUS_ADDRESS", bus);
+    std::env::set_var("XDG_SESSION_TYPE", "wayland");
+}
+
+// for the config of the server run as the user on Wayland, see server::sync_and_watch_config_dir
+#[cfg(feature = "wayland")]
+#[tokio::main(flavor = "current_thread")]
+async fn start_ipc_service() {
+    if let Err(err) = crate::ipc::start("_service").await {
+        log::error!("Failed to start ipc_service: {}", err);
+    }
+}
+
 pub fn get_active_userid() -> String {
+    if let Some((_, uid)) = linux_headless::get_user() {
+        return uid;
+    }
     get_value_of_seat0(1)
 }
 
@@ -345,6 +468,9 @@ fn get_value_of_seat0(i: usize) -> String {
 }
 
 pub fn get_display_server() -> String {
+    if linux_headless::get_user().is_some() {
+        return "x11".to_owned();
+    }
     let session = get_value_of_seat0(0);
     get_display_server_of_session(&session)
 }
@@ -514,6 +640,9 @@ fn _get_display_manager() -> String {
 }
 
 pub fn get_active_username() -> String {
+    if let Some((name, _)) = linux_headless::get_user() {
+        return name;
+    }
     get_value_of_seat0(2)
 }
 
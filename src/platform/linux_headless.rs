@@ -0,0 +1,435 @@
+// Headless mode: with "headless" set to Y and nobody logged in on seat0, the service starts a
+// virtual X server, Xvfb or Xorg with the dummy driver ("headless-server": xvfb or xdummy),
+// of "headless-resolution" (1920x1080 by default), runs the session command in it as the
+// session user, and points the server at it. The server then finds the session user in
+// HEADLESS_USER and HEADLESS_UID of its environment.
+// The options can be set by any local user through the ipc, so the session user and command are
+// only read from /etc/hoptodesk/headless-user and /etc/hoptodesk/headless-session, files of root
+// (/etc/X11/Xsession without the latter), and root or the system accounts are refused as session
+// user.
+// The virtual X server is stopped once someone logs in on seat0.
+
+use hbb_common::{bail, config::Config, log, sodiumoxide::randombytes, ResultType};
+use std::{
+    ffi::{CStr, CString},
+    fs::{self, File},
+    io::Write,
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    time::{Duration, Instant},
+};
+
+pub const OPTION_HEADLESS: &'static str = "headless";
+pub const OPTION_SERVER: &'static str = "headless-server";
+pub const OPTION_RESOLUTION: &'static str = "headless-resolution";
+const USER_FILE: &'static str = "/etc/hoptodesk/headless-user";
+const SESSION_FILE: &'static str = "/etc/hoptodesk/headless-session";
+const DEFAULT_SESSION: &'static str = "/etc/X11/Xsession";
+// the first uid of the users, if /etc/login.defs has no UID_MIN
+const DEFAULT_UID_MIN: u32 = 1000;
+const DEFAULT_RESOLUTION: (u32, u32) = (1920, 1080);
+pub const ENV_USER: &'static str = "HEADLESS_USER";
+pub const ENV_UID: &'static str = "HEADLESS_UID";
+// the display numbers tried, above the ones of the display managers
+const FIRST_DISPLAY: u32 = 99;
+const LAST_DISPLAY: u32 = 199;
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+// the time the session has to exit after SIGTERM
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+// The files given to the X servers, out of the reach of other users: only root can create files
+// in it, the session user can only open its own Xauthority whose name it knows.
+const RUN_DIR: &'static str = "/run/hoptodesk";
+
+pub fn is_enabled() -> bool {
+    Config::get_option(OPTION_HEADLESS) == "Y"
+}
+
+/// The session user, if this process serves a virtual X server
+pub fn get_user() -> Option<(String, String)> {
+    match (std::env::var(ENV_USER), std::env::var(ENV_UID)) {
+        (Ok(name), Ok(uid)) if !name.is_empty() => Some((name, uid)),
+        _ => None,
+    }
+}
+
+fn get_resolution() -> (u32, u32) {
+    let option = Config::get_option(OPTION_RESOLUTION);
+    let mut it = option
+        .trim()
+        .split('x')
+        .map(|x| x.trim().parse::<u32>().ok());
+    match (it.next(), it.next()) {
+        (Some(Some(w)), Some(Some(h))) if w >= 640 && h >= 480 => (w, h),
+        _ => DEFAULT_RESOLUTION,
+    }
+}
+
+struct Account {
+    uid: u32,
+    gid: u32,
+    home: String,
+    shell: String,
+}
+
+fn get_account(user: &str) -> ResultType<Account> {
+    let name = CString::new(user)?;
+    // only called by the service thread
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+        bail!("No user {}", user);
+    }
+    unsafe {
+        Ok(Account {
+            uid: (*pw).pw_uid,
+            gid: (*pw).pw_gid,
+            home: CStr::from_ptr((*pw).pw_dir).to_string_lossy().to_string(),
+            shell: CStr::from_ptr((*pw).pw_shell).to_string_lossy().to_string(),
+        })
+    }
+}
+
+fn get_uid_min() -> u32 {
+    fs::read_to_string("/etc/login.defs")
+        .ok()
+        .and_then(|defs| {
+            defs.lines().find_map(|line| {
+                let mut it = line.split_whitespace();
+                match (it.next(), it.next()) {
+                    (Some("UID_MIN"), Some(v)) => v.parse().ok(),
+                    _ => None,
+                }
+            })
+        })
+        .unwrap_or(DEFAULT_UID_MIN)
+}
+
+fn check_account(user: &str, account: &Account) -> ResultType<()> {
+    if account.uid < get_uid_min() {
+        bail!("{} is root or a system account", user);
+    }
+    if account.shell.ends_with("nologin") || account.shell.ends_with("false") {
+        bail!("{} can not log in", user);
+    }
+    Ok(())
+}
+
+// not writable by anybody but root, as are the directories above it
+fn is_of_root(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(m) if m.uid() == 0 && m.mode() & 0o022 == 0 => {
+            path.parent().map_or(true, |parent| is_of_root(parent))
+        }
+        _ => false,
+    }
+}
+
+// the trimmed content, None if the file is missing or empty
+fn read_file_of_root(file: &str) -> ResultType<Option<String>> {
+    if !Path::new(file).exists() {
+        return Ok(None);
+    }
+    // the file itself, not a link to it
+    let path = fs::canonicalize(file)?;
+    if !is_of_root(&path) {
+        bail!("{} can be written by others than root", file);
+    }
+    let content = fs::read_to_string(&path)?.trim().to_owned();
+    Ok(Some(content).filter(|x| !x.is_empty()))
+}
+
+fn get_session() -> ResultType<String> {
+    Ok(read_file_of_root(SESSION_FILE)?.unwrap_or_else(|| DEFAULT_SESSION.to_owned()))
+}
+
+fn get_session_user() -> ResultType<String> {
+    match read_file_of_root(USER_FILE)? {
+        Some(user) => Ok(user),
+        None => bail!(
+            "No user for the headless session, write it in {}",
+            USER_FILE
+        ),
+    }
+}
+
+fn create_run_dir() -> ResultType<()> {
+    match fs::symlink_metadata(RUN_DIR) {
+        Ok(m) => {
+            if !m.is_dir() || m.uid() != 0 {
+                bail!("{} is not a directory of root", RUN_DIR);
+            }
+        }
+        Err(_) => fs::DirBuilder::new().mode(0o711).create(RUN_DIR)?,
+    }
+    fs::set_permissions(RUN_DIR, fs::Permissions::from_mode(0o711))?;
+    Ok(())
+}
+
+// a new file of root, never through a link, removing the one of a previous run
+fn create_file(path: &Path) -> ResultType<File> {
+    create_run_dir()?;
+    fs::remove_file(path).ok();
+    Ok(fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?)
+}
+
+fn socket(n: u32) -> PathBuf {
+    PathBuf::from(format!("/tmp/.X11-unix/X{}", n))
+}
+
+fn get_free_display() -> ResultType<u32> {
+    for n in FIRST_DISPLAY..=LAST_DISPLAY {
+        if !socket(n).exists() && !Path::new(&format!("/tmp/.X{}-lock", n)).exists() {
+            return Ok(n);
+        }
+    }
+    bail!("No free X display number")
+}
+
+fn xauthority_path(n: u32) -> PathBuf {
+    PathBuf::from(format!("{}/headless-{}.Xauthority", RUN_DIR, n))
+}
+
+fn xdummy_config_path(n: u32) -> PathBuf {
+    PathBuf::from(format!("{}/xdummy-{}.conf", RUN_DIR, n))
+}
+
+// An Xauthority entry: family, address, display number, name and data,
+// each but the family prefixed by its length, all big endian.
+fn xauthority_entry(n: u32, cookie: &[u8]) -> Vec<u8> {
+    // FamilyWild, for any address
+    let mut entry = vec![0xff, 0xff];
+    let display = n.to_string();
+    let fields: [&[u8]; 4] = [b"", display.as_bytes(), b"MIT-MAGIC-COOKIE-1", cookie];
+    for field in fields.iter() {
+        entry.extend_from_slice(&(field.len() as u16).to_be_bytes());
+        entry.extend_from_slice(field);
+    }
+    entry
+}
+
+// a cookie only the session user and the server know
+fn create_xauthority(n: u32, account: &Account) -> ResultType<PathBuf> {
+    let path = xauthority_path(n);
+    let mut file = create_file(&path)?;
+    file.write_all(&xauthority_entry(n, &randombytes::randombytes(16)))?;
+    if unsafe { libc::fchown(file.as_raw_fd(), account.uid, account.gid) } != 0 {
+        bail!(
+            "Failed to give {} to the session user: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(path)
+}
+
+fn create_xdummy_config(n: u32, width: u32, height: u32) -> ResultType<PathBuf> {
+    let path = xdummy_config_path(n);
+    let config = format!(
+        r#"Section "Device"
+    Identifier "dummy"
+    Driver "dummy"
+    VideoRam 256000
+EndSection
+
+Section "Monitor"
+    Identifier "monitor"
+    HorizSync 5.0 - 1000.0
+    VertRefresh 5.0 - 200.0
+EndSection
+
+Section "Screen"
+    Identifier "screen"
+    Device "dummy"
+    Monitor "monitor"
+    DefaultDepth 24
+    SubSection "Display"
+        Depth 24
+        Virtual {} {}
+    EndSubSection
+EndSection
+"#,
+        width, height
+    );
+    create_file(&path)?.write_all(config.as_bytes())?;
+    Ok(path)
+}
+
+fn start_x_server(n: u32, xauthority: &Path) -> ResultType<Child> {
+    let display = format!(":{}", n);
+    let auth = xauthority.to_string_lossy().to_string();
+    let (width, height) = get_resolution();
+    let mut cmd = if Config::get_option(OPTION_SERVER) == "xdummy" {
+        let config = create_xdummy_config(n, width, height)?;
+        let mut cmd = Command::new("Xorg");
+        cmd.arg(&display).arg("-config").arg(config).args(vec![
+            "-noreset",
+            "-novtswitch",
+            "-sharevts",
+        ]);
+        cmd
+    } else {
+        let mut cmd = Command::new("Xvfb");
+        cmd.arg(&display)
+            .args(vec!["-screen", "0"])
+            .arg(format!("{}x{}x24", width, height))
+            .args(vec!["+extension", "RANDR"]);
+        cmd
+    };
+    let mut child = cmd.args(vec!["-auth", &auth, "-nolisten", "tcp"]).spawn()?;
+    let begin = Instant::now();
+    while !socket(n).exists() {
+        if let Ok(Some(status)) = child.try_wait() {
+            bail!("X server {} exited: {}", display, status);
+        }
+        if begin.elapsed() > START_TIMEOUT {
+            child.kill().ok();
+            bail!("X server {} did not start", display);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(child)
+}
+
+fn start_session(n: u32, xauthority: &Path, user: &str, account: &Account) -> ResultType<Child> {
+    let mut session = get_session()?;
+    // most desktops need a session bus
+    if Command::new("which")
+        .arg("dbus-launch")
+        .output()?
+        .status
+        .success()
+    {
+        session = format!("dbus-launch --exit-with-session {}", session);
+    }
+    log::info!("Start headless session of {} on :{}: {}", user, n, session);
+    let home = &account.home;
+    Ok(Command::new("sudo")
+        .args(vec!["-u", user, "-H", "env"])
+        .arg(format!("DISPLAY=:{}", n))
+        .arg(format!("XAUTHORITY={}", xauthority.to_string_lossy()))
+        .arg(format!("HOME={}", home))
+        .arg(format!("USER={}", user))
+        .arg(format!("XDG_RUNTIME_DIR=/run/user/{}", account.uid))
+        .arg("XDG_SESSION_TYPE=x11")
+        .args(vec!["sh", "-c", &session])
+        .current_dir(home)
+        .spawn()?)
+}
+
+/// A virtual X server and the desktop session in it, both stopped on drop
+pub struct Headless {
+    n: u32,
+    xauthority: PathBuf,
+    x_server: Child,
+    session: Child,
+    session_started: Instant,
+    user: String,
+    account: Account,
+}
+
+impl Headless {
+    pub fn start() -> ResultType<Self> {
+        let user = get_session_user()?;
+        let account = get_account(&user)?;
+        check_account(&user, &account)?;
+        let n = get_free_display()?;
+        let xauthority = create_xauthority(n, &account)?;
+        let mut x_server = start_x_server(n, &xauthority)?;
+        let session = match start_session(n, &xauthority, &user, &account) {
+            Ok(session) => session,
+            Err(err) => {
+                x_server.kill().ok();
+                x_server.wait().ok();
+                return Err(err);
+            }
+        };
+        log::info!("Headless X server on :{} for {}", n, user);
+        Ok(Self {
+            n,
+            xauthority,
+            x_server,
+            session,
+            session_started: Instant::now(),
+            user,
+            account,
+        })
+    }
+
+    pub fn display(&self) -> String {
+        format!(":{}", self.n)
+    }
+
+    pub fn xauthority(&self) -> String {
+        self.xauthority.to_string_lossy().to_string()
+    }
+
+    /// The environment of the server serving this X server
+    pub fn set_env(&self) {
+        std::env::set_var("DISPLAY", self.display());
+        std::env::set_var("XAUTHORITY", self.xauthority());
+        std::env::set_var(ENV_USER, &self.user);
+        std::env::set_var(ENV_UID, self.account.uid.to_string());
+    }
+
+    /// False if the X server died, a session logged out is started again,
+    /// not sooner than `retry_interval` seconds after the previous start
+    pub fn check(&mut self, retry_interval: u64) -> bool {
+        if let Ok(Some(status)) = self.x_server.try_wait() {
+            log::error!("Headless X server :{} exited: {}", self.n, status);
+            return false;
+        }
+        if let Ok(Some(status)) = self.session.try_wait() {
+            if self.session_started.elapsed().as_secs() <= retry_interval {
+                return true;
+            }
+            log::info!("Headless session exited: {}, start it again", status);
+            self.session_started = Instant::now();
+            match start_session(self.n, &self.xauthority, &self.user, &self.account) {
+                Ok(session) => self.session = session,
+                Err(err) => {
+                    log::error!("Failed to start the headless session: {}", err);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Drop for Headless {
+    fn drop(&mut self) {
+        log::info!("Stop headless X server :{}", self.n);
+        // sudo passes SIGTERM on to the session, not SIGKILL
+        unsafe {
+            libc::kill(self.session.id() as _, libc::SIGTERM);
+        }
+        self.x_server.kill().ok();
+        self.x_server.wait().ok();
+        let begin = Instant::now();
+        while let Ok(None) = self.session.try_wait() {
+            if begin.elapsed() > STOP_TIMEOUT {
+                log::warn!("Headless session did not exit, kill it");
+                self.session.kill().ok();
+                self.session.wait().ok();
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        fs::remove_file(&self.xauthority).ok();
+        fs::remove_file(xdummy_config_path(self.n)).ok();
+    }
+}
+
+/// Back to the environment of a seat0 session
+pub fn clear_env() {
+    std::env::remove_var(ENV_USER);
+    std::env::remove_var(ENV_UID);
+}
@@ -0,0 +1,268 @@
+// Wayland sessions, with the "wayland" feature: the screen is captured through the ScreenCast
+// portal, and the input injected through the RemoteDesktop portal of the same session, see
+// scrap::wayland::pipewire. The portal asks the session user before starting; the token it gives
+// back is kept in the "wayland-restore-token" option, to skip that the next time.
+// The cursor comes as metadata of the streams when the portal can, see scrap::wayland::cursor.
+
+use super::CursorData;
+use enigo::{Key, KeyboardControllable, MouseButton, MouseControllable};
+use hbb_common::{allow_err, bail, config::Config, log, ResultType};
+use scrap::wayland::{cursor, pipewire};
+
+pub const OPTION_RESTORE_TOKEN: &'static str = "wayland-restore-token";
+// evdev codes
+const BTN_LEFT: i32 = 0x110;
+const BTN_RIGHT: i32 = 0x111;
+const BTN_MIDDLE: i32 = 0x112;
+// X keycodes are the evdev ones plus 8
+const X_KEYCODE_OFFSET: i32 = 8;
+
+pub fn is_wayland() -> bool {
+    scrap::is_wayland()
+}
+
+pub fn get_cursor_pos() -> Option<(i32, i32)> {
+    cursor::get_position()
+}
+
+pub fn get_cursor() -> ResultType<Option<u64>> {
+    Ok(cursor::get_image_id())
+}
+
+pub fn get_cursor_data(hcursor: u64) -> ResultType<CursorData> {
+    match cursor::get_image() {
+        Some(image) if image.id == hcursor => Ok(CursorData {
+            id: image.id,
+            hotx: image.hotx,
+            hoty: image.hoty,
+            width: image.width,
+            height: image.height,
+            colors: image.colors,
+            ..Default::default()
+        }),
+        _ => bail!("Failed to get cursor image of {}", hcursor),
+    }
+}
+
+/// Wait for the portal session asked by the first try to get the displays, the user may take
+/// minutes to accept. True if there was one to wait for.
+pub async fn wait_portal_session() -> bool {
+    let mut waited = false;
+    while pipewire::is_requesting() {
+        waited = true;
+        hbb_common::sleep(0.5).await;
+    }
+    waited
+}
+
+/// Before a portal session is requested
+pub fn load_restore_token() {
+    pipewire::set_restore_token(Config::get_option(OPTION_RESTORE_TOKEN));
+}
+
+/// After a portal session is requested, a token is only good for one session
+pub fn save_restore_token() {
+    let token = pipewire::get_restore_token();
+    if token != Config::get_option(OPTION_RESTORE_TOKEN) {
+        log::info!("Save the restore token of the portal");
+        Config::set_option(OPTION_RESTORE_TOKEN.to_owned(), token);
+    }
+}
+
+fn keysym(key: Key) -> Option<i32> {
+    #[allow(deprecated)]
+    let keysym = match key {
+        // Latin-1 keysyms are the characters, the others are offset
+        Key::Layout(c) => match c as u32 {
+            x @ 0x20..=0x7e | x @ 0xa0..=0xff => x,
+            x => 0x0100_0000 | x,
+        },
+        Key::Alt => 0xffe9,
+        Key::Backspace => 0xff08,
+        Key::CapsLock => 0xffe5,
+        Key::Control => 0xffe3,
+        Key::Delete => 0xffff,
+        Key::DownArrow => 0xff54,
+        Key::End => 0xff57,
+        Key::Escape => 0xff1b,
+        Key::F1 => 0xffbe,
+        Key::F2 => 0xffbf,
+        Key::F3 => 0xffc0,
+        Key::F4 => 0xffc1,
+        Key::F5 => 0xffc2,
+        Key::F6 => 0xffc3,
+        Key::F7 => 0xffc4,
+        Key::F8 => 0xffc5,
+        Key::F9 => 0xffc6,
+        Key::F10 => 0xffc7,
+        Key::F11 => 0xffc8,
+        Key::F12 => 0xffc9,
+        Key::Home => 0xff50,
+        Key::LeftArrow => 0xff51,
+        Key::PageDown => 0xff56,
+        Key::PageUp => 0xff55,
+        Key::Return => 0xff0d,
+        Key::RightArrow => 0xff53,
+        Key::Shift => 0xffe1,
+        Key::Space => 0x20,
+        Key::Tab => 0xff09,
+        Key::UpArrow => 0xff52,
+        Key::Numpad0 => 0xffb0,
+        Key::Numpad1 => 0xffb1,
+        Key::Numpad2 => 0xffb2,
+        Key::Numpad3 => 0xffb3,
+        Key::Numpad4 => 0xffb4,
+        Key::Numpad5 => 0xffb5,
+        Key::Numpad6 => 0xffb6,
+        Key::Numpad7 => 0xffb7,
+        Key::Numpad8 => 0xffb8,
+        Key::Numpad9 => 0xffb9,
+        Key::Decimal => 0xffae,
+        Key::Cancel => 0xff69,
+        Key::Clear => 0xff0b,
+        Key::Pause => 0xff13,
+        Key::Kana => 0xff2d,
+        Key::Hangul => 0xff31,
+        Key::Hanja => 0xff34,
+        Key::Kanji => 0xff21,
+        Key::Select => 0xff60,
+        Key::Print | Key::Snapshot => 0xff61,
+        Key::Execute => 0xff62,
+        Key::Insert => 0xff63,
+        Key::Help => 0xff6a,
+        Key::Separator => 0xffac,
+        Key::Scroll => 0xff14,
+        Key::NumLock => 0xff7f,
+        Key::RWin => 0xffec,
+        Key::Apps => 0xff67,
+        Key::Multiply => 0xffaa,
+        Key::Add => 0xffab,
+        Key::Subtract => 0xffad,
+        Key::Divide => 0xffaf,
+        Key::Equals => 0xffbd,
+        Key::NumpadEnter => 0xff8d,
+        Key::RightShift => 0xffe2,
+        Key::RightControl => 0xffe4,
+        Key::RightAlt => 0xffea,
+        Key::Command | Key::Super | Key::Windows | Key::Meta => 0xffeb,
+        _ => return None,
+    };
+    Some(keysym as _)
+}
+
+/// The keyboard through the RemoteDesktop portal. The portal has no key state to query, so the
+/// keys pressed and the locks toggled through it are tracked here.
+#[derive(Default)]
+pub struct PortalKeyboard {
+    down: Vec<Key>,
+    caps_lock: bool,
+    num_lock: bool,
+}
+
+impl PortalKeyboard {
+    fn send(&mut self, key: Key, pressed: bool) -> enigo::ResultType {
+        if pressed {
+            match key {
+                Key::CapsLock => self.caps_lock = !self.caps_lock,
+                Key::NumLock => self.num_lock = !self.num_lock,
+                _ => {}
+            }
+            if !self.down.contains(&key) {
+                self.down.push(key);
+            }
+        } else {
+            self.down.retain(|x| *x != key);
+        }
+        if let Key::Raw(code) = key {
+            return pipewire::notify_keyboard_keycode(code as i32 - X_KEYCODE_OFFSET, pressed);
+        }
+        match keysym(key) {
+            Some(keysym) => pipewire::notify_keyboard_keysym(keysym, pressed),
+            None => Ok(()),
+        }
+    }
+}
+
+impl KeyboardControllable for PortalKeyboard {
+    fn key_sequence(&mut self, sequence: &str) {
+        for c in sequence.chars() {
+            self.key_click(Key::Layout(c));
+        }
+    }
+
+    fn key_down(&mut self, key: Key) -> enigo::ResultType {
+        self.send(key, true)
+    }
+
+    fn key_up(&mut self, key: Key) {
+        allow_err!(self.send(key, false));
+    }
+
+    fn key_click(&mut self, key: Key) {
+        allow_err!(self.send(key, true));
+        allow_err!(self.send(key, false));
+    }
+
+    fn get_key_state(&mut self, key: Key) -> bool {
+        match key {
+            Key::CapsLock => self.caps_lock,
+            Key::NumLock => self.num_lock,
+            _ => self.down.contains(&key),
+        }
+    }
+}
+
+/// The mouse through the RemoteDesktop portal
+pub struct PortalMouse;
+
+impl PortalMouse {
+    fn send(&mut self, button: MouseButton, pressed: bool) -> enigo::ResultType {
+        let (code, steps) = match button {
+            MouseButton::Left => (BTN_LEFT, 0),
+            MouseButton::Right => (BTN_RIGHT, 0),
+            MouseButton::Middle => (BTN_MIDDLE, 0),
+            MouseButton::ScrollUp => (0, -1),
+            MouseButton::ScrollDown => (0, 1),
+            MouseButton::ScrollLeft => (1, -1),
+            MouseButton::ScrollRight => (1, 1),
+        };
+        if steps == 0 {
+            pipewire::notify_pointer_button(code, pressed)
+        } else if pressed {
+            pipewire::notify_pointer_axis_discrete(code as _, steps)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl MouseControllable for PortalMouse {
+    fn mouse_move_to(&mut self, x: i32, y: i32) {
+        allow_err!(pipewire::notify_pointer_motion_absolute(x, y));
+    }
+
+    fn mouse_move_relative(&mut self, x: i32, y: i32) {
+        allow_err!(pipewire::notify_pointer_motion(x, y));
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) -> enigo::ResultType {
+        self.send(button, true)
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) {
+        allow_err!(self.send(button, false));
+    }
+
+    fn mouse_click(&mut self, button: MouseButton) {
+        allow_err!(self.send(button, true));
+        allow_err!(self.send(button, false));
+    }
+
+    fn mouse_scroll_x(&mut self, length: i32) {
+        allow_err!(pipewire::notify_pointer_axis_discrete(1, length));
+    }
+
+    fn mouse_scroll_y(&mut self, length: i32) {
+        allow_err!(pipewire::notify_pointer_axis_discrete(0, length));
+    }
+}
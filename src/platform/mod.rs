@@ -14,6 +14,12 @@ pub mod macos;
 #[cfg(target_os = "linux")]
 pub mod linux;
 
+#[cfg(target_os = "linux")]
+pub mod linux_headless;
+
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+pub mod linux_wayland;
+
 use hbb_common::{message_proto::CursorData, ResultType};
 #[cfg(not(target_os = "macos"))]
 const SERVICE_INTERVAL: u64 = 300;
@@ -6,7 +6,7 @@ use crate::{
 use futures::{SinkExt, StreamExt};
 use hbb_common::{
     allow_err,
-    anyhow::{anyhow, bail},
+    anyhow::bail,
     config::{self, Config, CONNECT_TIMEOUT, REG_INTERVAL, RENDEZVOUS_PORT},
     futures::future::join_all,
     log,
@@ -21,7 +21,7 @@ use hbb_common::{
 };
 use soketto::{handshake::ServerResponse, Data};
 use std::{
-    net::{SocketAddr, ToSocketAddrs},
+    net::SocketAddr,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex,
@@ -64,7 +64,9 @@ impl RendezvousMediator {
         }
         loop {
             Config::reset_online();
-            if Config::get_option("stop-service").is_empty() {
+            if Config::get_option("stop-service").is_empty()
+                && crate::server::schedule::should_advertise()
+            {
                 let mut futs = Vec::new();
                 if let Some(servers) = Config::get_rendezvous_servers().await {
                     SHOULD_EXIT.store(false, Ordering::SeqCst);
@@ -120,6 +122,10 @@ impl RendezvousMediator {
                     if SHOULD_EXIT.load(Ordering::SeqCst) {
                         break;
                     }
+                    if !crate::server::schedule::should_advertise() {
+                        log::info!("outside the access schedule, leaving {}", host);
+                        break;
+                    }
                     let now = SystemTime::now();
                     if now.duration_since(last_timer).map(|d| d < TIMER_OUT).unwrap_or(false) {
                         // a workaround of tokio timer bug
@@ -180,12 +186,37 @@ impl RendezvousMediator {
                                                 stream,
                                                 addr,
                                                 true,
+                                                false,
                                             )
                                             .await;
                                         });
                                     }
                                 }
                             }
+                        } else if let Ok(ws_relay_connection) =
+                            serde_json::from_str::<rendezvous_messages::WsRelayConnection>(&msg)
+                        {
+                            let server_clone = server.clone();
+                            let uri = ws_relay_connection.uri;
+                            tokio::spawn(async move {
+                                match crate::ws_tunnel::join_tunnel(&uri).await {
+                                    Ok((stream, addr)) => {
+                                        let _ = crate::create_tcp_connection(
+                                            server_clone,
+                                            stream,
+                                            addr,
+                                            true,
+                                            false,
+                                        )
+                                        .await;
+                                    }
+                                    Err(err) => log::error!(
+                                        "Failed to join websocket relay {}: {}",
+                                        uri,
+                                        err
+                                    ),
+                                }
+                            });
                         }
                     }
                     Err(e) => bail!("Failed to receive next {}", e),
@@ -237,13 +268,7 @@ async fn create_websocket_(
     let host = crate::check_port(split[1], RENDEZVOUS_PORT);
 
     log::info!("Trying to connect websocket to {}", host);
-    let addr = host
-        .to_socket_addrs()?
-        .next()
-        .ok_or(anyhow!("Cannot resolve dns for the host"))?;
-    log::info!("Parsed addr: {:?}", &addr);
-
-    let socket = TcpStream::connect(addr).await?;
+    let socket = socket_client::connect_tcp_stream(&host, CONNECT_TIMEOUT).await?;
     let local_ip = socket.local_addr().unwrap().ip();
     let uri = format!("{}://{}/?user={}", scheme, host, Config::get_id());
 
@@ -313,6 +338,7 @@ async fn direct_server(server: ServerPtr) {
                             hbb_common::Stream::from(stream, local_addr),
                             addr,
                             false,
+                            true,
                         )
                         .await
                     );
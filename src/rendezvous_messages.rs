@@ -76,6 +76,24 @@ impl<'a> RelayReady<'a> {
     }
 }
 
+// Sent by the initiator, indicates the websocket relay uri to join when the other ways fail
+#[derive(Serialize, Deserialize)]
+pub struct WsRelayConnection<'a> {
+    protocol: &'a str,
+    endpoint: &'a str,
+    pub uri: String,
+}
+
+impl<'a> WsRelayConnection<'a> {
+    pub fn new(endpoint: &'a str, uri: &str) -> Self {
+        Self {
+            protocol: PROTOCOL,
+            endpoint,
+            uri: uri.to_owned(),
+        }
+    }
+}
+
 pub trait ToJson {
     fn to_json(&self) -> String;
 }
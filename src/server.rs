@@ -36,8 +36,14 @@ pub const NAME_POS: &'static str = "";
 }
 }
 
+pub mod acl;
+pub mod approval;
+pub mod audit;
+pub mod login_guard;
+pub mod schedule;
 mod connection;
 mod service;
+mod video_qos;
 pub mod video_service;
 
 
@@ -85,10 +91,17 @@ pub fn new() -> ServerPtr {
 pub async fn accept(listener: TcpListener, server: ServerPtr, secure: bool) -> ResultType<()> {
     let local_addr = listener.local_addr()?;
     log::info!("Server listening on: {}", &local_addr);
-    if let Ok((stream, _)) = timeout(CONNECT_TIMEOUT, listener.accept()).await? {
+    if let Ok((stream, peer_addr)) = timeout(CONNECT_TIMEOUT, listener.accept()).await? {
         stream.set_nodelay(true).ok();
         let stream_addr = stream.local_addr()?;
-        create_tcp_connection(server, Stream::from(stream, stream_addr), local_addr, secure).await?;
+        create_tcp_connection(
+            server,
+            Stream::from(stream, stream_addr),
+            peer_addr,
+            secure,
+            true,
+        )
+        .await?;
     }
     Ok(())
 }
@@ -117,6 +130,7 @@ pub async fn create_tcp_connection(
     stream: Stream,
     addr: SocketAddr,
     secure: bool,
+    direct: bool,
 ) -> ResultType<()> {
     let mut stream = stream;
     check_privacy_mode_on(&mut stream).await?;
@@ -187,7 +201,7 @@ pub async fn create_tcp_connection(
         }
     }
 
-    Connection::start(addr, stream, id, Arc::downgrade(&server)).await;
+    Connection::start(addr, stream, id, Arc::downgrade(&server), direct).await;
     Ok(())
 }
 
@@ -328,7 +342,7 @@ pub async fn start_server(is_server: bool) {
             }
         });
         input_service::fix_key_down_timeout_loop();
-        #[cfg(target_os = "macos")]
+        #[cfg(any(target_os = "macos", all(target_os = "linux", feature = "wayland")))]
         tokio::spawn(async { sync_and_watch_config_dir().await });
         crate::RendezvousMediator::start_all().await;
     } else {
@@ -372,7 +386,7 @@ pub async fn start_server(is_server: bool) {
     }
 }
 
-#[cfg(target_os = "macos")]
+#[cfg(any(target_os = "macos", all(target_os = "linux", feature = "wayland")))]
 async fn sync_and_watch_config_dir() {
     if crate::platform::is_root() {
         return;
@@ -0,0 +1,155 @@
+// Access control rules in the "access-control" option, separated by newline, comma or semicolon:
+//   allow 123456789
+//   deny 10.0.0.0/8
+//   allow 2001:db8::/32
+// The target of a rule is "*", a peer ID, an IP address or a CIDR range.
+// A matching deny always wins. Once there is any allow rule, the peer has to match one of them.
+// IP rules are only checked on direct connections, a relayed one comes from the relay's address.
+
+use hbb_common::{bail, config::Config, log, socket_client::ip_in_cidr, ResultType};
+use std::net::IpAddr;
+
+pub const OPTION_ACCESS_CONTROL: &'static str = "access-control";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Any,
+    Id(String),
+    Ip(String), // address or cidr
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    allow: bool,
+    target: Target,
+}
+
+impl Rule {
+    fn matches(&self, peer_id: &str, ip: Option<IpAddr>) -> bool {
+        match &self.target {
+            Target::Any => true,
+            Target::Id(id) => id.eq_ignore_ascii_case(peer_id.trim()),
+            Target::Ip(cidr) => ip.map(|ip| ip_in_cidr(ip, cidr)).unwrap_or(false),
+        }
+    }
+}
+
+fn is_ip_or_cidr(s: &str) -> bool {
+    let mut it = s.splitn(2, '/');
+    let ip = match it.next().and_then(|x| x.parse::<IpAddr>().ok()) {
+        Some(ip) => ip,
+        None => return false,
+    };
+    match it.next() {
+        None => true,
+        Some(bits) => match bits.parse::<u32>() {
+            Ok(bits) => bits <= if ip.is_ipv4() { 32 } else { 128 },
+            Err(_) => false,
+        },
+    }
+}
+
+fn parse_rule(s: &str) -> ResultType<Rule> {
+    let mut it = s.split_whitespace();
+    let allow = match it.next().map(|x| x.to_lowercase()).as_deref() {
+        Some("allow") => true,
+        Some("deny") => false,
+        _ => bail!("Invalid rule, should start with allow or deny: {}", s),
+    };
+    let target = match it.next() {
+        Some("*") => Target::Any,
+        Some(x) if is_ip_or_cidr(x) => Target::Ip(x.to_owned()),
+        Some(x) if x.contains('/') || x.parse::<IpAddr>().is_ok() => {
+            bail!("Invalid IP range: {}", x)
+        }
+        Some(x) if x.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {
+            Target::Id(x.to_owned())
+        }
+        Some(x) => bail!("Invalid ID, IP or IP range: {}", x),
+        None => bail!("Missing ID, IP or IP range: {}", s),
+    };
+    if it.next().is_some() {
+        bail!("Invalid rule: {}", s);
+    }
+    Ok(Rule { allow, target })
+}
+
+pub fn parse(rules: &str) -> ResultType<Vec<Rule>> {
+    rules
+        .split(|c| c == '\n' || c == ',' || c == ';')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(parse_rule)
+        .collect()
+}
+
+pub fn check(rules: &[Rule], peer_id: &str, ip: Option<IpAddr>) -> bool {
+    if rules.iter().any(|r| !r.allow && r.matches(peer_id, ip)) {
+        return false;
+    }
+    let mut allows = rules.iter().filter(|r| r.allow).peekable();
+    allows.peek().is_none() || allows.any(|r| r.matches(peer_id, ip))
+}
+
+/// Evaluated at login, `ip` is None for relayed connections.
+pub fn is_allowed(peer_id: &str, ip: Option<IpAddr>) -> bool {
+    let rules = Config::get_option(OPTION_ACCESS_CONTROL);
+    if rules.trim().is_empty() {
+        return true;
+    }
+    match parse(&rules) {
+        Ok(rules) => check(&rules, peer_id, ip),
+        Err(err) => {
+            // fail closed rather than let everyone in because of a typo
+            log::error!("Invalid access control rules: {}", err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        s.parse().ok()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("").unwrap().len(), 0);
+        assert_eq!(
+            parse("allow 123456789\n deny 10.0.0.0/8; allow fd00::/8, deny *")
+                .unwrap()
+                .len(),
+            4
+        );
+        assert!(parse("permit 123").is_err());
+        assert!(parse("allow").is_err());
+        assert!(parse("allow 10.0.0.0/33").is_err());
+        assert!(parse("allow 1.2.3.4 5").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let rules = parse(
+            "allow 123456789\nallow 192.168.1.0/24\nallow 2001:db8::/32\ndeny 192.168.1.13",
+        )
+        .unwrap();
+        assert!(check(&rules, "123456789", None));
+        assert!(!check(&rules, "987654321", None));
+        assert!(check(&rules, "987654321", ip("192.168.1.20")));
+        assert!(!check(&rules, "987654321", ip("192.168.1.13")));
+        assert!(!check(&rules, "123456789", ip("192.168.1.13")));
+        assert!(check(&rules, "987654321", ip("2001:db8::1")));
+        assert!(check(&rules, "987654321", ip("::ffff:192.168.1.20")));
+        assert!(!check(&rules, "987654321", ip("10.0.0.1")));
+
+        let rules = parse("deny 111111111").unwrap();
+        assert!(check(&rules, "123456789", None));
+        assert!(!check(&rules, "111111111", ip("10.0.0.1")));
+
+        let rules = parse("deny *\nallow 123456789").unwrap();
+        assert!(!check(&rules, "123456789", None));
+    }
+}
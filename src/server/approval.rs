@@ -0,0 +1,236 @@
+// Automatic answers to logins without password, which otherwise wait for a click in the
+// connection manager. Rules in the "approval-rules" option, separated by newline or semicolon,
+// the first matching one wins:
+//   tz UTC+02:00
+//   view-only known
+//   approve * mon-fri 09:00-17:00
+//   deny unknown
+// The action is approve, view-only (approve, but without control), deny or ask (the default).
+// The target is "*", "known" for the IDs in the address book, "unknown" for the others, or an ID.
+// The ID is the one the peer says it has, nothing proves it, so "approve" is refused for "known"
+// or an ID, anyone could claim it; "approve *" or "approve unknown" say what they give.
+// Hours are optional and written like in the access schedule, in the timezone of "tz".
+// While 2FA is on, approving still goes to the connection manager.
+
+use super::schedule::{self, Window};
+use crate::ipc::Data;
+use hbb_common::{bail, config::Config, log, ResultType};
+
+pub const OPTION_APPROVAL_RULES: &'static str = "approval-rules";
+// IDs of the address book, kept up to date by the UI, the server itself can not fetch it
+pub const OPTION_ADDRESS_BOOK_IDS: &'static str = "address-book-ids";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Ask,
+    Approve,
+    ViewOnly,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Any,
+    Known,
+    Unknown,
+    Id(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    decision: Decision,
+    target: Target,
+    window: Option<Window>,
+    text: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rules {
+    offset: Option<i32>,
+    rules: Vec<Rule>,
+}
+
+fn parse_rule(s: &str) -> ResultType<Rule> {
+    let mut it = s.split_whitespace();
+    let decision = match it.next().map(|x| x.to_lowercase()).as_deref() {
+        Some("approve") => Decision::Approve,
+        Some("view-only") => Decision::ViewOnly,
+        Some("deny") => Decision::Deny,
+        Some("ask") => Decision::Ask,
+        _ => bail!(
+            "Invalid rule, should start with approve, view-only, deny or ask: {}",
+            s
+        ),
+    };
+    let target = match it.next() {
+        Some("*") => Target::Any,
+        Some(x) if x.eq_ignore_ascii_case("known") => Target::Known,
+        Some(x) if x.eq_ignore_ascii_case("unknown") => Target::Unknown,
+        Some(x) if x.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {
+            Target::Id(x.to_owned())
+        }
+        Some(x) => bail!("Invalid ID: {}", x),
+        None => bail!("Missing target: {}", s),
+    };
+    if decision == Decision::Approve {
+        if let Target::Known | Target::Id(_) = target {
+            bail!(
+                "The ID is claimed by the peer and not verified, anyone could use it, \
+                 approve only * or unknown, or use view-only: {}",
+                s
+            );
+        }
+    }
+    let window = match (it.next(), it.next(), it.next()) {
+        (None, _, _) => None,
+        (Some(days), Some(times), None) => Some(schedule::parse_window(days, times)?),
+        _ => bail!("Invalid hours, should be like mon-fri 09:00-17:00: {}", s),
+    };
+    Ok(Rule {
+        decision,
+        target,
+        window,
+        text: s.to_owned(),
+    })
+}
+
+pub fn parse(s: &str) -> ResultType<Rules> {
+    let mut rules = Rules::default();
+    for line in s
+        .split(|c| c == '\n' || c == ';')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+    {
+        let mut it = line.split_whitespace();
+        if it.next().map(|x| x.eq_ignore_ascii_case("tz")) == Some(true) {
+            match (it.next(), it.next()) {
+                (Some(tz), None) => rules.offset = schedule::parse_offset(tz)?,
+                _ => bail!("Invalid timezone: {}", line),
+            }
+        } else {
+            rules.rules.push(parse_rule(line)?);
+        }
+    }
+    Ok(rules)
+}
+
+impl Rules {
+    /// The first rule matching, `now` is the weekday (0 is Monday) and minute of the day
+    fn find(&self, peer_id: &str, known: &[String], now: (usize, u32)) -> Option<&Rule> {
+        let peer_id = peer_id.trim();
+        let is_known = known.iter().any(|x| x.trim().eq_ignore_ascii_case(peer_id));
+        self.rules.iter().find(|r| {
+            let target = match &r.target {
+                Target::Any => true,
+                Target::Known => is_known,
+                Target::Unknown => !is_known,
+                Target::Id(id) => id.eq_ignore_ascii_case(peer_id),
+            };
+            target
+                && r.window
+                    .as_ref()
+                    .map(|w| w.is_open(now.0, now.1))
+                    .unwrap_or(true)
+        })
+    }
+}
+
+fn get_known_ids() -> Vec<String> {
+    Config::get_option(OPTION_ADDRESS_BOOK_IDS)
+        .split(',')
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Answer a `Data::Login` that is not authorized yet, without the connection manager's UI.
+pub fn decide(login: &Data) -> Decision {
+    let (id, peer_id, name) = match login {
+        Data::Login {
+            id,
+            peer_id,
+            name,
+            authorized: false,
+            ..
+        } => (id, peer_id, name),
+        _ => return Decision::Ask,
+    };
+    let s = Config::get_option(OPTION_APPROVAL_RULES);
+    if s.trim().is_empty() {
+        return Decision::Ask;
+    }
+    let rules = match parse(&s) {
+        Ok(rules) => rules,
+        Err(err) => {
+            // leave it to the human rather than guess
+            log::error!("Invalid approval rules: {}", err);
+            return Decision::Ask;
+        }
+    };
+    let now = schedule::now(rules.offset);
+    let (mut decision, rule) = match rules.find(peer_id, &get_known_ids(), now) {
+        Some(rule) => (rule.decision, rule.text.as_str()),
+        None => (Decision::Ask, "no rule"),
+    };
+    if decision == Decision::Approve || decision == Decision::ViewOnly {
+        if crate::two_factor_auth::utils::is_2fa_enabled() {
+            decision = Decision::Ask;
+        }
+    }
+    log::info!(
+        "#{} login of {} ({}) without password: {:?} by {}",
+        id,
+        peer_id,
+        name,
+        decision,
+        rule
+    );
+    decision
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("").unwrap(), Rules::default());
+        let rules = parse(
+            "tz UTC+01:00\nview-only known; approve * mon-fri 09:00-17:00\ndeny unknown\nask 123",
+        )
+        .unwrap();
+        assert_eq!(rules.offset, Some(3600));
+        assert_eq!(rules.rules.len(), 4);
+        assert_eq!(rules.rules[1].decision, Decision::Approve);
+        assert!(parse("allow *").is_err());
+        assert!(parse("approve").is_err());
+        assert!(parse("approve * mon-fri").is_err());
+        assert!(parse("approve * mon-fri 9-25").is_err());
+        assert!(parse("deny 1.2.3.4").is_err());
+        assert!(parse("approve known").is_err());
+        assert!(parse("approve 123 mon-fri 09:00-17:00").is_err());
+        assert!(parse("approve unknown").is_ok());
+    }
+
+    #[test]
+    fn test_find() {
+        let rules = parse("view-only known\napprove * mon-fri 09:00-17:00\ndeny unknown").unwrap();
+        let known = vec!["123456789".to_owned()];
+        let check = |id: &str, now: (usize, u32)| {
+            rules
+                .find(id, &known, now)
+                .map(|r| r.decision)
+                .unwrap_or(Decision::Ask)
+        };
+        assert_eq!(check("123456789", (6, 0)), Decision::ViewOnly);
+        assert_eq!(check("987654321", (0, 10 * 60)), Decision::Approve);
+        assert_eq!(check("987654321", (0, 18 * 60)), Decision::Deny);
+        assert_eq!(check("987654321", (5, 10 * 60)), Decision::Deny);
+        let rules = parse("deny 111").unwrap();
+        assert!(rules.find("222", &known, (0, 0)).is_none());
+        let rules = parse("view-only 111\napprove *").unwrap();
+        let check = |id: &str| rules.find(id, &known, (0, 0)).unwrap().decision;
+        assert_eq!(check("111"), Decision::ViewOnly);
+        assert_eq!(check("222"), Decision::Approve);
+    }
+}
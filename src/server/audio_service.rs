@@ -13,12 +13,17 @@
 // https://github.com/krruzic/pulsectl
 
 use super::*;
-use magnum_opus::{Application::*, Channels::*, Encoder};
-use std::sync::atomic::{AtomicBool, Ordering};
+use magnum_opus::{Application::*, Bitrate, Channels::*, Encoder};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 
 pub const NAME: &'static str = "audio";
 pub const AUDIO_DATA_SIZE_U8: usize = 960 * 4; // 10ms in 48000 stereo
 static RESTARTING: AtomicBool = AtomicBool::new(false);
+// the time (ms) of the last frame dropped because a connection's queue was full
+static LAST_QUEUE_FULL: AtomicI64 = AtomicI64::new(0);
+static CONGESTED: AtomicBool = AtomicBool::new(false);
+const CONGESTION_TIMEOUT: i64 = 3_000;
+const CONGESTED_BITRATE: i32 = 24_000;
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
 pub fn new() -> GenericService {
@@ -34,6 +39,26 @@ pub fn new() -> GenericService {
     sp
 }
 
+pub fn notify_queue_full(conn_id: i32) {
+    log::trace!("#{} audio queue full, frame dropped", conn_id);
+    LAST_QUEUE_FULL.store(crate::get_time(), Ordering::SeqCst);
+}
+
+// lower the bitrate while any connection can not keep up
+fn check_congestion(encoder: &mut Encoder) {
+    let congested =
+        crate::get_time() - LAST_QUEUE_FULL.load(Ordering::SeqCst) < CONGESTION_TIMEOUT;
+    if congested != CONGESTED.swap(congested, Ordering::SeqCst) {
+        log::debug!("Audio congested: {}", congested);
+        let bitrate = if congested {
+            Bitrate::Bits(CONGESTED_BITRATE)
+        } else {
+            Bitrate::Auto
+        };
+        allow_err!(encoder.set_bitrate(bitrate));
+    }
+}
+
 pub fn restart() {
     log::info!("restart the audio service, freezing now...");
     if RESTARTING.load(Ordering::SeqCst) {
@@ -54,6 +79,7 @@ mod pa_impl {
         unsafe {
             AUDIO_ZERO_COUNT = 0;
         }
+        CONGESTED.store(false, Ordering::SeqCst);
         let mut encoder = Encoder::new(crate::platform::PA_SAMPLE_RATE, Stereo, LowDelay)?;
         #[cfg(target_os = "linux")]
         allow_err!(
@@ -235,6 +261,7 @@ mod cpal_impl {
         unsafe {
             AUDIO_ZERO_COUNT = 0;
         }
+        CONGESTED.store(false, Ordering::SeqCst);
         let mut encoder = Encoder::new(
             sample_rate,
             if config.channels() > 1 { Stereo } else { Mono },
@@ -331,6 +358,7 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
             AUDIO_ZERO_COUNT += 1;
         }
     }
+    check_congestion(encoder);
     #[cfg(target_os = "android")]
     {
         // the permitted opus data size are 120, 240, 480, 960, 1920, and 2880
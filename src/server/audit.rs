@@ -0,0 +1,157 @@
+// Audit log of the incoming connections, one JSON object per line in audit.jsonl of the log
+// directory, with "time" (ms since epoch), "conn" (connection id) and "event" in every line.
+// Once the file reaches "audit-log-max-size" MB (10 by default) it is rotated to audit.1.jsonl,
+// keeping the last KEEP files. Set "disable-audit-log" to Y to turn it off.
+
+use hbb_common::{config::Config, log};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+};
+
+pub const OPTION_MAX_SIZE: &'static str = "audit-log-max-size";
+pub const OPTION_DISABLE: &'static str = "disable-audit-log";
+const DEFAULT_MAX_SIZE: u64 = 10;
+const KEEP: usize = 5;
+
+lazy_static::lazy_static! {
+    static ref LOCK: Mutex<()> = Default::default();
+    // the lines are written by a thread of their own, the connections never wait for the disk
+    static ref WRITER: Mutex<mpsc::Sender<String>> = Mutex::new(start_writer());
+}
+
+fn file_in(dir: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        dir.join("audit.jsonl")
+    } else {
+        dir.join(format!("audit.{}.jsonl", n))
+    }
+}
+
+fn file(n: usize) -> PathBuf {
+    file_in(&Config::log_path(), n)
+}
+
+fn max_size() -> u64 {
+    Config::get_option(OPTION_MAX_SIZE)
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|x| *x > 0)
+        .unwrap_or(DEFAULT_MAX_SIZE)
+        * 1024
+        * 1024
+}
+
+fn rotate(dir: &Path) {
+    for n in (0..KEEP).rev() {
+        let from = file_in(dir, n);
+        if from.exists() {
+            fs::rename(&from, file_in(dir, n + 1)).ok();
+        }
+    }
+}
+
+fn start_writer() -> mpsc::Sender<String> {
+    let (tx, rx) = mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        for line in rx {
+            let _lock = LOCK.lock().unwrap();
+            let dir = Config::log_path();
+            if let Err(err) = write(&dir, &line, max_size()) {
+                log::error!("Failed to write audit log in {:?}: {}", dir, err);
+            }
+        }
+    });
+    tx
+}
+
+// append `line` to the log in `dir`, rotated first if it reached `max_size` bytes
+fn write(dir: &Path, line: &str, max_size: u64) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = file_in(dir, 0);
+    if fs::metadata(&path).map(|x| x.len()).unwrap_or(0) >= max_size {
+        rotate(dir);
+    }
+    let mut f = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(f, "{}", line)
+}
+
+fn make_line(conn: i32, event: &str, fields: Value) -> String {
+    let mut line = json!({
+        "time": crate::get_time(),
+        "conn": conn,
+        "event": event,
+    });
+    if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+    line.to_string()
+}
+
+/// Append `event` of connection `conn`, `fields` is a JSON object merged into the line.
+/// The line is written in the background.
+pub fn record(conn: i32, event: &str, fields: Value) {
+    if Config::get_option(OPTION_DISABLE) == "Y" {
+        return;
+    }
+    let line = make_line(conn, event, fields);
+    WRITER.lock().unwrap().send(line).ok();
+}
+
+/// The last `count` lines, oldest first, containing `filter` if not empty.
+pub fn query(count: usize, filter: &str) -> Vec<String> {
+    let _lock = LOCK.lock().unwrap();
+    let mut lines = Vec::new();
+    for n in 0..=KEEP {
+        let f = match fs::File::open(file(n)) {
+            Ok(f) => f,
+            Err(_) => break,
+        };
+        let mut found: Vec<String> = BufReader::new(f)
+            .lines()
+            .filter_map(|x| x.ok())
+            .filter(|x| filter.is_empty() || x.contains(filter))
+            .collect();
+        // older files go in front
+        found.append(&mut lines);
+        lines = found;
+        if lines.len() >= count {
+            break;
+        }
+    }
+    let skip = lines.len().saturating_sub(count);
+    lines.split_off(skip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let dir = std::env::temp_dir().join(format!("audit-test-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let line = make_line(7, "login", json!({ "peer_id": "123", "text": "a\nb" }));
+        // one line of JSON, even with a new line in a field
+        assert!(!line.contains('\n'));
+        let v: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["conn"], 7);
+        assert_eq!(v["event"], "login");
+        assert_eq!(v["peer_id"], "123");
+        assert!(v["time"].as_i64().unwrap() > 0);
+        let max_size = line.len() as u64 * 3;
+        for _ in 0..(KEEP + 2) * 3 {
+            write(&dir, &line, max_size).unwrap();
+        }
+        let lines = fs::read_to_string(file_in(&dir, 0)).unwrap();
+        assert_eq!(lines, format!("{}\n", line).repeat(3));
+        // the oldest files beyond KEEP are dropped
+        assert!(file_in(&dir, KEEP).exists());
+        assert!(!file_in(&dir, KEEP + 1).exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
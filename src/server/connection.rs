@@ -1,4 +1,4 @@
-use super::{input_service::*, *};
+use super::{approval::Decision, input_service::*, *};
 #[cfg(windows)]
 use crate::clipboard_file::*;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -11,10 +11,10 @@ use crate::{common::MOBILE_INFO2, mobile::connection_manager::start_channel};
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use crate::{two_factor_auth};
 
-use crate::{ipc, VERSION};
+use crate::{common::SessionStats, ipc, VERSION};
 use hbb_common::fs::can_enable_overwrite_detection;
 use hbb_common::{
-    config::Config,
+    config::{Config, PasswordProfile},
     fs,
     futures::{SinkExt, StreamExt},
     get_version_number,
@@ -22,7 +22,7 @@ use hbb_common::{
     sleep, timeout,
     tokio::{
         net::TcpStream,
-        sync::mpsc,
+        sync::mpsc::{self, error::TrySendError},
         time::{self, Duration, Instant, Interval},
     },
     tokio_util::codec::{BytesCodec, Framed},
@@ -32,14 +32,23 @@ use scrap::android::call_main_service_mouse_input;
 use serde_json::{json, value::Value};
 use sha2::{Digest, Sha256};
 use std::sync::{
-    atomic::{AtomicI64, Ordering},
+    atomic::{AtomicI64, AtomicU64, Ordering},
     mpsc as std_mpsc,
 };
 
 pub type Sender = mpsc::UnboundedSender<(Instant, Arc<Message>)>;
+// only the media queues are bounded, a dropped frame is recovered with a key frame
+type MediaSender = mpsc::Sender<(Instant, Arc<Message>)>;
+
+// a few frames only, so that latency does not pile up on slow links
+pub(super) const VIDEO_QUEUE_SIZE: usize = 3;
+const AUDIO_QUEUE_SIZE: usize = 32;
 
 lazy_static::lazy_static! {
-    static ref LOGIN_FAILURES: Arc::<Mutex<HashMap<String, (i32, i32, i32)>>> = Default::default();
+    static ref SESSION_STATS: Arc::<Mutex<HashMap<i32, SessionStats>>> = Default::default();
+    // sent by the remote windows, each in its own process, with the time they came
+    static ref CLIENT_SESSION_STATS: Arc::<Mutex<HashMap<i32, (Instant, SessionStats)>>> =
+        Default::default();
 }
 pub static CLICK_TIME: AtomicI64 = AtomicI64::new(0);
 pub static MOUSE_MOVE_TIME: AtomicI64 = AtomicI64::new(0);
@@ -48,7 +57,10 @@ pub static MOUSE_MOVE_TIME: AtomicI64 = AtomicI64::new(0);
 pub struct ConnInner {
     id: i32,
     tx: Option<Sender>,
-    tx_video: Option<Sender>,
+    tx_video: Option<MediaSender>,
+    tx_audio: Option<MediaSender>,
+    wait_key_frame: bool,
+    frames_dropped: Arc<AtomicU64>,
 }
 
 enum MessageInput {
@@ -84,7 +96,15 @@ pub struct Connection {
     enable_file_transfer: bool,               // by peer
     tx_input: std_mpsc::Sender<MessageInput>, // handle input messages
     video_ack_required: bool,
+    wait_key_frame: bool,
     peer_info: (String, String),
+    stats: SessionStats,
+    credential: String, // name of the password used, empty for the main one
+    password_profile: Option<PasswordProfile>,
+    view_only: bool,
+    // keyboard and file permissions taken by the view-only mode
+    view_only_taken: (bool, bool),
+    close_reason: String,
 }
 
 impl Subscriber for ConnInner {
@@ -96,26 +116,98 @@ impl Subscriber for ConnInner {
     #[inline]
     fn send(&mut self, msg: Arc<Message>) {
         match &msg.union {
-            Some(message::Union::video_frame(_)) => {
-                self.tx_video.as_mut().map(|tx| {
+            Some(message::Union::video_frame(vf)) => {
+                if self.wait_key_frame {
+                    if !video_service::is_key_frame(vf) {
+                        video_service::notify_video_frame_feched(self.id, None);
+                        self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                    self.wait_key_frame = false;
+                }
+                if !try_send(&self.tx_video, msg) {
+                    // the frames after a dropped one can not be decoded, skip them until a keyframe
+                    log::debug!("#{} video queue full, wait for keyframe", self.id);
+                    self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+                    self.wait_key_frame = true;
+                    video_service::notify_queue_full(self.id);
+                } else if let Some(tx) = &self.tx_video {
+                    video_service::update_queue_depth(self.id, VIDEO_QUEUE_SIZE - tx.capacity());
+                }
+            }
+            Some(message::Union::audio_frame(_)) => {
+                if !try_send(&self.tx_audio, msg) {
+                    audio_service::notify_queue_full(self.id);
+                }
+            }
+            Some(message::Union::cursor_position(pos)) => {
+                // in the pixels of a downscaled display
+                let msg = match video_service::scale_point(self.id, pos.x, pos.y) {
+                    Some((x, y)) => {
+                        let mut pos = pos.clone();
+                        pos.x = x;
+                        pos.y = y;
+                        let mut msg_out = Message::new();
+                        msg_out.set_cursor_position(pos);
+                        Arc::new(msg_out)
+                    }
+                    None => msg.clone(),
+                };
+                if let Some(tx) = &self.tx {
                     allow_err!(tx.send((Instant::now(), msg)));
-                });
+                }
             }
             _ => {
-                self.tx.as_mut().map(|tx| {
+                if let Some(tx) = &self.tx {
                     allow_err!(tx.send((Instant::now(), msg)));
-                });
+                }
             }
         }
     }
 }
 
+// return false if the queue is full
+#[inline]
+fn try_send(tx: &Option<MediaSender>, msg: Arc<Message>) -> bool {
+    if let Some(tx) = tx {
+        match tx.try_send((Instant::now(), msg)) {
+            Err(TrySendError::Full(_)) => return false,
+            Err(err) => log::debug!("Failed to send: {}", err),
+            Ok(_) => {}
+        }
+    }
+    true
+}
+
+/// Stats of the sessions served by this process, then of the ones controlling a peer
+pub fn get_session_stats() -> Vec<SessionStats> {
+    let mut v: Vec<SessionStats> = SESSION_STATS.lock().unwrap().values().cloned().collect();
+    v.sort_by_key(|x| x.conn_id);
+    let mut clients = CLIENT_SESSION_STATS.lock().unwrap();
+    // a closed remote window stops sending them
+    clients.retain(|_, (tm, _)| tm.elapsed() < CLIENT_STATS_TIMEOUT);
+    let mut c: Vec<SessionStats> = clients.values().map(|(_, x)| x.clone()).collect();
+    c.sort_by_key(|x| x.conn_id);
+    v.extend(c);
+    v
+}
+
+pub fn update_client_session_stats(stats: SessionStats) {
+    CLIENT_SESSION_STATS
+        .lock()
+        .unwrap()
+        .insert(stats.conn_id, (Instant::now(), stats));
+}
+
 const TEST_DELAY_TIMEOUT: Duration = Duration::from_secs(3);
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+const CLIENT_STATS_TIMEOUT: Duration = Duration::from_secs(5);
 const SEC30: Duration = Duration::from_secs(30);
 const H1: Duration = Duration::from_secs(3600);
 const MILLI1: Duration = Duration::from_millis(1);
 const SEND_TIMEOUT_VIDEO: u64 = 12_000;
 const SEND_TIMEOUT_OTHER: u64 = SEND_TIMEOUT_VIDEO * 10;
+const MAX_FRAME_LATENCY: Duration = Duration::from_millis(1000);
 
 impl Connection {
     pub async fn start(
@@ -123,6 +215,7 @@ impl Connection {
         stream: super::Stream,
         id: i32,
         server: super::ServerPtrWeak,
+        direct: bool,
     ) {
         let hash = Hash {
             salt: Config::get_salt(),
@@ -134,7 +227,8 @@ impl Connection {
         let tx_from_cm = tx_from_cm_holder.clone();
         let (tx_to_cm, rx_to_cm) = mpsc::unbounded_channel::<ipc::Data>();
         let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
-        let (tx_video, mut rx_video) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
+        let (tx_video, mut rx_video) = mpsc::channel::<(Instant, Arc<Message>)>(VIDEO_QUEUE_SIZE);
+        let (tx_audio, mut rx_audio) = mpsc::channel::<(Instant, Arc<Message>)>(AUDIO_QUEUE_SIZE);
         let (tx_input, rx_input) = std_mpsc::channel();
 
 		let tx_to_cm_2fa = tx_to_cm.clone();
@@ -145,6 +239,9 @@ impl Connection {
                 id,
                 tx: Some(tx),
                 tx_video: Some(tx_video),
+                tx_audio: Some(tx_audio),
+                wait_key_frame: false,
+                frames_dropped: Default::default(),
             },
             stream,
             server,
@@ -170,7 +267,18 @@ impl Connection {
             disable_clipboard: false,
             tx_input,
             video_ack_required: false,
+            wait_key_frame: false,
             peer_info: Default::default(),
+            stats: SessionStats {
+                conn_id: id,
+                direct,
+                ..Default::default()
+            },
+            credential: "".to_owned(),
+            password_profile: None,
+            view_only: false,
+            view_only_taken: (false, false),
+            close_reason: "".to_owned(),
         };
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         tokio::spawn(async move {
@@ -198,6 +306,7 @@ impl Connection {
         }
         let mut test_delay_timer =
             time::interval_at(Instant::now() + TEST_DELAY_TIMEOUT, TEST_DELAY_TIMEOUT);
+        let mut stats_timer = time::interval(STATS_INTERVAL);
         let mut last_recv_time = Instant::now();
 
         conn.stream.set_send_timeout(
@@ -253,6 +362,7 @@ impl Connection {
                             break;
                         }
                         ipc::Data::ChatMessage{text} => {
+                            conn.audit("chat", json!({ "from": "local", "text": text }));
                             let mut misc = Misc::new();
                             misc.set_chat_message(ChatMessage {
                                 text,
@@ -265,6 +375,11 @@ impl Connection {
                         ipc::Data::SwitchPermission{name, enabled} => {
                             log::info!("Change permission {} -> {}", name, enabled);
                             if &name == "keyboard" {
+                                if enabled && conn.view_only {
+                                    // the local user hands over the control
+                                    conn.leave_view_only().await;
+                                    conn.send_control_response(true).await;
+                                }
                                 conn.keyboard = enabled;
                                 conn.send_permission(Permission::Keyboard, enabled).await;
                                 if let Some(s) = conn.server.upgrade() {
@@ -294,6 +409,22 @@ impl Connection {
                                 conn.send_to_cm(ipc::Data::ClipboardFileEnabled(conn.file_transfer_enabled()));
                             }
                         }
+                        ipc::Data::RequestControl(Some(approved)) => {
+                            log::info!("Control request approved: {}", approved);
+                            if approved && conn.view_only {
+                                conn.leave_view_only().await;
+                                if let Some(s) = conn.server.upgrade() {
+                                    let mut s = s.write().unwrap();
+                                    s.subscribe(
+                                        NAME_CURSOR,
+                                        conn.inner.clone(), conn.keyboard || conn.show_remote_cursor);
+                                    s.subscribe(
+                                        super::clipboard_service::NAME,
+                                        conn.inner.clone(), conn.clipboard_enabled() && conn.keyboard);
+                                }
+                            }
+                            conn.send_control_response(approved).await;
+                        }
                         ipc::Data::RawMessage(bytes) => {
                             allow_err!(conn.stream.send_raw(bytes).await);
                         }
@@ -369,25 +500,33 @@ impl Connection {
                     if !conn.video_ack_required {
                         video_service::notify_video_frame_feched(id, Some(instant.into()));
                     }
+                    if !conn.check_video_frame(instant, &value) {
+                        if conn.video_ack_required {
+                            video_service::notify_video_frame_feched(id, None);
+                        }
+                        conn.inner.frames_dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
                     if let Err(err) = conn.stream.send(&value as &Message).await {
                         conn.on_close(&err.to_string(), false);
                         break;
                     }
+                    if let Some(message::Union::video_frame(vf)) = &value.union {
+                        conn.stats.on_video_frame(vf);
+                    }
                 },
-                Some((instant, value)) = rx.recv() => {
-                    let latency = instant.elapsed().as_millis() as i64;
-                    let msg: &Message = &value;
-
-                    if latency > 1000 {
-                        match &msg.union {
-                            Some(message::Union::audio_frame(_)) => {
-                                // log::info!("audio frame latency {}", instant.elapsed().as_secs_f32());
-                                continue;
-                            }
-                            _ => {}
-                        }
+                Some((instant, value)) = rx_audio.recv() => {
+                    if instant.elapsed() > MAX_FRAME_LATENCY {
+                        // log::info!("audio frame latency {}", instant.elapsed().as_secs_f32());
+                        continue;
+                    }
+                    if let Err(err) = conn.stream.send(&value as &Message).await {
+                        conn.on_close(&err.to_string(), false);
+                        break;
                     }
-                    if let Err(err) = conn.stream.send(msg).await {
+                },
+                Some((_, value)) = rx.recv() => {
+                    if let Err(err) = conn.stream.send(&value as &Message).await {
                         conn.on_close(&err.to_string(), false);
                         break;
                     }
@@ -403,11 +542,15 @@ impl Connection {
                         let mut msg_out = Message::new();
                         msg_out.set_test_delay(TestDelay{
                             time,
+                            last_delay: conn.stats.rtt,
                             ..Default::default()
                         });
                         conn.inner.send(msg_out.into());
                     }
                 }
+                _ = stats_timer.tick() => {
+                    conn.update_stats();
+                }
             }
         }
 
@@ -420,10 +563,23 @@ impl Connection {
         }
         video_service::notify_video_frame_feched(id, None);
         video_service::update_test_latency(id, 0);
+        video_service::update_queue_depth(id, 0);
         video_service::update_image_quality(id, None);
+        video_service::update_user_fps(id, None);
+        video_service::update_codec(id, None);
+        video_service::set_show_all_displays(id, false);
+        video_service::update_display(id, None);
+        video_service::update_image_scale(id, None);
+        SESSION_STATS.lock().unwrap().remove(&id);
         if let Err(err) = conn.try_port_forward_loop(&mut rx_from_cm).await {
             conn.on_close(&err.to_string(), false);
         }
+        let reason = if conn.close_reason.is_empty() {
+            "Closed".to_owned()
+        } else {
+            conn.close_reason.clone()
+        };
+        conn.audit("disconnect", json!({ "reason": reason }));
         log::info!("#{} connection loop exited", id);
     }
 
@@ -527,6 +683,10 @@ impl Connection {
     }
 
     async fn send_permission(&mut self, permission: Permission, enabled: bool) {
+        self.audit(
+            "permission",
+            json!({ "permission": format!("{:?}", permission), "enabled": enabled }),
+        );
         let mut misc = Misc::new();
         misc.set_permission_info(PermissionInfo {
             permission: permission.into(),
@@ -540,6 +700,8 @@ impl Connection {
 
     async fn on_open(&mut self, addr: SocketAddr) -> bool {
         log::debug!("#{} Connection opened from {}.", self.inner.id, addr);
+        self.ip = addr.ip().to_string();
+        self.audit("connect", json!({}));
         let whitelist: Vec<String> = Config::get_option("whitelist")
             .split(",")
             .filter(|x| !x.is_empty())
@@ -562,7 +724,6 @@ impl Connection {
             sleep(1.).await;
             return false;
         }
-        self.ip = addr.ip().to_string();
         let mut msg_out = Message::new();
         msg_out.set_hash(self.hash.clone());
         self.send(msg_out).await;
@@ -580,6 +741,19 @@ impl Connection {
         } else {
             0
         };
+        self.audit(
+            "authorized",
+            json!({
+                "type": ["desktop", "file_transfer", "port_forward"][conn_type],
+                "port_forward": self.port_forward_address,
+                "credential": self.credential,
+                "view_only": self.view_only,
+                "keyboard": self.keyboard,
+                "clipboard": self.clipboard,
+                "audio": self.audio,
+                "file": self.file,
+            }),
+        );
         #[allow(unused_mut)]
         let mut username = crate::platform::get_active_username();
         let mut res = LoginResponse::new();
@@ -614,7 +788,9 @@ impl Connection {
         #[cfg(target_os = "linux")]
         if !self.file_transfer.is_some() && !self.port_forward_socket.is_some() {
             let dtype = crate::platform::linux::get_display_server();
-            if dtype != "x11" {
+            // captured through the portal, see platform::linux_wayland
+            let wayland = cfg!(feature = "wayland") && dtype == "wayland";
+            if dtype != "x11" && !wayland {
                 res.set_error(format!(
                     "Unsupported display server type {}, x11 expected",
                     dtype
@@ -643,15 +819,25 @@ impl Connection {
         pi.mac_address = get_mac();       
         pi.features = Some(Features {
             privacy_mode: video_service::is_privacy_mode_supported(),
+            all_displays: true,
             ..Default::default()
         })
         .into();
+        pi.supported_decoding = Some(crate::common::supported_codecs()).into();
+        pi.supported_encoding = Some(crate::common::supported_codecs()).into();
+        pi.view_only = self.view_only;
         let mut sub_service = false;
         if self.file_transfer.is_some() {
             res.set_peer_info(pi);
         } else {
             try_activate_screen();
-            match super::video_service::get_displays() {
+            #[allow(unused_mut)]
+            let mut displays = super::video_service::get_displays(self.inner.id());
+            #[cfg(all(target_os = "linux", feature = "wayland"))]
+            if displays.is_err() && crate::platform::linux_wayland::wait_portal_session().await {
+                displays = super::video_service::get_displays(self.inner.id());
+            }
+            match displays {
                 Err(err) => {
                     res.set_error(format!("X11 error: {}", err));
                 }
@@ -695,6 +881,111 @@ impl Connection {
         }
     }
 
+    fn validate_password(&self, password: &[u8], candidate: &str) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(candidate);
+        hasher.update(&self.hash.salt);
+        let mut hasher2 = Sha256::new();
+        hasher2.update(&hasher.finalize()[..]);
+        hasher2.update(&self.hash.challenge);
+        hasher2.finalize()[..] == password[..]
+    }
+
+    // Narrow the permissions down to the ones of the named password used to log in
+    async fn apply_password_profile(&mut self, profile: &PasswordProfile) -> bool {
+        log::info!(
+            "#{} logged in with password \"{}\"",
+            self.inner.id,
+            profile.name
+        );
+        self.credential = profile.name.clone();
+        self.password_profile = Some(profile.clone());
+        if self.file_transfer.is_some() && !profile.file {
+            self.send_login_error("No permission of file transfer")
+                .await;
+            return false;
+        }
+        if self.port_forward_socket.is_some() && !profile.tunnel {
+            self.send_login_error("No permission of IP tunneling").await;
+            return false;
+        }
+        if self.keyboard && !profile.keyboard {
+            self.keyboard = false;
+            self.send_permission(Permission::Keyboard, false).await;
+        }
+        if self.clipboard && !profile.clipboard {
+            self.clipboard = false;
+            self.send_permission(Permission::Clipboard, false).await;
+        }
+        if self.audio && !profile.audio {
+            self.audio = false;
+            self.send_permission(Permission::Audio, false).await;
+        }
+        if self.file && !profile.file {
+            self.file = false;
+            self.send_permission(Permission::File, false).await;
+        }
+        if profile.view_only && !self.view_only {
+            return self.enter_view_only().await;
+        }
+        true
+    }
+
+    // Watch only, input, clipboard and files of the peer are rejected until the control is handed over
+    async fn enter_view_only(&mut self) -> bool {
+        if self.file_transfer.is_some() || self.port_forward_socket.is_some() {
+            self.send_login_error("Only remote desktop is allowed in view-only mode")
+                .await;
+            return false;
+        }
+        log::info!("#{} view-only", self.inner.id);
+        if !self.view_only {
+            self.view_only_taken = (self.keyboard, self.file);
+        }
+        self.view_only = true;
+        if self.keyboard {
+            self.keyboard = false;
+            self.send_permission(Permission::Keyboard, false).await;
+        }
+        if self.file {
+            self.file = false;
+            self.send_permission(Permission::File, false).await;
+        }
+        true
+    }
+
+    // The control is handed over, what the view-only mode took is given back as far as the
+    // options and the password used still allow it
+    async fn leave_view_only(&mut self) {
+        log::info!("#{} leaves view-only", self.inner.id);
+        self.view_only = false;
+        let (keyboard, file) = std::mem::take(&mut self.view_only_taken);
+        let profile = self.password_profile.as_ref();
+        let keyboard = keyboard
+            && Config::get_option("enable-keyboard").is_empty()
+            && profile.map_or(true, |p| p.keyboard);
+        let file = file
+            && Config::get_option("enable-file-transfer").is_empty()
+            && profile.map_or(true, |p| p.file);
+        if keyboard && !self.keyboard {
+            self.keyboard = true;
+            self.send_permission(Permission::Keyboard, true).await;
+        }
+        if file && !self.file {
+            self.file = true;
+            self.send_permission(Permission::File, true).await;
+            self.send_to_cm(ipc::Data::ClipboardFileEnabled(self.file_transfer_enabled()));
+        }
+    }
+
+    async fn send_control_response(&mut self, approved: bool) {
+        let mut misc = Misc::new();
+        misc.set_control_response(approved);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(msg_out).await;
+    }
+
     fn clipboard_enabled(&self) -> bool {
         self.clipboard && !self.disable_clipboard
     }
@@ -709,7 +1000,12 @@ impl Connection {
 
     fn try_start_cm(&mut self, peer_id: String, name: String, authorized: bool) {
         self.peer_info = (peer_id.clone(), name.clone());
-        self.send_to_cm(ipc::Data::Login {
+        let login = self.login_data(peer_id, name, authorized);
+        self.send_to_cm(login);
+    }
+
+    fn login_data(&self, peer_id: String, name: String, authorized: bool) -> ipc::Data {
+        ipc::Data::Login {
             id: self.inner.id(),
             is_file_transfer: self.file_transfer.is_some(),
             port_forward: self.port_forward_address.clone(),
@@ -721,7 +1017,8 @@ impl Connection {
             audio: self.audio,
             file: self.file,
             file_transfer_enabled: self.file_transfer_enabled(),
-        });
+            credential: self.credential.clone(),
+        }
     }
 
     #[inline]
@@ -734,7 +1031,22 @@ impl Connection {
         self.send_to_cm(ipc::Data::FS(data));
     }
 
+    /// Append to the audit log, with the peer and how it is connected
+    fn audit(&self, event: &str, mut fields: Value) {
+        if let Some(x) = fields.as_object_mut() {
+            x.insert("peer_id".to_owned(), json!(self.peer_info.0));
+            x.insert("name".to_owned(), json!(self.peer_info.1));
+            x.insert("ip".to_owned(), json!(self.ip));
+            x.insert(
+                "path".to_owned(),
+                json!(if self.stats.direct { "direct" } else { "relay" }),
+            );
+        }
+        super::audit::record(self.inner.id, event, fields);
+    }
+
     async fn send_login_error<T: std::string::ToString>(&mut self, err: T) {
+        self.audit("login_error", json!({ "error": err.to_string() }));
         let mut msg_out = Message::new();
         let mut res = LoginResponse::new();
         res.set_error(err.to_string());
@@ -753,6 +1065,38 @@ impl Connection {
         s.send((Instant::now(), Arc::new(msg_out))).ok();
     }
 
+    fn update_stats(&mut self) {
+        self.stats
+            .update(self.stream.bytes_sent(), self.stream.bytes_received());
+        self.stats.frames_dropped = self.inner.frames_dropped.load(Ordering::Relaxed);
+        if self.stats.peer_id != self.peer_info.0 {
+            self.stats.peer_id = self.peer_info.0.clone();
+        }
+        SESSION_STATS
+            .lock()
+            .unwrap()
+            .insert(self.inner.id, self.stats.clone());
+    }
+
+    // drop stale non-key frames in favour of fresh ones, a keyframe is requested to resume
+    fn check_video_frame(&mut self, instant: Instant, msg: &Message) -> bool {
+        if let Some(message::Union::video_frame(vf)) = &msg.union {
+            let key = video_service::is_key_frame(vf);
+            if self.wait_key_frame {
+                if !key {
+                    return false;
+                }
+                self.wait_key_frame = false;
+            } else if !key && instant.elapsed() > MAX_FRAME_LATENCY {
+                log::debug!("#{} stale video frame, wait for keyframe", self.inner.id);
+                self.wait_key_frame = true;
+                video_service::request_key_frame(self.inner.id);
+                return false;
+            }
+        }
+        true
+    }
+
     #[inline]
     fn input_mouse(&self, msg: MouseEvent, conn_id: i32) {
         self.tx_input.send(MessageInput::Mouse((msg, conn_id))).ok();
@@ -769,9 +1113,41 @@ impl Connection {
                 self.update_option(o).await;
             }
             self.video_ack_required = lr.video_ack_required;
+            if let Some(d) = lr.supported_decoding.as_ref() {
+                let codec = video_service::select_codec(d.av1, d.h264);
+                log::info!("Video codec of {}: {:?}", self.inner.id(), codec);
+                video_service::update_codec(self.inner.id(), Some(codec));
+            }
             if self.authorized {
                 return true;
             }
+            self.peer_info = (lr.my_id.clone(), lr.my_name.clone());
+            self.audit(
+                "login_request",
+                json!({
+                    "with_password": !lr.password.is_empty(),
+                    "view_only": lr.view_only,
+                }),
+            );
+            let ip = if self.stats.direct {
+                self.ip.parse().ok()
+            } else {
+                None
+            };
+            if !super::acl::is_allowed(&lr.my_id, ip) {
+                log::info!("#{} {} rejected by access control", self.inner.id, lr.my_id);
+                self.send_login_error("Your ID or IP is not allowed by the peer")
+                    .await;
+                sleep(1.).await;
+                return false;
+            }
+            if !super::schedule::is_allowed(&lr.my_id) {
+                log::info!("#{} {} rejected outside the access schedule", self.inner.id, lr.my_id);
+                self.send_login_error("The peer only accepts connections at scheduled hours")
+                    .await;
+                sleep(1.).await;
+                return false;
+            }
             match lr.union {
                 Some(login_request::Union::file_transfer(ft)) => {
                     if !Config::get_option("enable-file-transfer").is_empty() {
@@ -817,47 +1193,107 @@ impl Connection {
                 }
                 _ => {}
             }
+            if lr.view_only && !self.enter_view_only().await {
+                sleep(1.).await;
+                return false;
+            }
             if !crate::is_ip(&lr.username) && lr.username != Config::get_id() {
                 self.send_login_error("Offline").await;
             } else if lr.password.is_empty() {
-                self.try_start_cm(lr.my_id, lr.my_name, false);
-            } else {
-                let mut hasher = Sha256::new();
-                hasher.update(&Config::get_password());
-                hasher.update(&self.hash.salt);
-                let mut hasher2 = Sha256::new();
-                hasher2.update(&hasher.finalize()[..]);
-                hasher2.update(&self.hash.challenge);
-                let mut failure = LOGIN_FAILURES
-                    .lock()
-                    .unwrap()
-                    .get(&self.ip)
-                    .map(|x| x.clone())
-                    .unwrap_or((0, 0, 0));
-                let time = (crate::get_time() / 60_000) as i32;
-                if failure.2 > 30 {
-                    self.send_login_error("Too many wrong password attempts")
-                        .await;
-                } else if time == failure.0 && failure.1 > 6 {
-                    self.send_login_error("Please try 1 minute later").await;
-                } else if hasher2.finalize()[..] != lr.password[..] {
-                    if failure.0 == time {
-                        failure.1 += 1;
-                        failure.2 += 1;
-                    } else {
-                        failure.0 = time;
-                        failure.1 = 1;
-                        failure.2 += 1;
+                let login = self.login_data(lr.my_id.clone(), lr.my_name.clone(), false);
+                let decision = super::approval::decide(&login);
+                self.audit("approval", json!({ "decision": format!("{:?}", decision) }));
+                match decision {
+                    Decision::Ask => self.try_start_cm(lr.my_id, lr.my_name, false),
+                    Decision::Deny => {
+                        self.send_login_error("Connection denied by the peer's rules")
+                            .await;
+                        sleep(1.).await;
+                        return false;
+                    }
+                    decision => {
+                        if decision == Decision::ViewOnly
+                            && !self.view_only
+                            && !self.enter_view_only().await
+                        {
+                            sleep(1.).await;
+                            return false;
+                        }
+                        self.try_start_cm(lr.my_id, lr.my_name, true);
+                        self.send_logon_response().await;
+                        if self.port_forward_socket.is_some() {
+                            return false;
+                        }
                     }
-                    LOGIN_FAILURES
-                        .lock()
-                        .unwrap()
-                        .insert(self.ip.clone(), failure);
+                }
+            } else {
+                let ip = if self.stats.direct {
+                    Some(self.ip.clone())
+                } else {
+                    None
+                };
+                let locked = super::login_guard::locked_for(&lr.my_id, ip.as_deref());
+                // consumed only once the login passed the 2FA code as well
+                let mut one_time_password = None;
+                // Some(None) for the main password, checked only when not locked out
+                let credential = if locked.is_some() {
+                    None
+                } else if self.validate_password(&lr.password, &Config::get_password()) {
+                    Some(None)
+                } else if let Some(profile) =
+                    Config::get_password_profiles().into_iter().find(|p| {
+                        !p.password.is_empty()
+                            && !p.is_expired()
+                            && self.validate_password(&lr.password, &p.password)
+                    })
+                {
+                    Some(Some(profile))
+                } else if let Some(p) = Config::find_one_time_password(|p| {
+                    self.validate_password(&lr.password, p)
+                }) {
+                    one_time_password = Some(p);
+                    Some(None)
+                } else {
+                    None
+                };
+                if let Some(secs) = locked {
+                    self.send_login_error(format!(
+                        "Too many wrong password attempts, please try again in {} seconds",
+                        secs
+                    ))
+                    .await;
+                } else if credential.is_none() {
+                    super::login_guard::on_failure(&lr.my_id, ip.as_deref());
                     self.send_login_error("Wrong Password").await;
                     self.try_start_cm(lr.my_id, lr.my_name, false);
                 } else {
-                    if failure.0 != 0 {
-                        LOGIN_FAILURES.lock().unwrap().remove(&self.ip);
+                    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                    if TFAManager::is_totp_enabled() {
+                        if lr.totp_code.is_empty() {
+                            self.send_login_error("2FA Code Required").await;
+                            return true;
+                        }
+                        if !two_factor_auth::totp::verify_code(&lr.totp_code) {
+                            super::login_guard::on_failure(&lr.my_id, ip.as_deref());
+                            self.send_login_error("Wrong 2FA Code").await;
+                            return true;
+                        }
+                    }
+                    if let Some(p) = one_time_password {
+                        // another login may have used it in the meantime
+                        if !Config::take_one_time_password(|x| x == p) {
+                            self.send_login_error("Wrong Password").await;
+                            return true;
+                        }
+                        log::info!("#{} logged in with a one-time password", self.inner.id);
+                        self.credential = "One-time password".to_owned();
+                    }
+                    super::login_guard::on_success(&lr.my_id, ip.as_deref());
+                    if let Some(Some(profile)) = credential {
+                        if !self.apply_password_profile(&profile).await {
+                            sleep(1.).await;
+                            return false;
+                        }
                     }
 
                     // always true when 2fa not enabled
@@ -895,13 +1331,18 @@ impl Connection {
             } else {
                 self.last_test_delay = 0;
                 let latency = crate::get_time() - t.time;
+                self.stats.rtt = latency.max(0) as _;
                 if latency > 0 {
                     super::video_service::update_test_latency(self.inner.id(), latency);
                 }
             }
         } else if self.authorized {
             match msg.union {
-                Some(message::Union::mouse_event(me)) => {
+                Some(message::Union::mouse_event(mut me)) => {
+                    // the peer points in its pixels of a downscaled display
+                    let (x, y) = video_service::unscale_point(self.inner.id(), me.x, me.y);
+                    me.x = x;
+                    me.y = y;
                     #[cfg(any(target_os = "android", target_os = "ios"))]
                     if let Err(e) = call_main_service_mouse_input(me.mask, me.x, me.y) {
                         log::debug!("call_main_service_mouse_input fail:{}", e);
@@ -948,7 +1389,7 @@ impl Connection {
                 Some(message::Union::clipboard(cb)) =>
                 {
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
-                    if self.clipboard {
+                    if self.clipboard && !self.view_only {
                         update_clipboard(cb, None);
                     }
                 }
@@ -994,6 +1435,14 @@ impl Connection {
                                         self.send(fs::new_error(id, err, 0)).await;
                                     }
                                     Ok(job) => {
+                                        self.audit(
+                                            "file_read",
+                                            json!({
+                                                "path": path,
+                                                "files": job.files().len(),
+                                                "size": job.files().iter().map(|f| f.size).sum::<u64>(),
+                                            }),
+                                        );
                                         self.send(fs::new_dir(id, path, job.files().to_vec()))
                                             .await;
                                         self.read_jobs.push(job);
@@ -1002,6 +1451,14 @@ impl Connection {
                                 }
                             }
                             Some(file_action::Union::receive(r)) => {
+                                self.audit(
+                                    "file_write",
+                                    json!({
+                                        "path": r.path,
+                                        "files": r.files.len(),
+                                        "size": r.files.iter().map(|f| f.size).sum::<u64>(),
+                                    }),
+                                );
                                 self.send_fs(ipc::FS::NewWrite {
                                     path: r.path,
                                     id: r.id,
@@ -1015,6 +1472,7 @@ impl Connection {
                                 });
                             }
                             Some(file_action::Union::remove_dir(d)) => {
+                                self.audit("file_remove", json!({ "path": d.path }));
                                 self.send_fs(ipc::FS::RemoveDir {
                                     path: d.path,
                                     id: d.id,
@@ -1022,6 +1480,7 @@ impl Connection {
                                 });
                             }
                             Some(file_action::Union::remove_file(f)) => {
+                                self.audit("file_remove", json!({ "path": f.path }));
                                 self.send_fs(ipc::FS::RemoveFile {
                                     path: f.path,
                                     id: f.id,
@@ -1029,6 +1488,7 @@ impl Connection {
                                 });
                             }
                             Some(file_action::Union::create(c)) => {
+                                self.audit("file_create", json!({ "path": c.path }));
                                 self.send_fs(ipc::FS::CreateDir {
                                     path: c.path,
                                     id: c.id,
@@ -1073,9 +1533,10 @@ impl Connection {
                 },
                 Some(message::Union::misc(misc)) => match misc.union {
                     Some(misc::Union::switch_display(s)) => {
-                        super::video_service::switch_display(s.display);
+                        super::video_service::switch_display(self.inner.id(), s.display);
                     }
                     Some(misc::Union::chat_message(c)) => {
+                        self.audit("chat", json!({ "from": "peer", "text": c.text }));
                         self.send_to_cm(ipc::Data::ChatMessage { text: c.text });
                     }
                     Some(misc::Union::option(o)) => {
@@ -1092,6 +1553,11 @@ impl Connection {
                             Some(Instant::now().into()),
                         );
                     }
+                    Some(misc::Union::request_control(_)) => {
+                        if self.view_only {
+                            self.send_to_cm(ipc::Data::RequestControl(None));
+                        }
+                    }
                     _ => {}
                 },
                 _ => {}
@@ -1111,6 +1577,19 @@ impl Connection {
             self.image_quality = q;
             super::video_service::update_image_quality(self.inner.id(), Some(q));
         }
+        if o.custom_fps > 0 {
+            super::video_service::update_user_fps(self.inner.id(), Some(o.custom_fps as _));
+        } else if o.custom_fps < 0 {
+            super::video_service::update_user_fps(self.inner.id(), None);
+        }
+        if o.image_scale > 0 {
+            super::video_service::update_image_scale(self.inner.id(), Some(o.image_scale as _));
+        }
+        if let Ok(q) = o.show_all_displays.enum_value() {
+            if q != BoolOption::NotSet {
+                super::video_service::set_show_all_displays(self.inner.id(), q == BoolOption::Yes);
+            }
+        }
         if let Ok(q) = o.lock_after_session_end.enum_value() {
             if q != BoolOption::NotSet {
                 self.lock_after_session_end = q == BoolOption::Yes;
@@ -1244,6 +1723,9 @@ impl Connection {
             s.write().unwrap().remove_connection(&self.inner);
         }
         log::info!("#{} Connection closed: {}", self.inner.id(), reason);
+        if self.close_reason.is_empty() {
+            self.close_reason = reason.to_owned();
+        }
         if lock && self.lock_after_session_end && self.keyboard {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             lock_screen();
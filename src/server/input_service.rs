@@ -173,7 +173,15 @@ lazy_static::lazy_static! {
                 std::env::set_var("PYNPUT_USERID", crate::platform::linux::get_active_userid());
             }
         }
-        Arc::new(Mutex::new(Enigo::new()))
+        #[allow(unused_mut)]
+        let mut en = Enigo::new();
+        #[cfg(all(target_os = "linux", feature = "wayland"))]
+        if crate::platform::linux_wayland::is_wayland() {
+            use crate::platform::linux_wayland::*;
+            en.set_custom_keyboard(Box::new(PortalKeyboard::default()));
+            en.set_custom_mouse(Box::new(PortalMouse));
+        }
+        Arc::new(Mutex::new(en))
     };
     static ref KEYS_DOWN: Arc<Mutex<HashMap<u64, Instant>>> = Default::default();
     static ref LATEST_INPUT: Arc<Mutex<Input>> = Default::default();
@@ -0,0 +1,231 @@
+// Brute-force protection of the password login, persisted across restarts.
+// Failures are counted per peer ID and, on direct connections, per IP as well;
+// a relayed connection comes from the relay's address, which is shared by everyone.
+// Since the peer ID is not verified, relayed failures also go into one budget of all relays,
+// RELAY_FACTOR times larger, against an attacker changing the ID on every try.
+// After "login-max-failures" wrong passwords the key is locked for "login-lockout-duration"
+// seconds, doubled on every further lockout up to a day. The counters are forgotten
+// after a day without failures.
+
+use hbb_common::{
+    config::{Config, LoginFailure, LoginFailures},
+    log,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+pub const OPTION_MAX_FAILURES: &'static str = "login-max-failures";
+pub const OPTION_LOCKOUT_DURATION: &'static str = "login-lockout-duration";
+const DEFAULT_MAX_FAILURES: u32 = 6;
+const DEFAULT_LOCKOUT_DURATION: i64 = 60;
+const MAX_LOCKOUT: i64 = 24 * 3600_000;
+const FORGET_AFTER: i64 = 24 * 3600_000;
+const RELAY_KEY: &'static str = "relay";
+const RELAY_FACTOR: u32 = 50;
+
+lazy_static::lazy_static! {
+    static ref FAILURES: Arc<Mutex<LoginFailures>> = Arc::new(Mutex::new(LoginFailures::load()));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Settings {
+    max_failures: u32,
+    // in ms
+    lockout: i64,
+}
+
+impl Settings {
+    fn parse(max_failures: &str, lockout: &str) -> Self {
+        let max_failures = max_failures
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|x| *x > 0)
+            .unwrap_or(DEFAULT_MAX_FAILURES);
+        let lockout = lockout
+            .trim()
+            .parse::<i64>()
+            .ok()
+            .filter(|x| *x > 0)
+            .unwrap_or(DEFAULT_LOCKOUT_DURATION);
+        Self {
+            max_failures,
+            lockout: std::cmp::min(lockout.saturating_mul(1000), MAX_LOCKOUT),
+        }
+    }
+
+    fn for_key(self, key: &str) -> Self {
+        if key == RELAY_KEY {
+            Self {
+                max_failures: self.max_failures.saturating_mul(RELAY_FACTOR),
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+}
+
+fn settings() -> Settings {
+    Settings::parse(
+        &Config::get_option(OPTION_MAX_FAILURES),
+        &Config::get_option(OPTION_LOCKOUT_DURATION),
+    )
+}
+
+fn keys(peer_id: &str, ip: Option<&str>) -> Vec<String> {
+    let mut keys = vec![format!("id:{}", peer_id.trim())];
+    match ip {
+        Some(ip) => keys.push(format!("ip:{}", ip)),
+        None => keys.push(RELAY_KEY.to_owned()),
+    }
+    keys
+}
+
+fn lockout_duration(base: i64, lockouts: u32) -> i64 {
+    let shift = std::cmp::min(lockouts.saturating_sub(1), 16);
+    std::cmp::min(base << shift, MAX_LOCKOUT)
+}
+
+fn record_failure(entry: &mut LoginFailure, settings: &Settings, now: i64) {
+    if now - entry.last_failure > FORGET_AFTER {
+        *entry = Default::default();
+    }
+    entry.last_failure = now;
+    entry.failures += 1;
+    if entry.failures >= settings.max_failures {
+        entry.failures = 0;
+        entry.lockouts += 1;
+        entry.locked_until = now + lockout_duration(settings.lockout, entry.lockouts);
+    }
+}
+
+fn remaining(entry: &LoginFailure, now: i64) -> i64 {
+    std::cmp::max(entry.locked_until - now, 0)
+}
+
+/// Seconds left if the peer is locked out, `ip` is None for relayed connections.
+pub fn locked_for(peer_id: &str, ip: Option<&str>) -> Option<i64> {
+    let now = crate::get_time();
+    let lock = FAILURES.lock().unwrap();
+    keys(peer_id, ip)
+        .iter()
+        .filter_map(|k| lock.failures.get(k))
+        .map(|x| remaining(x, now))
+        .max()
+        .filter(|x| *x > 0)
+        .map(|x| (x + 999) / 1000)
+}
+
+pub fn on_failure(peer_id: &str, ip: Option<&str>) {
+    let settings = settings();
+    let now = crate::get_time();
+    let mut lock = FAILURES.lock().unwrap();
+    for k in keys(peer_id, ip) {
+        let entry = lock.failures.entry(k.clone()).or_default();
+        record_failure(entry, &settings.for_key(&k), now);
+        if entry.locked_until > now && entry.failures == 0 {
+            log::warn!(
+                "{} locked out for {}s after too many wrong passwords",
+                k,
+                (entry.locked_until - now) / 1000
+            );
+        }
+    }
+    lock.failures
+        .retain(|_, x| now - x.last_failure <= FORGET_AFTER || x.locked_until > now);
+    lock.store();
+}
+
+// the budget of all relays is left to expire, a success through one relay says nothing
+// about the others
+pub fn on_success(peer_id: &str, ip: Option<&str>) {
+    let mut lock = FAILURES.lock().unwrap();
+    let mut changed = false;
+    for k in keys(peer_id, ip) {
+        if k != RELAY_KEY {
+            changed |= lock.failures.remove(&k).is_some();
+        }
+    }
+    if changed {
+        lock.store();
+    }
+}
+
+pub fn get_login_failures() -> HashMap<String, LoginFailure> {
+    FAILURES.lock().unwrap().failures.clone()
+}
+
+/// Clear one key, e.g. "id:123456789", "ip:1.2.3.4" or "relay", or all of them if empty.
+pub fn clear_login_failures(key: &str) {
+    let mut lock = FAILURES.lock().unwrap();
+    if key.is_empty() {
+        lock.failures.clear();
+    } else {
+        lock.failures.remove(key);
+    }
+    lock.store();
+    log::info!("login failures cleared: {}", if key.is_empty() { "*" } else { key });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout() {
+        let settings = Settings {
+            max_failures: 3,
+            lockout: 60_000,
+        };
+        let mut entry = LoginFailure::default();
+        let now = FORGET_AFTER + 1;
+        record_failure(&mut entry, &settings, now);
+        record_failure(&mut entry, &settings, now);
+        assert_eq!(remaining(&entry, now), 0);
+        record_failure(&mut entry, &settings, now);
+        assert_eq!(remaining(&entry, now), 60_000);
+        for _ in 0..3 {
+            record_failure(&mut entry, &settings, now);
+        }
+        assert_eq!(remaining(&entry, now), 120_000);
+        for _ in 0..3 {
+            record_failure(&mut entry, &settings, now);
+        }
+        assert_eq!(remaining(&entry, now), 240_000);
+        record_failure(&mut entry, &settings, now + FORGET_AFTER + 1);
+        assert_eq!(entry.lockouts, 0);
+        assert_eq!(entry.failures, 1);
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(keys(" 123 ", Some("1.2.3.4")), vec!["id:123", "ip:1.2.3.4"]);
+        assert_eq!(keys("123", None), vec!["id:123", RELAY_KEY]);
+        let settings = Settings::parse("", "abc");
+        assert_eq!(
+            settings,
+            Settings {
+                max_failures: DEFAULT_MAX_FAILURES,
+                lockout: DEFAULT_LOCKOUT_DURATION * 1000,
+            }
+        );
+        assert_eq!(settings.for_key("id:123"), settings);
+        assert_eq!(
+            Settings::parse("3", "10").for_key(RELAY_KEY),
+            Settings {
+                max_failures: 3 * RELAY_FACTOR,
+                lockout: 10_000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_lockout_duration() {
+        assert_eq!(lockout_duration(60_000, 1), 60_000);
+        assert_eq!(lockout_duration(60_000, 3), 240_000);
+        assert_eq!(lockout_duration(60_000, 100), MAX_LOCKOUT);
+    }
+}
@@ -0,0 +1,297 @@
+// Access windows in the "access-schedule" option, separated by newline or semicolon:
+//   tz UTC+02:00
+//   mon-fri 22:00-02:00
+//   sat,sun 00:00-24:00
+//   except 123456789
+//   hide
+// "tz" is "local" (the default), "UTC" or a fixed offset like UTC-05:00.
+// A window ending at or before its start runs past midnight, into the day after the listed one.
+// Peers listed after "except" can log in at any time.
+// With "hide" the rendezvous servers are left outside the windows, so the ID shows offline.
+// No window at all means no restriction.
+
+use hbb_common::{bail, config::Config, log, ResultType};
+
+pub const OPTION_ACCESS_SCHEDULE: &'static str = "access-schedule";
+
+const DAYS: [&'static str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    // index 0 is Monday
+    days: [bool; 7],
+    // minutes since midnight, end is exclusive
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    pub fn is_open(&self, weekday: usize, minute: u32) -> bool {
+        if self.start < self.end {
+            self.days[weekday] && self.start <= minute && minute < self.end
+        } else {
+            (self.days[weekday] && minute >= self.start)
+                || (self.days[(weekday + 6) % 7] && minute < self.end)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schedule {
+    // offset from UTC in seconds, None for the local time
+    offset: Option<i32>,
+    windows: Vec<Window>,
+    exceptions: Vec<String>,
+    hide: bool,
+}
+
+fn parse_day(s: &str) -> ResultType<usize> {
+    let s = s.to_lowercase();
+    // "mon", "tues" or "monday"
+    match DAYS.iter().position(|d| s.len() >= 3 && d.starts_with(&s)) {
+        Some(i) => Ok(i),
+        None => bail!("Invalid day: {}", s),
+    }
+}
+
+fn parse_days(s: &str) -> ResultType<[bool; 7]> {
+    let mut days = [false; 7];
+    if s == "*" || s.eq_ignore_ascii_case("daily") {
+        return Ok([true; 7]);
+    }
+    for part in s.split(',').filter(|x| !x.is_empty()) {
+        match part.split_once('-') {
+            Some((a, b)) => {
+                let (a, b) = (parse_day(a)?, parse_day(b)?);
+                // fri-mon wraps over the weekend
+                let mut i = a;
+                loop {
+                    days[i] = true;
+                    if i == b {
+                        break;
+                    }
+                    i = (i + 1) % 7;
+                }
+            }
+            None => days[parse_day(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+fn parse_time(s: &str) -> ResultType<u32> {
+    let (h, m) = match s.split_once(':') {
+        Some((h, m)) => (h.parse::<u32>(), m.parse::<u32>()),
+        None => (s.parse::<u32>(), Ok(0)),
+    };
+    match (h, m) {
+        (Ok(h), Ok(m)) if m < 60 && h * 60 + m <= MINUTES_PER_DAY => Ok(h * 60 + m),
+        _ => bail!("Invalid time: {}", s),
+    }
+}
+
+pub fn parse_offset(s: &str) -> ResultType<Option<i32>> {
+    let lower = s.to_lowercase();
+    if lower == "local" {
+        return Ok(None);
+    }
+    let rest = lower.trim_start_matches("utc").trim_start_matches("gmt");
+    if rest.is_empty() {
+        return Ok(Some(0));
+    }
+    let sign = match rest.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => bail!("Invalid timezone, should be local or like UTC+02:00: {}", s),
+    };
+    match parse_time(&rest[1..]) {
+        Ok(minutes) if minutes <= 14 * 60 => Ok(Some(sign * minutes as i32 * 60)),
+        _ => bail!("Invalid timezone offset: {}", s),
+    }
+}
+
+/// `days` like mon-fri and `times` like 22:00-02:00
+pub fn parse_window(days: &str, times: &str) -> ResultType<Window> {
+    let (start, end) = match times.split_once('-') {
+        Some((start, end)) => (parse_time(start)?, parse_time(end)?),
+        None => bail!("Invalid hours, should be like 22:00-02:00: {}", times),
+    };
+    if start == MINUTES_PER_DAY {
+        bail!("Invalid window start: {}", times);
+    }
+    Ok(Window {
+        days: parse_days(days)?,
+        start,
+        end,
+    })
+}
+
+/// Weekday (0 is Monday) and minute of the day now, at `offset` seconds from UTC or local time
+pub fn now(offset: Option<i32>) -> (usize, u32) {
+    use chrono::{Datelike, Timelike};
+    match offset {
+        None => {
+            let now = chrono::Local::now();
+            (
+                now.weekday().num_days_from_monday() as usize,
+                now.hour() * 60 + now.minute(),
+            )
+        }
+        Some(offset) => {
+            let now = chrono::Utc::now().with_timezone(&chrono::FixedOffset::east(offset));
+            (
+                now.weekday().num_days_from_monday() as usize,
+                now.hour() * 60 + now.minute(),
+            )
+        }
+    }
+}
+
+pub fn parse(s: &str) -> ResultType<Schedule> {
+    let mut schedule = Schedule::default();
+    for line in s
+        .split(|c| c == '\n' || c == ';')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+    {
+        let mut it = line.split_whitespace();
+        let first = it.next().unwrap_or_default();
+        match first.to_lowercase().as_str() {
+            "tz" => match (it.next(), it.next()) {
+                (Some(tz), None) => schedule.offset = parse_offset(tz)?,
+                _ => bail!("Invalid timezone: {}", line),
+            },
+            "except" => {
+                let ids: Vec<String> = it
+                    .flat_map(|x| x.split(','))
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_owned())
+                    .collect();
+                if ids.is_empty() {
+                    bail!("Missing ID: {}", line);
+                }
+                schedule.exceptions.extend(ids);
+            }
+            "hide" if it.next().is_none() => schedule.hide = true,
+            _ => match (it.next(), it.next()) {
+                (Some(times), None) => schedule.windows.push(parse_window(first, times)?),
+                _ => bail!("Invalid window, should be like mon-fri 22:00-02:00: {}", line),
+            },
+        }
+    }
+    Ok(schedule)
+}
+
+impl Schedule {
+    /// `weekday` 0 is Monday, `minute` is since midnight, both in the schedule's timezone
+    fn is_open_at(&self, weekday: usize, minute: u32) -> bool {
+        self.windows.is_empty() || self.windows.iter().any(|w| w.is_open(weekday, minute))
+    }
+
+    fn is_open(&self) -> bool {
+        let (weekday, minute) = now(self.offset);
+        self.is_open_at(weekday, minute)
+    }
+
+    fn is_exempt(&self, peer_id: &str) -> bool {
+        self.exceptions
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(peer_id.trim()))
+    }
+}
+
+fn get_schedule() -> Option<Schedule> {
+    let s = Config::get_option(OPTION_ACCESS_SCHEDULE);
+    if s.trim().is_empty() {
+        return None;
+    }
+    match parse(&s) {
+        Ok(schedule) => Some(schedule),
+        Err(err) => {
+            // fail closed like the access control rules, no window at all
+            log::error!("Invalid access schedule: {}", err);
+            Some(Schedule {
+                windows: vec![Window {
+                    days: [false; 7],
+                    start: 0,
+                    end: MINUTES_PER_DAY,
+                }],
+                ..Default::default()
+            })
+        }
+    }
+}
+
+/// Evaluated at login, before the password is checked.
+pub fn is_allowed(peer_id: &str) -> bool {
+    match get_schedule() {
+        Some(schedule) => schedule.is_exempt(peer_id) || schedule.is_open(),
+        None => true,
+    }
+}
+
+/// Whether to stay registered on the rendezvous servers now.
+pub fn should_advertise() -> bool {
+    match get_schedule() {
+        Some(schedule) => !schedule.hide || schedule.is_open(),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("").unwrap(), Schedule::default());
+        let schedule =
+            parse("tz UTC+02:00\nmon-fri 22:00-02:00; sat,sun 0-24\nexcept 123456789\nhide")
+                .unwrap();
+        assert_eq!(schedule.offset, Some(7200));
+        assert_eq!(schedule.windows.len(), 2);
+        assert_eq!(schedule.exceptions, vec!["123456789".to_owned()]);
+        assert!(schedule.hide);
+        assert_eq!(parse("tz UTC-05:30").unwrap().offset, Some(-19800));
+        assert_eq!(parse("tz local").unwrap().offset, None);
+        assert!(parse("tz Europe/Berlin").is_err());
+        assert!(parse("mon-fri 22:00").is_err());
+        assert!(parse("mon-fri 25:00-02:00").is_err());
+        assert!(parse("funday 10:00-12:00").is_err());
+        assert!(parse("mo 10:00-12:00").is_err());
+        assert_eq!(parse_days("Tuesday,thu-fri").unwrap(), [false, true, false, true, true, false, false]);
+        assert!(parse("except").is_err());
+    }
+
+    #[test]
+    fn test_is_open() {
+        let schedule = parse("mon-fri 22:00-02:00\nsun 10:00-12:00\nfri-mon 12:00-13:00").unwrap();
+        // monday 23:00
+        assert!(schedule.is_open_at(0, 23 * 60));
+        // tuesday 01:59, from monday night
+        assert!(schedule.is_open_at(1, 2 * 60 - 1));
+        assert!(!schedule.is_open_at(1, 2 * 60));
+        // saturday 01:00, from friday night
+        assert!(schedule.is_open_at(5, 60));
+        // sunday 01:00, saturday night is not in the window
+        assert!(!schedule.is_open_at(6, 60));
+        // monday 01:00, sunday night is not in the window either
+        assert!(!schedule.is_open_at(0, 60));
+        assert!(schedule.is_open_at(6, 11 * 60));
+        assert!(schedule.is_open_at(6, 12 * 60 + 30));
+        assert!(!schedule.is_open_at(2, 12 * 60 + 30));
+        assert!(parse("tz UTC").unwrap().is_open_at(3, 0));
+        assert!(parse("daily 0-24").unwrap().is_open_at(3, 24 * 60 - 1));
+        assert!(!schedule.is_exempt("123456789"));
+        assert!(parse("except 123456789,987654321").unwrap().is_exempt("987654321"));
+    }
+}
@@ -179,6 +179,24 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         conn_ids
     }
 
+    // only to the subscribers in `ids`, returns the ones it was sent to
+    pub fn send_video_frame_to(&self, msg: Message, ids: &HashSet<i32>) -> HashSet<i32> {
+        let msg = Arc::new(msg);
+        let mut conn_ids = HashSet::new();
+        let mut lock = self.0.write().unwrap();
+        for s in lock.subscribes.values_mut() {
+            if ids.contains(&s.id()) {
+                s.send(msg.clone());
+                conn_ids.insert(s.id());
+            }
+        }
+        conn_ids
+    }
+
+    pub fn subscriber_ids(&self) -> Vec<i32> {
+        self.0.read().unwrap().subscribes.keys().cloned().collect()
+    }
+
     pub fn send_without(&self, msg: Message, sub: i32) {
         let mut lock = self.0.write().unwrap();
         let msg = Arc::new(msg);
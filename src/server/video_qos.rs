@@ -0,0 +1,252 @@
+// Congestion control of the video stream. Once a second the controller looks at
+//   - the round trip time measured with TestDelay, against the lowest one seen lately,
+//   - how long the peers take to ack a frame with video_received,
+//   - how full the video queues of the connections are, and the frames dropped on a full queue,
+// and moves the encoder bitrate and the capture frame rate: down quickly when the link is
+// congested, back up slowly when it is clear (AIMD). The frame rate stays between the
+// "video-min-fps" and "video-max-fps" options, 5 and 30 by default. A peer asking for a
+// frame rate with custom_fps gets up to that instead of the default 30, but never more
+// than "video-max-fps" when it is set.
+
+use hbb_common::config::Config;
+use std::{collections::VecDeque, time::Duration};
+
+pub const OPTION_MIN_FPS: &'static str = "video-min-fps";
+pub const OPTION_MAX_FPS: &'static str = "video-max-fps";
+const DEFAULT_MIN_FPS: u32 = 5;
+const DEFAULT_MAX_FPS: u32 = 30;
+const MAX_FPS_LIMIT: u32 = 120;
+pub const ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+// lowest and highest percentage of the bitrate asked by the image quality
+const MIN_RATIO: u32 = 10;
+const MAX_RATIO: u32 = 100;
+// rtt samples kept for the baseline, they come every few seconds
+const RTT_WINDOW: usize = 20;
+// the min quantizer goes up by this much at the lowest bitrate
+const QUANTIZER_RANGE: u32 = 24;
+
+/// What happened on the links since the last adjustment
+#[derive(Debug, Default, Clone)]
+pub struct Signals {
+    // ms, 0 if not measured
+    pub rtt: i64,
+    // ms, from sending a frame until all the peers acked it
+    pub ack_delays: Vec<u64>,
+    // the fullest video queue
+    pub queue_depth: usize,
+    pub queue_size: usize,
+    // frames dropped because a queue was full
+    pub queue_full: u32,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Congested,
+    Loaded,
+    Clear,
+}
+
+pub struct VideoQoS {
+    min_fps: u32,
+    max_fps: u32,
+    fps: u32,
+    base_bitrate: u32,
+    base_min_quantizer: u32,
+    max_quantizer: u32,
+    ratio: u32,
+    rtts: VecDeque<i64>,
+}
+
+fn get_fps_option(name: &str) -> Option<u32> {
+    Config::get_option(name)
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .filter(|x| *x > 0)
+}
+
+fn fps_range(min_option: Option<u32>, max_option: Option<u32>, fps: u32) -> (u32, u32) {
+    let max = match (max_option, fps) {
+        (Some(max), 0) => max,
+        (Some(max), fps) => std::cmp::min(max, fps),
+        (None, 0) => DEFAULT_MAX_FPS,
+        (None, fps) => fps,
+    };
+    let max = std::cmp::min(max, MAX_FPS_LIMIT);
+    let min = std::cmp::min(min_option.unwrap_or(DEFAULT_MIN_FPS), max);
+    (min, max)
+}
+
+/// `fps` is the frame rate asked by the peers, 0 if they did not ask
+pub fn get_fps_range(fps: u32) -> (u32, u32) {
+    fps_range(
+        get_fps_option(OPTION_MIN_FPS),
+        get_fps_option(OPTION_MAX_FPS),
+        fps,
+    )
+}
+
+impl VideoQoS {
+    /// `bitrate` and the quantizers are the ones asked by the image quality, used when the link is clear,
+    /// `fps` the frame rate asked, 0 for the default
+    pub fn new(bitrate: u32, min_quantizer: u32, max_quantizer: u32, fps: u32) -> Self {
+        let (min_fps, max_fps) = get_fps_range(fps);
+        Self::with_fps_range(bitrate, min_quantizer, max_quantizer, min_fps, max_fps)
+    }
+
+    fn with_fps_range(
+        bitrate: u32,
+        min_quantizer: u32,
+        max_quantizer: u32,
+        min_fps: u32,
+        max_fps: u32,
+    ) -> Self {
+        Self {
+            min_fps,
+            max_fps,
+            fps: max_fps,
+            base_bitrate: bitrate,
+            base_min_quantizer: min_quantizer,
+            max_quantizer,
+            ratio: MAX_RATIO,
+            rtts: VecDeque::new(),
+        }
+    }
+
+    pub fn fps(&self) -> u32 {
+        self.fps
+    }
+
+    pub fn spf(&self) -> Duration {
+        Duration::from_secs_f32(1. / self.fps as f32)
+    }
+
+    pub fn bitrate(&self) -> u32 {
+        std::cmp::max(self.base_bitrate * self.ratio / 100, 1)
+    }
+
+    pub fn min_quantizer(&self) -> u32 {
+        let q = self.base_min_quantizer
+            + (MAX_RATIO - self.ratio) * QUANTIZER_RANGE / (MAX_RATIO - MIN_RATIO);
+        std::cmp::min(q, self.max_quantizer)
+    }
+
+    fn state(&mut self, signals: &Signals) -> State {
+        if signals.rtt > 0 {
+            self.rtts.push_back(signals.rtt);
+            if self.rtts.len() > RTT_WINDOW {
+                self.rtts.pop_front();
+            }
+        }
+        let base_rtt = self.rtts.iter().min().cloned().unwrap_or(0);
+        // queueing delay on top of the path's own
+        let rtt_growth = if signals.rtt > 0 && base_rtt > 0 {
+            signals.rtt - base_rtt
+        } else {
+            0
+        };
+        let ack_delay = if signals.ack_delays.is_empty() {
+            0
+        } else {
+            signals.ack_delays.iter().sum::<u64>() / signals.ack_delays.len() as u64
+        };
+        // time to ack beyond a round trip and a frame, is spent waiting in some queue
+        let frame_ms = 1000 / self.fps as u64;
+        let ack_excess = ack_delay.saturating_sub(base_rtt.max(0) as u64 + frame_ms);
+        if signals.queue_full > 0
+            || rtt_growth > std::cmp::max(base_rtt, 100)
+            || ack_excess > 300
+        {
+            State::Congested
+        } else if rtt_growth > std::cmp::max(base_rtt / 2, 50)
+            || ack_excess > 100
+            || (signals.queue_size > 1 && signals.queue_depth >= signals.queue_size - 1)
+        {
+            State::Loaded
+        } else {
+            State::Clear
+        }
+    }
+
+    /// Called every ADJUST_INTERVAL, returns true if the bitrate changed
+    pub fn adjust(&mut self, signals: &Signals) -> bool {
+        let old = (self.bitrate(), self.min_quantizer());
+        match self.state(signals) {
+            State::Congested => {
+                self.ratio = std::cmp::max(self.ratio * 7 / 10, MIN_RATIO);
+                self.fps = std::cmp::max(self.fps * 3 / 4, self.min_fps);
+            }
+            State::Loaded => {
+                self.ratio = std::cmp::max(self.ratio * 9 / 10, MIN_RATIO);
+                self.fps = std::cmp::max(self.fps.saturating_sub(1), self.min_fps);
+            }
+            State::Clear => {
+                self.ratio = std::cmp::min(self.ratio + 5, MAX_RATIO);
+                self.fps = std::cmp::min(self.fps + 1, self.max_fps);
+            }
+        }
+        old != (self.bitrate(), self.min_quantizer())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signals(rtt: i64, ack: u64) -> Signals {
+        Signals {
+            rtt,
+            ack_delays: vec![ack],
+            queue_size: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_congestion() {
+        let mut qos = VideoQoS::with_fps_range(1000, 12, 56, 5, 30);
+        assert!(!qos.adjust(&signals(50, 60)));
+        assert_eq!((qos.fps(), qos.bitrate(), qos.min_quantizer()), (30, 1000, 12));
+        // rtt quadrupled, the acks are late
+        assert!(qos.adjust(&signals(200, 500)));
+        assert_eq!(qos.fps(), 22);
+        assert_eq!(qos.bitrate(), 700);
+        assert!(qos.min_quantizer() > 12);
+        let mut s = signals(50, 60);
+        s.queue_full = 1;
+        for _ in 0..20 {
+            qos.adjust(&s);
+        }
+        assert_eq!(qos.fps(), 5);
+        assert_eq!(qos.bitrate(), 100);
+        assert_eq!(qos.min_quantizer(), 12 + 24);
+        // recovers slowly
+        assert!(qos.adjust(&signals(50, 60)));
+        assert_eq!(qos.fps(), 6);
+        assert_eq!(qos.bitrate(), 150);
+        for _ in 0..40 {
+            qos.adjust(&signals(50, 60));
+        }
+        assert_eq!((qos.fps(), qos.bitrate(), qos.min_quantizer()), (30, 1000, 12));
+    }
+
+    #[test]
+    fn test_loaded() {
+        let mut qos = VideoQoS::with_fps_range(1000, 12, 56, 5, 30);
+        qos.adjust(&signals(100, 120));
+        let mut s = signals(100, 120);
+        s.queue_depth = 2;
+        assert!(qos.adjust(&s));
+        assert_eq!((qos.fps(), qos.bitrate()), (29, 900));
+    }
+
+    #[test]
+    fn test_fps_range() {
+        assert_eq!(fps_range(None, None, 0), (5, 30));
+        assert_eq!(fps_range(None, None, 60), (5, 60));
+        assert_eq!(fps_range(None, None, 500), (5, 120));
+        assert_eq!(fps_range(None, Some(20), 60), (5, 20));
+        assert_eq!(fps_range(Some(10), Some(20), 0), (10, 20));
+        assert_eq!(fps_range(Some(10), None, 8), (8, 8));
+    }
+}
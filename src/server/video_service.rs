@@ -18,12 +18,17 @@
 // to-do:
 // https://slhck.info/video/2017/03/01/rate-control.html
 
-use super::*;
+use super::{
+    video_qos::{self, VideoQoS},
+    *,
+};
 use hbb_common::tokio::sync::{
     mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     Mutex as TokioMutex,
 };
-use scrap::{Capturer, Config, Display, EncodeFrame, Encoder, Frame, VideoCodecId, STRIDE_ALIGN};
+use scrap::{
+    Capturer, Config, Display, EncodedFrame, Frame, Rect, VideoCodecId, VideoEncoder, STRIDE_ALIGN,
+};
 use std::{
     collections::HashSet,
     io::{ErrorKind::WouldBlock, Result},
@@ -33,13 +38,42 @@ use std::{
 use virtual_display;
 
 pub const NAME: &'static str = "video";
+// comma separated codecs, the first one this side and the peer both support is used
+pub const OPTION_CODEC_PREFERENCE: &'static str = "video-codec-preference";
+const DEFAULT_CODEC_PREFERENCE: &'static str = "av1,vp9,h264";
+// the screen is polled less often once it has not changed for a while
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+const IDLE_SPF: Duration = Duration::from_millis(100);
+// how long a tier waits for the ack of its last frame before sending the next one anyway
+const ACK_TIMEOUT: Duration = Duration::from_secs(3);
+// changed rectangles kept for a tier before encoding their bounds instead
+const MAX_DIRTY_RECTS: usize = 256;
+// ImageQuality::Lossless in the converted quality, above its bitrate and quantizer
+const QUALITY_LOSSLESS: i32 = 1 << 16;
+// how long the screen stays static before a 4:4:4 tier sends it without loss
+const LOSSLESS_DELAY: Duration = Duration::from_secs(1);
+// smallest percentage of the native resolution a peer can ask for
+const MIN_SCALE: u32 = 10;
 
 lazy_static::lazy_static! {
-    static ref CURRENT_DISPLAY: Arc<Mutex<usize>> = Arc::new(Mutex::new(usize::MAX));
-    static ref LAST_ACTIVE: Arc<Mutex<Instant>> = Arc::new(Mutex::new(Instant::now()));
+    // the display watched by each connection, the primary one if not switched
+    static ref CONN_DISPLAYS: Arc<Mutex<HashMap<i32, usize>>> = Default::default();
     static ref SWITCH: Arc<Mutex<bool>> = Default::default();
     static ref TEST_LATENCIES: Arc<Mutex<HashMap<i32, i64>>> = Default::default();
+    static ref VIDEO_QUEUE_DEPTHS: Arc<Mutex<HashMap<i32, usize>>> = Default::default();
     static ref IMAGE_QUALITIES: Arc<Mutex<HashMap<i32, i32>>> = Default::default();
+    static ref USER_FPS: Arc<Mutex<HashMap<i32, u32>>> = Default::default();
+    static ref CODECS: Arc<Mutex<HashMap<i32, VideoCodecId>>> = Default::default();
+    // percentage of the native resolution asked by each connection
+    static ref IMAGE_SCALES: Arc<Mutex<HashMap<i32, u32>>> = Default::default();
+    // the display watched by the connections getting it downscaled, with its scaled size
+    static ref SCALED_DISPLAYS: Arc<Mutex<HashMap<i32, (DisplayInfo, i32, i32)>>> = Default::default();
+    // connections streaming every display, not only the current one
+    static ref ALL_DISPLAYS_CONN_IDS: Arc<Mutex<HashSet<i32>>> = Default::default();
+    // frames dropped on a full video queue since the last bitrate adjustment, per connection
+    static ref QUEUE_FULL_COUNTS: Arc<Mutex<HashMap<i32, u32>>> = Default::default();
+    // connections waiting for a key frame from the encoder of their tier
+    static ref KEY_FRAME_CONN_IDS: Arc<Mutex<HashSet<i32>>> = Default::default();
     static ref FRAME_FETCHED_NOTIFIER: (UnboundedSender<(i32, Option<Instant>)>, Arc<TokioMutex<UnboundedReceiver<(i32, Option<Instant>)>>>) = {
         let (tx, rx) = unbounded_channel();
         (tx, Arc::new(TokioMutex::new(rx)))
@@ -59,6 +93,27 @@ pub fn notify_video_frame_feched(conn_id: i32, frame_tm: Option<Instant>) {
     FRAME_FETCHED_NOTIFIER.0.send((conn_id, frame_tm)).unwrap()
 }
 
+pub fn request_key_frame(conn_id: i32) {
+    KEY_FRAME_CONN_IDS.lock().unwrap().insert(conn_id);
+}
+
+// called when the video queue of a connection is full and the frame is dropped
+pub fn notify_queue_full(conn_id: i32) {
+    *QUEUE_FULL_COUNTS.lock().unwrap().entry(conn_id).or_default() += 1;
+    request_key_frame(conn_id);
+    // the dropped frame will never be fetched, do not wait for it
+    notify_video_frame_feched(conn_id, None);
+}
+
+pub fn is_key_frame(vf: &VideoFrame) -> bool {
+    match &vf.union {
+        Some(video_frame::Union::vp9s(vp9s)) => vp9s.frames.iter().any(|f| f.key),
+        Some(video_frame::Union::av1s(av1s)) => av1s.frames.iter().any(|f| f.key),
+        Some(video_frame::Union::h264s(h264s)) => h264s.frames.iter().any(|f| f.key),
+        _ => true,
+    }
+}
+
 pub fn set_privacy_mode_conn_id(conn_id: i32) {
     *PRIVACY_MODE_CONN_ID.lock().unwrap() = conn_id
 }
@@ -75,52 +130,65 @@ pub fn is_privacy_mode_supported() -> bool {
 }
 
 struct VideoFrameController {
-    cur: Instant,
-    send_conn_ids: HashSet<i32>,
+    // the frames sent and not acked yet, with the time they were captured, per connection
+    pending: HashMap<i32, Instant>,
+    // ms from capturing the frame until the connection acked it
+    ack_delays: HashMap<i32, u64>,
 }
 
 impl VideoFrameController {
     fn new() -> Self {
         Self {
-            cur: Instant::now(),
-            send_conn_ids: HashSet::new(),
+            pending: HashMap::new(),
+            ack_delays: HashMap::new(),
         }
     }
 
-    fn reset(&mut self) {
-        self.send_conn_ids.clear();
-    }
-
     fn set_send(&mut self, tm: Instant, conn_ids: HashSet<i32>) {
-        if !conn_ids.is_empty() {
-            self.cur = tm;
-            self.send_conn_ids = conn_ids;
+        for id in conn_ids {
+            self.pending.insert(id, tm);
         }
     }
 
+    // the connections whose last frame is not acked yet, the next one of their tier waits
+    fn waiting_conn_ids(&mut self) -> HashSet<i32> {
+        // a lost ack does not stall the tier for ever
+        self.pending.retain(|_, tm| tm.elapsed() < ACK_TIMEOUT);
+        self.pending.keys().cloned().collect()
+    }
+
+    // collect the acks received within `timeout`, return false if none is expected
     #[tokio::main(flavor = "current_thread")]
-    async fn try_wait_next(&mut self, fetched_conn_ids: &mut HashSet<i32>, timeout_millis: u64) {
-        if self.send_conn_ids.is_empty() {
-            return;
+    async fn try_wait_next(&mut self, timeout: Duration) -> bool {
+        if self.pending.is_empty() {
+            return false;
         }
-
-        let timeout_dur = Duration::from_millis(timeout_millis as u64);
-        match tokio::time::timeout(timeout_dur, FRAME_FETCHED_NOTIFIER.1.lock().await.recv()).await
-        {
+        let mut rx = FRAME_FETCHED_NOTIFIER.1.lock().await;
+        match tokio::time::timeout(timeout, rx.recv()).await {
             Err(_) => {
                 // break if timeout
-                // log::error!("blocking wait frame receiving timeout {}", timeout_millis);
+                // log::error!("blocking wait frame receiving timeout {:?}", timeout);
             }
             Ok(Some((id, instant))) => {
-                if let Some(tm) = instant {
-                    log::trace!("Channel recv latency: {}", tm.elapsed().as_secs_f32());
+                self.on_fetched(id, instant);
+                while let Ok((id, instant)) = rx.try_recv() {
+                    self.on_fetched(id, instant);
                 }
-                fetched_conn_ids.insert(id);
             }
             Ok(None) => {
                 // this branch would nerver be reached
             }
         }
+        true
+    }
+
+    fn on_fetched(&mut self, id: i32, instant: Option<Instant>) {
+        if let Some(cur) = self.pending.remove(&id) {
+            if let Some(tm) = instant {
+                log::trace!("Channel recv latency: {}", tm.elapsed().as_secs_f32());
+                self.ack_delays.insert(id, cur.elapsed().as_millis() as u64);
+            }
+        }
     }
 }
 
@@ -164,6 +232,399 @@ impl TraitCapturer for scrap::CapturerMag {
     }
 }
 
+// Connections asking for the same image quality and frame rate, and decoding the same codec,
+// share an encoder, so a slow or low quality viewer does not hold back the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TierKey {
+    quality: i32,
+    // 0 if not asked
+    fps: u32,
+    codec: VideoCodecId,
+    // percentage of the native resolution, 100 for none
+    scale: u32,
+}
+
+struct Tier {
+    encoder: VideoEncoder,
+    qos: VideoQoS,
+    conn_ids: HashSet<i32>,
+    next_frame: Instant,
+    last_adjust: Instant,
+    ack_delays: Vec<u64>,
+    // changes since the last frame the encoder gave, including the captures skipped or dropped
+    dirty: Vec<Rect>,
+    // a whole frame is needed, even if nothing changed
+    key_frame: bool,
+    // encodes I444 with VP9 profile 1, and a lossless frame once the screen is static
+    yuv444: bool,
+    // the lossless frame was sent, nothing changed since
+    refined: bool,
+    scale: u32,
+    // the encoded size, smaller than the display's if scaled
+    width: usize,
+    height: usize,
+}
+
+impl Tier {
+    fn new(key: TierKey, width: usize, height: usize) -> ResultType<Self> {
+        let (width, height) = scaled_size(width, height, key.scale);
+        let (bitrate, rc_min_quantizer, rc_max_quantizer, speed) =
+            get_quality(width, height, key.quality);
+        log::info!(
+            "{:?}: bitrate={}, rc_min_quantizer={}",
+            key,
+            bitrate,
+            rc_min_quantizer
+        );
+        let cfg = Config {
+            width: width as _,
+            height: height as _,
+            timebase: [1, 1000], // Output timestamp precision
+            bitrate,
+            codec: key.codec,
+            rc_min_quantizer,
+            rc_max_quantizer,
+            speed,
+            yuv444: is_yuv444(key.quality),
+        };
+        let encoder = match VideoEncoder::new(&cfg, (num_cpus::get() / 2) as _) {
+            Ok(x) => x,
+            Err(err) => bail!("Failed to create encoder: {}", err),
+        };
+        Ok(Self {
+            encoder,
+            qos: VideoQoS::new(bitrate, rc_min_quantizer, rc_max_quantizer, key.fps),
+            conn_ids: HashSet::new(),
+            next_frame: Instant::now(),
+            last_adjust: Instant::now(),
+            ack_delays: Vec::new(),
+            dirty: Vec::new(),
+            key_frame: true,
+            yuv444: cfg.yuv444,
+            refined: false,
+            scale: key.scale,
+            width,
+            height,
+        })
+    }
+
+    fn set_key_frame(&mut self) {
+        self.key_frame = true;
+    }
+
+    // `rects` of the display, covered in the pixels of a scaled tier
+    fn add_dirty(&mut self, rects: &[Rect]) {
+        if self.scale < 100 {
+            let s = self.scale as usize;
+            self.dirty.extend(rects.iter().map(|r| {
+                let (x, y) = (r.x * s / 100, r.y * s / 100);
+                let right = std::cmp::min(((r.x + r.w) * s + 99) / 100, self.width);
+                let bottom = std::cmp::min(((r.y + r.h) * s + 99) / 100, self.height);
+                Rect {
+                    x,
+                    y,
+                    w: right.saturating_sub(x),
+                    h: bottom.saturating_sub(y),
+                }
+            }));
+        } else {
+            self.dirty.extend_from_slice(rects);
+        }
+        if self.dirty.len() > MAX_DIRTY_RECTS {
+            let x = self.dirty.iter().map(|r| r.x).min().unwrap_or(0);
+            let y = self.dirty.iter().map(|r| r.y).min().unwrap_or(0);
+            let right = self.dirty.iter().map(|r| r.x + r.w).max().unwrap_or(0);
+            let bottom = self.dirty.iter().map(|r| r.y + r.h).max().unwrap_or(0);
+            self.dirty = vec![Rect {
+                x,
+                y,
+                w: right - x,
+                h: bottom - y,
+            }];
+        }
+    }
+
+    fn adjust(&mut self) {
+        self.last_adjust = Instant::now();
+        let ids = &self.conn_ids;
+        let mut queue_full = 0;
+        QUEUE_FULL_COUNTS.lock().unwrap().retain(|id, n| {
+            if ids.contains(id) {
+                queue_full += *n;
+                false
+            } else {
+                true
+            }
+        });
+        let signals = video_qos::Signals {
+            rtt: TEST_LATENCIES
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(id, _)| ids.contains(id))
+                .map(|(_, x)| *x)
+                .max()
+                .unwrap_or(0),
+            ack_delays: std::mem::take(&mut self.ack_delays),
+            queue_depth: VIDEO_QUEUE_DEPTHS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(id, _)| ids.contains(id))
+                .map(|(_, x)| *x)
+                .max()
+                .unwrap_or(0),
+            queue_size: super::connection::VIDEO_QUEUE_SIZE,
+            queue_full,
+        };
+        if self.qos.adjust(&signals) {
+            log::debug!(
+                "video qos of {:?}: bitrate={}, rc_min_quantizer={}, fps={}, {:?}",
+                self.conn_ids,
+                self.qos.bitrate(),
+                self.qos.min_quantizer(),
+                self.qos.fps(),
+                signals
+            );
+            if let Err(err) =
+                self.encoder
+                    .set_bitrate(self.qos.bitrate(), self.qos.min_quantizer(), 0)
+            {
+                log::error!("Failed to set bitrate: {}", err);
+            }
+        }
+    }
+}
+
+fn get_tier_key(id: i32) -> TierKey {
+    let quality = IMAGE_QUALITIES
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .unwrap_or(convert_quality(ImageQuality::Balanced.value()));
+    TierKey {
+        quality,
+        fps: USER_FPS.lock().unwrap().get(&id).cloned().unwrap_or(0),
+        scale: get_image_scale(id),
+        // only VP9 has a 4:4:4 profile, every peer decodes it
+        codec: if is_yuv444(quality) {
+            VideoCodecId::VP9
+        } else {
+            CODECS
+                .lock()
+                .unwrap()
+                .get(&id)
+                .cloned()
+                .unwrap_or(VideoCodecId::VP9)
+        },
+    }
+}
+
+fn get_image_scale(id: i32) -> u32 {
+    // the mobile capturers give I420 only, and all the displays are composed at their size
+    if cfg!(any(target_os = "android", target_os = "ios"))
+        || ALL_DISPLAYS_CONN_IDS.lock().unwrap().contains(&id)
+    {
+        return 100;
+    }
+    IMAGE_SCALES.lock().unwrap().get(&id).cloned().unwrap_or(100)
+}
+
+// even, as the chroma planes are half the size
+fn scaled_size(width: usize, height: usize, scale: u32) -> (usize, usize) {
+    if scale >= 100 {
+        return (width, height);
+    }
+    let scale = scale as usize;
+    (
+        std::cmp::max(width * scale / 100 / 2 * 2, 2),
+        std::cmp::max(height * scale / 100 / 2 * 2, 2),
+    )
+}
+
+#[inline]
+fn is_yuv444(quality: i32) -> bool {
+    // the mobile capturers give I420 only
+    quality & QUALITY_LOSSLESS != 0 && cfg!(not(any(target_os = "android", target_os = "ios")))
+}
+
+// The whole screen as last captured, updated tile by tile. With a 4:4:4 or a scaled tier the
+// screen is captured in BGRA and converted here to I444, and to I420 for the other tiers.
+// The scaled frames are made again from the BGRA one after a change.
+#[derive(Default)]
+struct LastFrame {
+    bgra: Vec<u8>,
+    i420: Vec<u8>,
+    i444: Vec<u8>,
+    // by scale and 4:4:4
+    scaled: HashMap<(u32, bool), Vec<u8>>,
+    scaled_bgra: Vec<u8>,
+}
+
+impl LastFrame {
+    fn is_empty(&self) -> bool {
+        self.i420.is_empty()
+    }
+
+    // None if the frame at this scale is not made yet
+    fn get(&self, scale: u32, yuv444: bool) -> Option<&[u8]> {
+        if scale < 100 {
+            self.scaled.get(&(scale, yuv444)).map(|x| &x[..])
+        } else if yuv444 {
+            Some(&self.i444)
+        } else {
+            Some(&self.i420)
+        }
+    }
+
+    // scale the BGRA frame for the tiers lacking theirs
+    fn prepare(&mut self, width: usize, height: usize, tiers: &HashMap<TierKey, Tier>) {
+        if self.bgra.is_empty() {
+            return;
+        }
+        for tier in tiers.values().filter(|t| t.scale < 100) {
+            let key = (tier.scale, tier.yuv444);
+            if self.scaled.contains_key(&key) {
+                continue;
+            }
+            let (w, h) = (tier.width, tier.height);
+            scrap::bgra_scale(width, height, &self.bgra, w, h, &mut self.scaled_bgra);
+            let mut frame = Vec::new();
+            if tier.yuv444 {
+                scrap::bgra_to_i444(w, h, &self.scaled_bgra, &mut frame);
+            } else {
+                scrap::bgra_to_i420(w, h, &self.scaled_bgra, &mut frame);
+            }
+            self.scaled.insert(key, frame);
+        }
+    }
+
+    // copy the changed tiles of `frame`, I420 or BGRA if `bgra`, and mark them dirty
+    // in every tier, returns false if nothing changed. The tiles are compared here unless
+    // the capturer gave them as `changed`.
+    fn update(
+        &mut self,
+        frame: &[u8],
+        width: usize,
+        height: usize,
+        bgra: bool,
+        changed: Option<&[Rect]>,
+        tiers: &mut HashMap<TierKey, Tier>,
+    ) -> bool {
+        let rects = if bgra {
+            let stride = frame.len() / height;
+            let rects = match changed {
+                Some(rects) => {
+                    scrap::copy_tiles(&mut self.bgra, frame, width, height, stride, rects)
+                }
+                None => scrap::diff_tiles(&mut self.bgra, frame, width, height, stride),
+            };
+            if !rects.is_empty() {
+                scrap::bgra_to_i420_rects(width, height, &self.bgra, &mut self.i420, &rects);
+                scrap::bgra_to_i444_rects(width, height, &self.bgra, &mut self.i444, &rects);
+            }
+            rects
+        } else {
+            match changed {
+                Some(rects) => scrap::i420_copy_tiles(width, height, &mut self.i420, frame, rects),
+                None => scrap::i420_diff_tiles(width, height, &mut self.i420, frame),
+            }
+        };
+        if !rects.is_empty() {
+            self.scaled.clear();
+        }
+        for tier in tiers.values_mut() {
+            tier.add_dirty(&rects);
+        }
+        !rects.is_empty()
+    }
+}
+
+pub fn set_show_all_displays(id: i32, on: bool) {
+    let mut lock = ALL_DISPLAYS_CONN_IDS.lock().unwrap();
+    if on {
+        lock.insert(id);
+    } else {
+        lock.remove(&id);
+    }
+}
+
+// One captured display with its own encoders, tagged with `display` in the frames it sends.
+struct Pipeline {
+    display: usize,
+    capturer: Box<dyn TraitCapturer>,
+    width: usize,
+    height: usize,
+    // captured in BGRA for a 4:4:4 or a scaled tier
+    bgra: bool,
+    tiers: HashMap<TierKey, Tier>,
+    last_frame: LastFrame,
+    last_change: Instant,
+    #[cfg(windows)]
+    try_gdi: i32,
+}
+
+impl Pipeline {
+    fn new(privacy_mode_id: i32, display: usize, bgra: bool) -> ResultType<Self> {
+        let d = match try_get_displays()?.into_iter().nth(display) {
+            Some(d) => d,
+            None => bail!("No display {}", display),
+        };
+        let (width, height) = (d.width(), d.height());
+        log::debug!(
+            "display {}: origin: {:?}, width={}, height={}",
+            display,
+            d.origin(),
+            width,
+            height
+        );
+        // a tier in 4:4:4 or scaled needs the screen in BGRA, the capturers give I420 otherwise
+        let capturer = create_capturer(privacy_mode_id, d, !bgra)?;
+        #[cfg(windows)]
+        log::info!("gdi of display {}: {}", display, capturer.is_gdi());
+        Ok(Self {
+            display,
+            capturer,
+            width,
+            height,
+            bgra,
+            tiers: HashMap::new(),
+            last_frame: LastFrame::default(),
+            last_change: Instant::now(),
+            #[cfg(windows)]
+            try_gdi: 1,
+        })
+    }
+}
+
+// regroup the subscribers after one of them changed its image quality, frame rate or codec
+fn update_tiers(
+    tiers: &mut HashMap<TierKey, Tier>,
+    conn_ids: Vec<i32>,
+    width: usize,
+    height: usize,
+) -> ResultType<()> {
+    let mut groups: HashMap<TierKey, HashSet<i32>> = HashMap::new();
+    for id in conn_ids {
+        groups.entry(get_tier_key(id)).or_default().insert(id);
+    }
+    tiers.retain(|key, _| groups.contains_key(key));
+    for (key, conn_ids) in groups {
+        if !tiers.contains_key(&key) {
+            tiers.insert(key, Tier::new(key, width, height)?);
+        }
+        if let Some(tier) = tiers.get_mut(&key) {
+            // a connection coming from another tier can not decode this stream before a key frame
+            if conn_ids.iter().any(|id| !tier.conn_ids.contains(id)) {
+                tier.set_key_frame();
+            }
+            tier.conn_ids = conn_ids;
+        }
+    }
+    Ok(())
+}
+
 pub fn new() -> GenericService {
     let sp = GenericService::new(NAME, true);
     sp.run(run);
@@ -172,7 +633,7 @@ pub fn new() -> GenericService {
 
 fn check_display_changed(
     last_n: usize,
-    last_current: usize,
+    last_display: usize,
     last_width: usize,
     last_hegiht: usize,
 ) -> bool {
@@ -186,24 +647,18 @@ fn check_display_changed(
         return true;
     };
 
-    for (i, d) in displays.iter().enumerate() {
-        if d.is_primary() {
-            if i != last_current {
-                return true;
-            };
-            if d.width() != last_width || d.height() != last_hegiht {
-                return true;
-            };
-        }
+    match displays.get(last_display) {
+        Some(d) => d.width() != last_width || d.height() != last_hegiht,
+        None => true,
     }
-
-    return false;
 }
 
 // Capturer object is expensive, avoiding to create it frequently.
-fn create_capturer(privacy_mode_id: i32, display: Display) -> ResultType<Box<dyn TraitCapturer>> {
-    let use_yuv = true;
-
+fn create_capturer(
+    privacy_mode_id: i32,
+    display: Display,
+    use_yuv: bool,
+) -> ResultType<Box<dyn TraitCapturer>> {
     #[cfg(not(windows))]
     let c: Option<Box<dyn TraitCapturer>> = None;
     #[cfg(windows)]
@@ -264,6 +719,11 @@ fn create_capturer(privacy_mode_id: i32, display: Display) -> ResultType<Box<dyn
         None => {
             let c1 =
                 Capturer::new(display, use_yuv).with_context(|| "Failed to create capturer")?;
+            #[cfg(all(target_os = "linux", feature = "wayland"))]
+            if crate::platform::linux_wayland::is_wayland() {
+                log::debug!("Create capturer pipewire");
+                return Ok(Box::new(c1));
+            }
             log::debug!("Create capturer dxgi|gdi");
             Box::new(c1)
         }
@@ -291,8 +751,9 @@ fn ensure_close_virtual_device() -> ResultType<()> {
 pub fn test_create_capturer(privacy_mode_id: i32, timeout_millis: u64) -> bool {
     let test_begin = Instant::now();
     while test_begin.elapsed().as_millis() < timeout_millis as _ {
-        if let Ok((_, _, display)) = get_current_display() {
-            if let Ok(_) = create_capturer(privacy_mode_id, display) {
+        let primary = get_primary();
+        if let Some(display) = try_get_displays().ok().and_then(|d| d.into_iter().nth(primary)) {
+            if let Ok(_) = create_capturer(privacy_mode_id, display, true) {
                 return true;
             }
         }
@@ -320,18 +781,15 @@ fn run(sp: GenericService) -> ResultType<()> {
     #[cfg(windows)]
     ensure_close_virtual_device()?;
 
-    let fps = 30;
-    let wait = 1000 / fps;
-    let spf = time::Duration::from_secs_f32(1. / (fps as f32));
-    let (ndisplay, current, display) = get_current_display()?;
-    let (origin, width, height) = (display.origin(), display.width(), display.height());
+    let (primary, displays) = get_display_infos()?;
+    let ndisplay = displays.len();
+    if ndisplay == 0 {
+        bail!("No displays");
+    }
     log::debug!(
-        "#displays={}, current={}, origin: {:?}, width={}, height={}, cpus={}/{}",
+        "#displays={}, primary={}, cpus={}/{}",
         ndisplay,
-        current,
-        &origin,
-        width,
-        height,
+        primary,
         num_cpus::get_physical(),
         num_cpus::get(),
     );
@@ -355,52 +813,19 @@ fn run(sp: GenericService) -> ResultType<()> {
     } else {
         log::info!("In privacy mode, the peer side cannot watch the screen");
     }
-    let mut c = create_capturer(captuerer_privacy_mode_id, display)?;
 
-    let q = get_image_quality();
-    let (bitrate, rc_min_quantizer, rc_max_quantizer, speed) = get_quality(width, height, q);
-    log::info!("bitrate={}, rc_min_quantizer={}", bitrate, rc_min_quantizer);
-    let cfg = Config {
-        width: width as _,
-        height: height as _,
-        timebase: [1, 1000], // Output timestamp precision
-        bitrate,
-        codec: VideoCodecId::VP9,
-        rc_min_quantizer,
-        rc_max_quantizer,
-        speed,
-    };
-    let mut vpx;
-    match Encoder::new(&cfg, (num_cpus::get() / 2) as _) {
-        Ok(x) => vpx = x,
-        Err(err) => bail!("Failed to create encoder: {}", err),
-    }
-
-    if *SWITCH.lock().unwrap() {
-        log::debug!("Broadcasting display switch");
-        let mut misc = Misc::new();
-        misc.set_switch_display(SwitchDisplay {
-            display: current as _,
-            x: origin.0 as _,
-            y: origin.1 as _,
-            width: width as _,
-            height: height as _,
-            ..Default::default()
-        });
-        let mut msg_out = Message::new();
-        msg_out.set_misc(misc);
-        *SWITCH.lock().unwrap() = false;
-        sp.send(msg_out);
-    }
+    let mut pipelines: Vec<Pipeline> = Vec::new();
+    // the display and scale each connection was told about last
+    let mut conn_displays: HashMap<i32, (usize, u32)> = HashMap::new();
+    // after a switch, every connection is told about its display again
+    let mut broadcast = *SWITCH.lock().unwrap();
+    *SWITCH.lock().unwrap() = false;
+    QUEUE_FULL_COUNTS.lock().unwrap().clear();
 
     let mut frame_controller = VideoFrameController::new();
 
     let start = time::Instant::now();
     let mut last_check_displays = time::Instant::now();
-    #[cfg(windows)]
-    let mut try_gdi = 1;
-    #[cfg(windows)]
-    log::info!("gdi: {}", c.is_gdi());
     while sp.ok() {
         #[cfg(windows)]
         check_uac_switch(privacy_mode_id, captuerer_privacy_mode_id)?;
@@ -408,14 +833,71 @@ fn run(sp: GenericService) -> ResultType<()> {
         if *SWITCH.lock().unwrap() {
             bail!("SWITCH");
         }
-        if current != *CURRENT_DISPLAY.lock().unwrap() {
-            *SWITCH.lock().unwrap() = true;
-            bail!("SWITCH");
-        }
         check_privacy_mode_changed(&sp, privacy_mode_id)?;
-        if get_image_quality() != q {
-            bail!("SWITCH");
+
+        // which connections watch each display
+        let conn_ids = sp.subscriber_ids();
+        let all_displays_conn_ids = ALL_DISPLAYS_CONN_IDS.lock().unwrap().clone();
+        let mut watchers: HashMap<usize, Vec<i32>> = HashMap::new();
+        for &id in conn_ids.iter() {
+            let i = get_conn_display(id, primary, ndisplay);
+            let scale = get_image_scale(id);
+            let last = match conn_displays.get(&id) {
+                Some(last) => Some(*last),
+                // a new connection got its display with the peer info, at full size
+                None if !broadcast => Some((i, 100)),
+                None => None,
+            };
+            if last != Some((i, scale)) {
+                log::info!("Connection {} switched to display {} at {}%", id, i, scale);
+                sp.send_to(make_switch_display_msg(i, &displays[i], scale), id);
+            }
+            if conn_displays.insert(id, (i, scale)) != Some((i, scale)) {
+                set_scaled_display(id, &displays[i], scale);
+            }
+            if all_displays_conn_ids.contains(&id) {
+                for j in 0..ndisplay {
+                    watchers.entry(j).or_default().push(id);
+                }
+            } else {
+                watchers.entry(i).or_default().push(id);
+            }
+        }
+        conn_displays.retain(|id, _| conn_ids.contains(id));
+        broadcast = false;
+
+        // one pipeline per display watched
+        pipelines.retain(|p| watchers.contains_key(&p.display));
+        for (&i, ids) in watchers.iter() {
+            let bgra = ids.iter().any(|id| {
+                let key = get_tier_key(*id);
+                is_yuv444(key.quality) || key.scale < 100
+            });
+            if let Some(k) = pipelines
+                .iter()
+                .position(|p| p.display == i && p.bgra != bgra)
+            {
+                log::info!("Capture format of display {} changed, recreate capturer", i);
+                pipelines.remove(k);
+            }
+            if !pipelines.iter().any(|p| p.display == i) {
+                pipelines.push(Pipeline::new(captuerer_privacy_mode_id, i, bgra)?);
+            }
         }
+        for p in pipelines.iter_mut() {
+            let ids = watchers.get(&p.display).cloned().unwrap_or_default();
+            update_tiers(&mut p.tiers, ids, p.width, p.height)?;
+        }
+
+        // capture as often as the fastest tier needs
+        let spf = pipelines
+            .iter()
+            .flat_map(|p| p.tiers.values())
+            .map(|t| t.qos.spf())
+            .min()
+            .unwrap_or(Duration::from_millis(1000 / 30));
+        // the displays share the wait for a new frame
+        let wait = spf.as_millis() as u32 / std::cmp::max(pipelines.len(), 1) as u32;
         #[cfg(windows)]
         {
             if crate::platform::windows::desktop_changed() {
@@ -432,95 +914,143 @@ fn run(sp: GenericService) -> ResultType<()> {
             }
         }
 
-        *LAST_ACTIVE.lock().unwrap() = now;
+        let time = now - start;
+        let ms = (time.as_secs() * 1000 + time.subsec_millis() as u64) as i64;
 
-        frame_controller.reset();
-
-        #[cfg(any(target_os = "android", target_os = "ios"))]
-        let res = match (*c).frame(wait as _) {
-            Ok(frame) => {
-                let time = now - start;
-                let ms = (time.as_secs() * 1000 + time.subsec_millis() as u64) as i64;
-                match frame {
-                    scrap::Frame::VP9(data) => {
-                        let send_conn_ids = handle_one_frame_encoded(&sp, data, ms)?;
-                        frame_controller.set_send(now, send_conn_ids);
-                    }
-                    scrap::Frame::RAW(data) => {
-                        if (data.len() != 0) {
-                            let send_conn_ids = handle_one_frame(&sp, data, ms, &mut vpx)?;
-                            frame_controller.set_send(now, send_conn_ids);
-                        }
-                    }
-                    _ => {}
-                };
-                Ok(())
+        let key_frame_conn_ids = std::mem::take(&mut *KEY_FRAME_CONN_IDS.lock().unwrap());
+        for tier in pipelines.iter_mut().flat_map(|p| p.tiers.values_mut()) {
+            if !tier.conn_ids.is_disjoint(&key_frame_conn_ids) {
+                log::debug!("Keyframe requested for {:?}", tier.conn_ids);
+                tier.set_key_frame();
             }
-            Err(err) => Err(err),
-        };
+        }
 
-        #[cfg(not(any(target_os = "android", target_os = "ios")))]
-        let res = match (*c).frame(wait as _) {
-            Ok(frame) => {
-                let time = now - start;
-                let ms = (time.as_secs() * 1000 + time.subsec_millis() as u64) as i64;
-                let send_conn_ids = handle_one_frame(&sp, &frame, ms, &mut vpx)?;
-                frame_controller.set_send(now, send_conn_ids);
-                #[cfg(windows)]
-                {
-                    try_gdi = 0;
+        let waiting = frame_controller.waiting_conn_ids();
+        let mut send_conn_ids = HashSet::new();
+        for p in pipelines.iter_mut() {
+            #[cfg(any(target_os = "android", target_os = "ios"))]
+            let res = match p.capturer.frame(wait as _) {
+                Ok(frame) => {
+                    match frame {
+                        scrap::Frame::VP9(data) => {
+                            let ids: HashSet<i32> = p
+                                .tiers
+                                .values()
+                                .flat_map(|t| t.conn_ids.iter().cloned())
+                                .collect();
+                            send_conn_ids.extend(handle_one_frame_encoded(
+                                &sp, data, ms, p.display, &ids,
+                            )?);
+                        }
+                        scrap::Frame::RAW(data) => {
+                            if (data.len() != 0) {
+                                let (w, h) = (p.width, p.height);
+                                if p.last_frame.update(data, w, h, false, None, &mut p.tiers) {
+                                    p.last_change = now;
+                                }
+                                send_conn_ids.extend(handle_one_frame(
+                                    &sp,
+                                    p,
+                                    ms,
+                                    now,
+                                    spf / 2,
+                                    &waiting,
+                                )?);
+                            }
+                        }
+                        _ => {}
+                    };
+                    Ok(())
                 }
-                Ok(())
-            }
-            Err(err) => Err(err),
-        };
+                Err(err) => Err(err),
+            };
 
-        match res {
-            Err(ref e) if e.kind() == WouldBlock =>
-            {
-                #[cfg(windows)]
-                if try_gdi > 0 && !c.is_gdi() {
-                    if try_gdi > 3 {
-                        c.set_gdi();
-                        try_gdi = 0;
-                        log::info!("No image, fall back to gdi");
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            let res = match p.capturer.frame(wait as _) {
+                Ok(frame) => {
+                    let (w, h, bgra) = (p.width, p.height, p.bgra);
+                    let changed = frame.changed_rects();
+                    if p.last_frame
+                        .update(&frame, w, h, bgra, changed, &mut p.tiers)
+                    {
+                        p.last_change = now;
                     }
-                    try_gdi += 1;
-                }
-            }
-            Err(err) => {
-                if check_display_changed(ndisplay, current, width, height) {
-                    log::info!("Displays changed");
-                    *SWITCH.lock().unwrap() = true;
-                    bail!("SWITCH");
+                    // give the capturer its buffer back before encoding
+                    drop(frame);
+                    send_conn_ids.extend(handle_one_frame(&sp, p, ms, now, spf / 2, &waiting)?);
+                    #[cfg(windows)]
+                    {
+                        p.try_gdi = 0;
+                    }
+                    Ok(())
                 }
+                Err(err) => Err(err),
+            };
 
-                #[cfg(windows)]
-                if !c.is_gdi() {
-                    c.set_gdi();
-                    log::info!("dxgi error, fall back to gdi: {:?}", err);
-                    continue;
+            match res {
+                Err(ref e) if e.kind() == WouldBlock => {
+                    // nothing changed, but a tier may still need a key frame or a lossless one
+                    if !p.last_frame.is_empty() {
+                        send_conn_ids.extend(handle_one_frame(&sp, p, ms, now, spf / 2, &waiting)?);
+                    }
+                    #[cfg(windows)]
+                    if p.try_gdi > 0 && !p.capturer.is_gdi() {
+                        if p.try_gdi > 3 {
+                            p.capturer.set_gdi();
+                            p.try_gdi = 0;
+                            log::info!("No image, fall back to gdi");
+                        }
+                        p.try_gdi += 1;
+                    }
                 }
+                Err(err) => {
+                    if check_display_changed(ndisplay, p.display, p.width, p.height) {
+                        log::info!("Displays changed");
+                        *SWITCH.lock().unwrap() = true;
+                        bail!("SWITCH");
+                    }
 
-                return Err(err.into());
+                    #[cfg(windows)]
+                    if !p.capturer.is_gdi() {
+                        p.capturer.set_gdi();
+                        log::info!("dxgi error, fall back to gdi: {:?}", err);
+                        continue;
+                    }
+
+                    return Err(err.into());
+                }
+                _ => {}
             }
-            _ => {}
         }
+        frame_controller.set_send(now, send_conn_ids);
 
-        let mut fetched_conn_ids = HashSet::new();
-        let timeout_millis = 3_000u64;
-        let wait_begin = Instant::now();
-        while wait_begin.elapsed().as_millis() < timeout_millis as _ {
+        // collect the acks until the next capture, a tier only waits for its own connections
+        while let Some(left) = spf.checked_sub(now.elapsed()) {
             check_privacy_mode_changed(&sp, privacy_mode_id)?;
             #[cfg(windows)]
             check_uac_switch(privacy_mode_id, captuerer_privacy_mode_id)?;
-            frame_controller.try_wait_next(&mut fetched_conn_ids, 300);
-            // break if all connections have received current frame
-            if fetched_conn_ids.len() >= frame_controller.send_conn_ids.len() {
+            if !frame_controller.try_wait_next(std::cmp::min(left, Duration::from_millis(300))) {
                 break;
             }
         }
+        let ack_delays = std::mem::take(&mut frame_controller.ack_delays);
+        for tier in pipelines.iter_mut().flat_map(|p| p.tiers.values_mut()) {
+            tier.ack_delays.extend(
+                tier.conn_ids
+                    .iter()
+                    .filter_map(|id| ack_delays.get(id).cloned()),
+            );
+            if tier.last_adjust.elapsed() >= video_qos::ADJUST_INTERVAL {
+                tier.adjust();
+            }
+        }
 
+        let last_change = pipelines.iter().map(|p| p.last_change).max();
+        let spf = if last_change.map(|x| x.elapsed() > IDLE_TIMEOUT) == Some(true) {
+            std::cmp::max(spf, IDLE_SPF)
+        } else {
+            spf
+        };
         let elapsed = now.elapsed();
         // may need to enable frame(timeout)
         log::trace!("{:?} {:?}", time::Instant::now(), elapsed);
@@ -547,34 +1077,64 @@ fn check_privacy_mode_changed(sp: &GenericService, privacy_mode_id: i32) -> Resu
 }
 
 #[inline]
-fn create_msg(vp9s: Vec<VP9>) -> Message {
+fn create_msg(codec: VideoCodecId, frames: Vec<EncodedFrame>, display: usize) -> Message {
     let mut msg_out = Message::new();
     let mut vf = VideoFrame::new();
-    vf.set_vp9s(VP9s {
-        frames: vp9s.into(),
-        ..Default::default()
-    });
+    match codec {
+        VideoCodecId::AV1 | VideoCodecId::H264 => {
+            let frames = EncodedVideoFrames {
+                frames: frames
+                    .into_iter()
+                    .map(|f| EncodedVideoFrame {
+                        data: f.data,
+                        key: f.key,
+                        pts: f.pts,
+                        ..Default::default()
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+                ..Default::default()
+            };
+            if codec == VideoCodecId::AV1 {
+                vf.set_av1s(frames);
+            } else {
+                vf.set_h264s(frames);
+            }
+        }
+        _ => {
+            vf.set_vp9s(VP9s {
+                frames: frames
+                    .into_iter()
+                    .map(|f| VP9 {
+                        data: f.data,
+                        key: f.key,
+                        pts: f.pts,
+                        ..Default::default()
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+                ..Default::default()
+            });
+        }
+    }
+    vf.display = display as _;
     vf.timestamp = crate::common::get_time();
     msg_out.set_video_frame(vf);
     msg_out
 }
 
-#[inline]
-fn create_frame(frame: &EncodeFrame) -> VP9 {
-    VP9 {
-        data: frame.data.to_vec(),
-        key: frame.key,
-        pts: frame.pts,
-        ..Default::default()
-    }
-}
-
+// encode the changes of the display captured at `now` for the tiers due by then, give or take
+// `slack`, each sent to its own connections. A tier with nothing new is skipped unless it
+// waits for a key frame, or is in 4:4:4 and the screen stayed static since its last lossy frame.
+// A tier with a connection in `waiting` still has a frame in flight and is skipped too.
 #[inline]
 fn handle_one_frame(
     sp: &GenericService,
-    frame: &[u8],
+    p: &mut Pipeline,
     ms: i64,
-    vpx: &mut Encoder,
+    now: Instant,
+    slack: Duration,
+    waiting: &HashSet<i32>,
 ) -> ResultType<HashSet<i32>> {
     sp.snapshot(|sps| {
         // so that new sub and old sub share the same encoder after switch
@@ -584,21 +1144,48 @@ fn handle_one_frame(
         Ok(())
     })?;
 
+    let idle = p.last_change.elapsed() >= LOSSLESS_DELAY;
+    p.last_frame.prepare(p.width, p.height, &p.tiers);
     let mut send_conn_ids: HashSet<i32> = Default::default();
-    let mut frames = Vec::new();
-    for ref frame in vpx
-        .encode(ms, frame, STRIDE_ALIGN)
-        .with_context(|| "Failed to encode")?
-    {
-        frames.push(create_frame(frame));
-    }
-    for ref frame in vpx.flush().with_context(|| "Failed to flush")? {
-        frames.push(create_frame(frame));
-    }
-
-    // to-do: flush periodically, e.g. 1 second
-    if frames.len() > 0 {
-        send_conn_ids = sp.send_video_frame(create_msg(frames));
+    for tier in p.tiers.values_mut() {
+        let refine = tier.yuv444 && idle && !tier.refined;
+        if tier.next_frame > now + slack || (!tier.key_frame && tier.dirty.is_empty() && !refine) {
+            continue;
+        }
+        if !tier.conn_ids.is_disjoint(waiting) {
+            continue;
+        }
+        let frame = match p.last_frame.get(tier.scale, tier.yuv444) {
+            Some(frame) => frame,
+            None => continue,
+        };
+        tier.next_frame = now + tier.qos.spf();
+        if tier.key_frame {
+            tier.encoder.set_key_frame();
+        }
+        tier.encoder
+            .set_lossless(refine)
+            .with_context(|| "Failed to set lossless")?;
+        // a key frame is always whole, and so is a lossless one
+        tier.encoder
+            .set_active_rects(if tier.key_frame || refine {
+                None
+            } else {
+                Some(&tier.dirty[..])
+            })
+            .with_context(|| "Failed to set active map")?;
+        let frames = tier
+            .encoder
+            .encode(ms, frame, STRIDE_ALIGN)
+            .with_context(|| "Failed to encode")?;
+
+        if frames.len() > 0 {
+            tier.key_frame = false;
+            tier.dirty.clear();
+            tier.refined = refine;
+            let msg = create_msg(tier.encoder.codec(), frames, p.display);
+            send_conn_ids.extend(sp.send_video_frame_to(msg, &tier.conn_ids));
+        }
     }
     Ok(send_conn_ids)
 }
@@ -609,6 +1196,8 @@ pub fn handle_one_frame_encoded(
     sp: &GenericService,
     frame: &[u8],
     ms: i64,
+    display: usize,
+    conn_ids: &HashSet<i32>,
 ) -> ResultType<HashSet<i32>> {
     sp.snapshot(|sps| {
         // so that new sub and old sub share the same encoder after switch
@@ -618,13 +1207,13 @@ pub fn handle_one_frame_encoded(
         Ok(())
     })?;
     let mut send_conn_ids: HashSet<i32> = Default::default();
-    let vp9_frame = VP9 {
+    let vp9_frame = EncodedFrame {
         data: frame.to_vec(),
         key: true,
         pts: ms,
-        ..Default::default()
     };
-    send_conn_ids = sp.send_video_frame(create_msg(vec![vp9_frame]));
+    let msg = create_msg(VideoCodecId::VP9, vec![vp9_frame], display);
+    send_conn_ids = sp.send_video_frame_to(msg, conn_ids);
     Ok(send_conn_ids)
 }
 
@@ -636,11 +1225,8 @@ fn get_display_num() -> usize {
     }
 }
 
-pub fn get_displays() -> ResultType<(usize, Vec<DisplayInfo>)> {
-    // switch to primary display if long time (30 seconds) no users
-    if LAST_ACTIVE.lock().unwrap().elapsed().as_secs() >= 30 {
-        *CURRENT_DISPLAY.lock().unwrap() = usize::MAX;
-    }
+// the primary display and all of them
+fn get_display_infos() -> ResultType<(usize, Vec<DisplayInfo>)> {
     let mut displays = Vec::new();
     let mut primary = 0;
     for (i, d) in try_get_displays()?.iter().enumerate() {
@@ -657,19 +1243,106 @@ pub fn get_displays() -> ResultType<(usize, Vec<DisplayInfo>)> {
             ..Default::default()
         });
     }
-    let mut lock = CURRENT_DISPLAY.lock().unwrap();
-    if *lock >= displays.len() {
-        *lock = primary
+    Ok((primary, displays))
+}
+
+fn get_conn_display(id: i32, primary: usize, n: usize) -> usize {
+    match CONN_DISPLAYS.lock().unwrap().get(&id) {
+        Some(i) if *i < n => *i,
+        _ => primary,
+    }
+}
+
+/// The display watched by connection `id` and all of them
+pub fn get_displays(id: i32) -> ResultType<(usize, Vec<DisplayInfo>)> {
+    let (primary, displays) = get_display_infos()?;
+    Ok((get_conn_display(id, primary, displays.len()), displays))
+}
+
+/// Connection `id` watches display `i` from now on, the video service tells it
+/// with a SwitchDisplay before its first frame
+pub fn switch_display(id: i32, i: i32) {
+    if let Ok((_, displays)) = get_display_infos() {
+        if i >= 0 && (i as usize) < displays.len() {
+            update_display(id, Some(i as _));
+        }
+    }
+}
+
+pub fn update_display(id: i32, display: Option<usize>) {
+    let mut lock = CONN_DISPLAYS.lock().unwrap();
+    match display {
+        Some(i) => {
+            lock.insert(id, i);
+        }
+        None => {
+            lock.remove(&id);
+        }
+    }
+}
+
+// the size is the scaled one, the origin stays
+fn make_switch_display_msg(i: usize, d: &DisplayInfo, scale: u32) -> Message {
+    let (width, height) = scaled_size(d.width as _, d.height as _, scale);
+    let mut misc = Misc::new();
+    misc.set_switch_display(SwitchDisplay {
+        display: i as _,
+        x: d.x,
+        y: d.y,
+        width: width as _,
+        height: height as _,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_misc(misc);
+    msg_out
+}
+
+fn set_scaled_display(id: i32, d: &DisplayInfo, scale: u32) {
+    let mut lock = SCALED_DISPLAYS.lock().unwrap();
+    if scale < 100 {
+        let (w, h) = scaled_size(d.width as _, d.height as _, scale);
+        lock.insert(id, (d.clone(), w as _, h as _));
+    } else {
+        lock.remove(&id);
     }
-    Ok((*lock, displays))
 }
 
-pub fn switch_display(i: i32) {
-    let i = i as usize;
-    if let Ok((_, displays)) = get_displays() {
-        if i < displays.len() {
-            *CURRENT_DISPLAY.lock().unwrap() = i;
+/// `scale` is the percentage of the native resolution asked by connection `id`,
+/// None or 100 for all of it
+pub fn update_image_scale(id: i32, scale: Option<u32>) {
+    let mut lock = IMAGE_SCALES.lock().unwrap();
+    match scale {
+        Some(scale) if scale < 100 => {
+            lock.insert(id, std::cmp::max(scale, MIN_SCALE));
         }
+        _ => {
+            lock.remove(&id);
+            SCALED_DISPLAYS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+/// Map a point in the downscaled display of connection `id` to the screen
+pub fn unscale_point(id: i32, x: i32, y: i32) -> (i32, i32) {
+    match SCALED_DISPLAYS.lock().unwrap().get(&id) {
+        Some((d, w, h)) if *w > 0 && *h > 0 => (
+            d.x + ((x - d.x) as i64 * d.width as i64 / *w as i64) as i32,
+            d.y + ((y - d.y) as i64 * d.height as i64 / *h as i64) as i32,
+        ),
+        _ => (x, y),
+    }
+}
+
+/// Map a point of the screen to the downscaled display of connection `id`,
+/// None if it watches the display at full size
+pub fn scale_point(id: i32, x: i32, y: i32) -> Option<(i32, i32)> {
+    match SCALED_DISPLAYS.lock().unwrap().get(&id) {
+        Some((d, w, h)) if d.width > 0 && d.height > 0 => Some((
+            d.x + ((x - d.x) as i64 * *w as i64 / d.width as i64) as i32,
+            d.y + ((y - d.y) as i64 * *h as i64 / d.height as i64) as i32,
+        )),
+        _ => None,
     }
 }
 
@@ -690,13 +1363,20 @@ fn get_primary() -> usize {
     0
 }
 
+// every connection goes back to the primary display
 pub fn switch_to_primary() {
-    switch_display(get_primary() as _);
+    CONN_DISPLAYS.lock().unwrap().clear();
 }
 
 #[cfg(not(windows))]
 fn try_get_displays() -> ResultType<Vec<Display>> {
-    Ok(Display::all()?)
+    // the displays are the streams of a portal session on Wayland, started with the token
+    #[cfg(all(target_os = "linux", feature = "wayland"))]
+    crate::platform::linux_wayland::load_restore_token();
+    let displays = Display::all()?;
+    #[cfg(all(target_os = "linux", feature = "wayland"))]
+    crate::platform::linux_wayland::save_restore_token();
+    Ok(displays)
 }
 
 #[cfg(windows)]
@@ -729,26 +1409,6 @@ fn try_get_displays() -> ResultType<Vec<Display>> {
     Ok(displays)
 }
 
-fn get_current_display() -> ResultType<(usize, usize, Display)> {
-    let mut current = *CURRENT_DISPLAY.lock().unwrap() as usize;
-    let mut displays = try_get_displays()?;
-    if displays.len() == 0 {
-        bail!("No displays");
-    }
-    let n = displays.len();
-    if current >= n {
-        current = 0;
-        for (i, d) in displays.iter().enumerate() {
-            if d.is_primary() {
-                current = i;
-                break;
-            }
-        }
-        *CURRENT_DISPLAY.lock().unwrap() = current;
-    }
-    return Ok((n, current, displays.remove(current)));
-}
-
 #[inline]
 fn update_latency(id: i32, latency: i64, latencies: &mut HashMap<i32, i64>) {
     if latency <= 0 {
@@ -762,7 +1422,21 @@ pub fn update_test_latency(id: i32, latency: i64) {
     update_latency(id, latency, &mut *TEST_LATENCIES.lock().unwrap());
 }
 
+// how many frames wait in the video queue of a connection, 0 once it is gone
+pub fn update_queue_depth(id: i32, depth: usize) {
+    let mut depths = VIDEO_QUEUE_DEPTHS.lock().unwrap();
+    if depth == 0 {
+        depths.remove(&id);
+    } else {
+        depths.insert(id, depth);
+    }
+}
+
 fn convert_quality(q: i32) -> i32 {
+    if q == ImageQuality::Lossless.value() {
+        // the lossy frames as good as the best quality, without a floor for the quantizer
+        return 100 << 8 | QUALITY_LOSSLESS;
+    }
     let q = {
         if q == ImageQuality::Balanced.value() {
             (100 * 2 / 3, 12)
@@ -799,14 +1473,48 @@ pub fn update_image_quality(id: i32, q: Option<i32>) {
     }
 }
 
-fn get_image_quality() -> i32 {
-    IMAGE_QUALITIES
-        .lock()
-        .unwrap()
-        .values()
-        .min()
-        .unwrap_or(&convert_quality(ImageQuality::Balanced.value()))
-        .clone()
+pub fn update_user_fps(id: i32, fps: Option<u32>) {
+    match fps {
+        Some(fps) if fps > 0 => {
+            USER_FPS.lock().unwrap().insert(id, fps);
+        }
+        _ => {
+            USER_FPS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+/// The codec to send to a peer decoding AV1 and H.264 as told, by "video-codec-preference"
+pub fn select_codec(av1: bool, h264: bool) -> VideoCodecId {
+    let preference = hbb_common::config::Config::get_option(OPTION_CODEC_PREFERENCE);
+    let preference = if preference.trim().is_empty() {
+        DEFAULT_CODEC_PREFERENCE.to_owned()
+    } else {
+        preference
+    };
+    for name in preference.split(',') {
+        let (codec, peer) = match name.trim().to_lowercase().as_str() {
+            "av1" => (VideoCodecId::AV1, av1),
+            "h264" => (VideoCodecId::H264, h264),
+            "vp9" => (VideoCodecId::VP9, true),
+            _ => continue,
+        };
+        if peer && scrap::is_codec_supported(codec) {
+            return codec;
+        }
+    }
+    VideoCodecId::VP9
+}
+
+pub fn update_codec(id: i32, codec: Option<VideoCodecId>) {
+    match codec {
+        Some(codec) => {
+            CODECS.lock().unwrap().insert(id, codec);
+        }
+        None => {
+            CODECS.lock().unwrap().remove(&id);
+        }
+    }
 }
 
 #[inline]
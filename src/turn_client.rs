@@ -1,4 +1,6 @@
-use hbb_common::{log, tcp::FramedStream, tokio::net::TcpStream, ResultType};
+use hbb_common::{
+    config::CONNECT_TIMEOUT, log, socket_client, tcp::FramedStream, ResultType,
+};
 use serde_json::Value;
 use std::{net::SocketAddr, sync::Arc};
 use turn::client::{tcp::TcpSplit, ClientConfig};
@@ -55,7 +57,9 @@ pub struct TurnClient {
 
 impl TurnClient {
     pub async fn new(config: TurnConfig) -> ResultType<Self> {
-        let tcp_split = TcpSplit::from(TcpStream::connect(&config.addr).await?);
+        let tcp_split = TcpSplit::from(
+            socket_client::connect_tcp_stream(&config.addr, CONNECT_TIMEOUT).await?,
+        );
         let mut client = turn::client::Client::new(ClientConfig {
             stun_serv_addr: config.addr.clone(),
             turn_serv_addr: config.addr,
@@ -7,13 +7,16 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 pub mod utils {
-    use crate::two_factor_auth::api_access;
     use hbb_common::config::Config;
     use hbb_common::rand::random;
+    use hmac::{Hmac, Mac};
     use image::{Luma, Rgba};
     use qrcode::QrCode;
     use sha2::{Digest, Sha256, Sha512};
 
+    // the approval page may only speak the old unkeyed hashes, which are refused unless this is "Y"
+    pub const OPTION_ALLOW_LEGACY_HASH: &'static str = "2fa-allow-legacy-hash";
+
     pub fn random_alphanum(length: usize) -> String {
         (0..)
             .map(|_| random::<char>())
@@ -26,8 +29,8 @@ pub mod utils {
         format!("https://www.hoptodesk.com/2fa/#{}", string)
     }
 
-    pub fn make_qr_code(string: &str) -> QrCode {
-        let link = make_2fa_link(string);
+    // the approval link, or the otpauth uri for an authenticator app
+    pub fn make_qr_code(link: &str) -> QrCode {
         QrCode::new(link).unwrap()
     }
 
@@ -53,25 +56,28 @@ pub mod utils {
         &get_secret() != ""
     }
 
+    // codes from an authenticator app checked locally, instead of the approval on the web page
+    pub fn is_totp_enabled() -> bool {
+        is_2fa_enabled() && Config::get_option("2fa-mode") == "totp"
+    }
+
     #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-    enum HashAlgorithm {
+    pub enum HashAlgorithm {
         MD5,
         SHA256,
         SHA512,
     }
 
-    impl From<&str> for HashAlgorithm {
-        fn from(x: &str) -> Self {
+    impl HashAlgorithm {
+        pub fn parse(x: &str) -> Option<Self> {
             match x {
-                "md5" | "MD5" => HashAlgorithm::MD5,
-                "sha256" | "SHA256" => HashAlgorithm::SHA256,
-                "sha512" | "SHA512" => HashAlgorithm::SHA512,
-                _ => HashAlgorithm::MD5,
+                "md5" | "MD5" => Some(HashAlgorithm::MD5),
+                "sha256" | "SHA256" => Some(HashAlgorithm::SHA256),
+                "sha512" | "SHA512" => Some(HashAlgorithm::SHA512),
+                _ => None,
             }
         }
-    }
 
-    impl HashAlgorithm {
         pub fn hash_str(&self, s: &str) -> String {
             match self {
                 HashAlgorithm::MD5 => format!("{:x}", md5::compute(s)),
@@ -81,20 +87,277 @@ pub mod utils {
         }
     }
 
-    pub async fn hash_str(s: &str) -> String {
-        HashAlgorithm::from(api_access::get_hash_algorithm().await.as_str()).hash_str(s)
+    /// Version of the challenge-response spoken with the approval page.
+    /// The version is part of the challenge sent, so the page never has to guess.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum ChallengeVersion {
+        // hash of the secret followed by the challenge, no version prefix
+        Legacy(HashAlgorithm),
+        // hex HMAC-SHA256 of the whole "v2:" challenge, keyed with the secret
+        V2,
+    }
+
+    impl ChallengeVersion {
+        pub fn make_challenge(&self, nonce: &str) -> String {
+            match self {
+                ChallengeVersion::Legacy(_) => nonce.to_owned(),
+                ChallengeVersion::V2 => format!("v2:{}", nonce),
+            }
+        }
+
+        pub fn answer(&self, secret: &str, challenge: &str) -> String {
+            match self {
+                ChallengeVersion::Legacy(algorithm) => {
+                    algorithm.hash_str(&format!("{}{}", secret, challenge))
+                }
+                ChallengeVersion::V2 => hmac_sha256_hex(secret.as_bytes(), challenge.as_bytes()),
+            }
+        }
+    }
+
+    /// Pick the challenge version from the versions the api says the approval page supports.
+    /// No list means an api from before the versions, whose page only knows the legacy hash.
+    /// Legacy is only used if `allow_legacy`, and MD5 if the api names no known algorithm.
+    pub fn negotiate_version(
+        versions: &[u32],
+        hash_algorithm: Option<&str>,
+        allow_legacy: bool,
+    ) -> Option<ChallengeVersion> {
+        if versions.contains(&2) {
+            return Some(ChallengeVersion::V2);
+        }
+        if !allow_legacy {
+            return None;
+        }
+        Some(ChallengeVersion::Legacy(
+            hash_algorithm
+                .and_then(HashAlgorithm::parse)
+                .unwrap_or(HashAlgorithm::MD5),
+        ))
+    }
+
+    pub fn hmac_sha256_hex(key: &[u8], data: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(data);
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    // so that the time taken does not tell how much of an answer was right
+    pub fn constant_time_eq(a: &str, b: &str) -> bool {
+        a.len() == b.len()
+            && a.bytes()
+                .zip(b.bytes())
+                .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+                == 0
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_negotiate_version() {
+            assert_eq!(negotiate_version(&[], None, false), None);
+            assert_eq!(negotiate_version(&[], Some("md5"), false), None);
+            assert_eq!(
+                negotiate_version(&[], None, true),
+                Some(ChallengeVersion::Legacy(HashAlgorithm::MD5))
+            );
+            assert_eq!(negotiate_version(&[1, 2], Some("md5"), false), Some(ChallengeVersion::V2));
+            assert_eq!(negotiate_version(&[1], Some("md5"), false), None);
+            assert_eq!(negotiate_version(&[1], Some("sha512"), false), None);
+            assert_eq!(
+                negotiate_version(&[1], Some("sha512"), true),
+                Some(ChallengeVersion::Legacy(HashAlgorithm::SHA512))
+            );
+            assert_eq!(
+                negotiate_version(&[1], Some("whatever"), true),
+                Some(ChallengeVersion::Legacy(HashAlgorithm::MD5))
+            );
+        }
+
+        #[test]
+        fn test_hmac_sha256() {
+            // RFC 4231 test case 2
+            assert_eq!(
+                hmac_sha256_hex(b"Jefe", b"what do ya want for nothing?"),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+            );
+            let v2 = ChallengeVersion::V2;
+            let challenge = v2.make_challenge("abc");
+            assert_eq!(challenge, "v2:abc");
+            assert_eq!(v2.answer("key", &challenge), hmac_sha256_hex(b"key", b"v2:abc"));
+            assert_ne!(
+                v2.answer("key", &challenge),
+                ChallengeVersion::Legacy(HashAlgorithm::SHA256).answer("key", &challenge)
+            );
+            assert!(constant_time_eq("abc", "abc"));
+            assert!(!constant_time_eq("abc", "abd"));
+            assert!(!constant_time_eq("abc", "ab"));
+        }
     }
+}
+
+// RFC 6238 time-based one-time passwords, 6 digits every 30 seconds with HMAC-SHA1,
+// which is what the authenticator apps support.
+pub mod totp {
+    use hbb_common::{config::Config, rand::random};
+    use hmac::{Hmac, Mac};
+    use sha1::Sha1;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use std::time::SystemTime;
+
+    const DIGITS: u32 = 6;
+    const PERIOD: u64 = 30;
+    // steps accepted before and after the current one, for clock drift
+    const DRIFT: u64 = 1;
+    const BASE32: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    lazy_static::lazy_static! {
+        // the steps of the accepted codes still in the drift window, so that a code can not be
+        // replayed while codes of other steps are still accepted
+        static ref USED_STEPS: Mutex<HashSet<u64>> = Default::default();
+    }
+
+    pub fn base32_encode(data: &[u8]) -> String {
+        let mut out = String::new();
+        let mut buf = 0u32;
+        let mut bits = 0;
+        for b in data {
+            buf = (buf << 8) | *b as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(BASE32[((buf >> bits) & 31) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            out.push(BASE32[((buf << (5 - bits)) & 31) as usize] as char);
+        }
+        out
+    }
+
+    pub fn base32_decode(s: &str) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut buf = 0u32;
+        let mut bits = 0;
+        for c in s.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+            let v = BASE32.iter().position(|x| *x as char == c.to_ascii_uppercase())?;
+            buf = (buf << 5) | v as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                out.push((buf >> bits) as u8);
+            }
+        }
+        Some(out)
+    }
+
+    pub fn generate_secret() -> String {
+        base32_encode(&(0..20).map(|_| random::<u8>()).collect::<Vec<u8>>())
+    }
+
+    pub fn make_uri(secret: &str) -> String {
+        let app = crate::get_app_name();
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&digits={}&period={}",
+            app,
+            Config::get_id(),
+            secret,
+            app,
+            DIGITS,
+            PERIOD
+        )
+    }
+
+    fn hotp(key: &[u8], counter: u64) -> u32 {
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(&counter.to_be_bytes());
+        let h = mac.finalize().into_bytes();
+        let offset = (h[h.len() - 1] & 0xf) as usize;
+        let bin = ((h[offset] as u32 & 0x7f) << 24)
+            | ((h[offset + 1] as u32) << 16)
+            | ((h[offset + 2] as u32) << 8)
+            | h[offset + 3] as u32;
+        bin % 10u32.pow(DIGITS)
+    }
+
+    fn make_code(key: &[u8], step: u64) -> String {
+        format!("{:0width$}", hotp(key, step), width = DIGITS as usize)
+    }
+
+    /// The step matching `code` around the time `now` in seconds
+    fn find_step(key: &[u8], code: &str, now: u64) -> Option<u64> {
+        let step = now / PERIOD;
+        (step.saturating_sub(DRIFT)..=step + DRIFT).find(|s| make_code(key, *s) == code)
+    }
+
+    /// Check a code against the configured secret, each code is accepted only once
+    pub fn verify_code(code: &str) -> bool {
+        let key = match base32_decode(&super::utils::get_secret()) {
+            Some(key) if !key.is_empty() => key,
+            _ => return false,
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        let step = match find_step(&key, code.trim(), now) {
+            Some(step) => step,
+            None => return false,
+        };
+        use_step(&mut USED_STEPS.lock().unwrap(), step, now / PERIOD)
+    }
+
+    /// Mark `step` as used, false if it already was
+    fn use_step(used: &mut HashSet<u64>, step: u64, current: u64) -> bool {
+        used.retain(|x| *x + DRIFT >= current);
+        used.insert(step)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_base32() {
+            let secret = base32_encode(b"12345678901234567890");
+            assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+            assert_eq!(
+                base32_decode(&secret.to_lowercase()).unwrap(),
+                b"12345678901234567890"
+            );
+            assert!(base32_decode("GEZ1").is_none());
+        }
+
+        #[test]
+        fn test_rfc6238() {
+            let key = b"12345678901234567890";
+            assert_eq!(make_code(key, 59 / PERIOD), "287082");
+            assert_eq!(make_code(key, 1111111109 / PERIOD), "081804");
+            assert_eq!(make_code(key, 1234567890 / PERIOD), "005924");
+            assert_eq!(find_step(key, "287082", 59 + PERIOD), Some(1));
+            assert_eq!(find_step(key, "287082", 59 + 3 * PERIOD), None);
+        }
 
-    pub async fn challenge_answer(challenge: &str) -> String {
-        let string = get_secret()
-            .chars()
-            .chain(challenge.chars())
-            .collect::<String>();
-        hash_str(&string).await
+        #[test]
+        fn test_use_step() {
+            let mut used = HashSet::new();
+            assert!(use_step(&mut used, 10, 10));
+            assert!(!use_step(&mut used, 10, 10));
+            assert!(use_step(&mut used, 9, 10));
+            assert!(use_step(&mut used, 11, 10));
+            assert!(!use_step(&mut used, 11, 11));
+            assert!(use_step(&mut used, 12, 12));
+            assert_eq!(used.len(), 2);
+        }
     }
 }
 
 mod api_access {
+    use crate::two_factor_auth::utils::*;
+    use hbb_common::config::Config;
     use serde_derive::{Deserialize, Serialize};
 
     #[derive(Deserialize)]
@@ -119,6 +382,8 @@ mod api_access {
     struct API2FA {
         hash_algorithm: Option<String>,
         ping_time: Option<f64>,
+        // challenge versions the approval page understands
+        versions: Option<Vec<u32>>,
     }
 
     #[derive(Deserialize)]
@@ -127,20 +392,22 @@ mod api_access {
         tfa: Option<API2FA>,
     }
 
-    pub async fn get_hash_algorithm() -> String {
-        let body = serde_json::from_value::<API2FAWrapper>(hbb_common::api::call_api().await.unwrap());
-		
-        body.map(|x| x.tfa.and_then(|x| x.hash_algorithm))
-            .unwrap_or(Some("md5".to_owned()))
-            .unwrap_or("md5".to_owned())
+    async fn get_2fa() -> Option<API2FA> {
+        let body = hbb_common::api::call_api().await.ok()?;
+        serde_json::from_value::<API2FAWrapper>(body).ok()?.tfa
     }
 
-    pub async fn get_ping_time() -> f64 {
-        let body = serde_json::from_value::<API2FAWrapper>(hbb_common::api::call_api().await.unwrap());
+    pub async fn get_challenge_version() -> Option<ChallengeVersion> {
+        let tfa = get_2fa().await;
+        negotiate_version(
+            &tfa.as_ref().and_then(|x| x.versions.clone()).unwrap_or_default(),
+            tfa.as_ref().and_then(|x| x.hash_algorithm.as_deref()),
+            Config::get_option(OPTION_ALLOW_LEGACY_HASH) == "Y",
+        )
+    }
 
-        body.map(|x| x.tfa.and_then(|x| x.ping_time))
-            .unwrap_or(Some(10.0))
-            .unwrap_or(10.0)
+    pub async fn get_ping_time() -> f64 {
+        get_2fa().await.and_then(|x| x.ping_time).unwrap_or(10.0)
     }
 }
 
@@ -148,7 +415,7 @@ pub mod sockets {
     use crate::two_factor_auth::api_access;
     use crate::two_factor_auth::utils::*;
     use futures::lock::Mutex;
-    use futures::{FutureExt, SinkExt, StreamExt, TryStreamExt};
+    use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
     use hbb_common::tokio::net::TcpStream;
     use hbb_common::tokio::time::sleep;
     use hbb_common::{log, tokio};
@@ -163,10 +430,9 @@ pub mod sockets {
 
     pub async fn create_socket() -> WebSocketStream<MaybeTlsStream<TcpStream>> {
         let websockets_uri = api_access::get_ws_uri().await;
-        let (socket, _) = tokio_tungstenite::connect_async(&websockets_uri)
+        crate::ws_tunnel::connect_websocket(&websockets_uri)
             .await
-            .unwrap();
-        socket
+            .unwrap()
     }
 
     #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -213,27 +479,55 @@ pub mod sockets {
                 return self.get_answer().await.unwrap(); // cannot panic
             }
 
+            let version = match api_access::get_challenge_version().await {
+                Some(version) => version,
+                None => {
+                    log::error!(
+                        "The approval page only supports legacy 2FA hashes, set {} to Y to allow them",
+                        OPTION_ALLOW_LEGACY_HASH
+                    );
+                    return self.finish(AuthAnswer::Denied).await;
+                }
+            };
+            let ping_time = api_access::get_ping_time().await;
+            self.check_with(create_socket().await, &get_secret(), version, ping_time)
+                .await
+        }
+
+        /// Run the challenge-response over an already connected websocket
+        pub async fn check_with<S>(
+            &mut self,
+            socket: S,
+            secret: &str,
+            version: ChallengeVersion,
+            ping_time: f64,
+        ) -> AuthAnswer
+        where
+            S: Stream<Item = Result<Message, Error>> + Sink<Message, Error = Error> + Send + 'static,
+        {
+            if self.is_finished().await {
+                return self.get_answer().await.unwrap(); // cannot panic
+            }
+
             let status = self.status.clone();
-            let final_answer = self.answer.clone();
-            let callbacks = self.callbacks.clone();
 
             // spawn a thread to check the challenge
             let fut = async move {
                 // activate
                 *status.lock().await.deref_mut() = CheckerStatus::Active;
 
-                let challenge = random_alphanum(16);
-                let answer = challenge_answer(&challenge).await;
-                let (mut write, read) = create_socket().await.split();
+                let challenge = version.make_challenge(&random_alphanum(16));
+                let answer = version.answer(secret, &challenge);
+                let deny = format!("-{answer}");
+                let (mut write, read) = socket.split();
 
                 let mut last_sent_challenge = Instant::now();
                 // send challenge
-                write.send(Message::Text(challenge.clone())).await.unwrap();
-                log::info!("Sent challenge!");
+                write.send(Message::Text(challenge.clone())).await.ok();
+                log::info!("Sent {:?} challenge!", version);
 
                 let success = Arc::new(Mutex::new(false));
                 let read_success = success.clone();
-                let read_answer = answer.clone();
                 let read_status = status.clone();
                 let write_success = success.clone();
                 let write_status = status.clone();
@@ -241,7 +535,6 @@ pub mod sockets {
                 let read_fut = async move {
                     let status = read_status;
                     let success = read_success;
-                    let answer = read_answer;
 
                     let mut fut = read.map(|message| async {
                         log::info!("Got message {:?}", message);
@@ -251,20 +544,20 @@ pub mod sockets {
                                 Some(false)
                             }
                             Ok(Message::Text(s)) // allow
-                                if !success.lock().await.clone() && s == answer =>
+                                if !success.lock().await.clone() && constant_time_eq(&s, &answer) =>
                             {
                                 log::info!("Got allow!");
                                 *status.lock().await.deref_mut() = CheckerStatus::Inactive;
                                 Some(true)
                             }
                             Ok(Message::Text(s)) // deny
-                                if !success.lock().await.clone() && s == format!("-{answer}") => {
+                                if !success.lock().await.clone() && constant_time_eq(&s, &deny) => {
                                 log::info!("Got deny!");
                                 *status.lock().await.deref_mut() = CheckerStatus::Inactive;
                                 Some(false)
                             }
-                            Ok(Message::Text(s)) => {
-                                log::info!("Got wrong answer {}, expected {}.", s, answer);
+                            Ok(Message::Text(_)) => {
+                                log::info!("Got wrong answer");
                                 None
                             }
                             Ok(Message::Ping(_)) => {
@@ -287,11 +580,16 @@ pub mod sockets {
 
                     // break on Some
                     loop {
-                        let next = fut.next().await;
-
-                        if let Some(x) = next {
-                            if let Some(x) = x.await {
-                                out = x;
+                        match fut.next().await {
+                            Some(x) => {
+                                if let Some(x) = x.await {
+                                    out = x;
+                                    break;
+                                }
+                            }
+                            None => {
+                                log::info!("Websocket closed before an answer");
+                                *status.lock().await.deref_mut() = CheckerStatus::Inactive;
                                 break;
                             }
                         }
@@ -316,7 +614,7 @@ pub mod sockets {
                         if Instant::now()
                             .duration_since(last_sent_challenge)
                             .as_secs_f64()
-                            >= api_access::get_ping_time().await
+                            >= ping_time
                         {
                             // send challenge
                             if let Ok(()) = write.send(Message::Text(challenge.clone())).await {
@@ -338,27 +636,30 @@ pub mod sockets {
                 let succ = succ.unwrap_or(false);
                 *success.lock().await.deref_mut() = succ;
 
-                let answer = if succ {
+                if succ {
                     AuthAnswer::Allowed
                 } else {
                     AuthAnswer::Denied
-                };
+                }
+            };
 
-                *final_answer.lock().await.deref_mut() = Some(answer);
+            let answer = fut.await;
+            self.finish(answer).await
+        }
 
-                log::info!("Resolving callbacks...");
-                {
-                    let mut callbacks = callbacks.lock().await;
-                    for callback in callbacks.drain(0..).collect::<Vec<_>>() {
-                        callback(answer);
-                    }
-                }
-                log::info!("Callbacks resolved!");
+        async fn finish(&self, answer: AuthAnswer) -> AuthAnswer {
+            *self.answer.lock().await.deref_mut() = Some(answer);
 
-                answer
-            };
+            log::info!("Resolving callbacks...");
+            {
+                let mut callbacks = self.callbacks.lock().await;
+                for callback in callbacks.drain(0..).collect::<Vec<_>>() {
+                    callback(answer);
+                }
+            }
+            log::info!("Callbacks resolved!");
 
-            fut.await
+            answer
         }
 
         pub async fn stop_checking(&mut self) {
@@ -383,6 +684,98 @@ pub mod sockets {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use hbb_common::tokio::net::TcpListener;
+
+        const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+        // Stand-in for the approval page: answers every challenge with what `reply` makes of it,
+        // then closes the websocket once the replies are out.
+        async fn run_checker(
+            version: ChallengeVersion,
+            reply: impl Fn(&str) -> Vec<String> + Send + 'static,
+        ) -> (AuthAnswer, Vec<String>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                let mut challenges = Vec::new();
+                // the first challenge is ignored, to get it sent again after ping_time
+                while let Some(Ok(Message::Text(challenge))) = ws.next().await {
+                    challenges.push(challenge.clone());
+                    if challenges.len() < 2 {
+                        continue;
+                    }
+                    for x in reply(&challenge) {
+                        ws.send(Message::Text(x)).await.unwrap();
+                    }
+                    break;
+                }
+                ws.close(None).await.ok();
+                challenges
+            });
+            let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+                .await
+                .unwrap();
+            let mut checker = TFAChecker::new();
+            let callback_answer = Arc::new(std::sync::Mutex::new(None));
+            let cb = callback_answer.clone();
+            checker
+                .add_callback(Box::new(move |x| *cb.lock().unwrap() = Some(x)))
+                .await;
+            let answer = checker.check_with(socket, SECRET, version, 0.05).await;
+            assert_eq!(*callback_answer.lock().unwrap(), Some(answer));
+            assert_eq!(checker.get_answer().await, Some(answer));
+            (answer, server.await.unwrap())
+        }
+
+        #[tokio::test]
+        async fn test_checker_allow() {
+            let version = ChallengeVersion::V2;
+            let (answer, challenges) = run_checker(version, move |c| {
+                vec!["wrong".to_owned(), version.answer(SECRET, c)]
+            })
+            .await;
+            assert_eq!(answer, AuthAnswer::Allowed);
+            assert!(challenges[0].starts_with("v2:"));
+            assert_eq!(challenges[0], challenges[1]);
+        }
+
+        #[tokio::test]
+        async fn test_checker_deny() {
+            let version = ChallengeVersion::V2;
+            let (answer, _) =
+                run_checker(version, move |c| vec![format!("-{}", version.answer(SECRET, c))])
+                    .await;
+            assert_eq!(answer, AuthAnswer::Denied);
+        }
+
+        #[tokio::test]
+        async fn test_checker_no_downgrade() {
+            // an unkeyed hash of the challenge is not accepted as a v2 answer
+            let (answer, _) = run_checker(ChallengeVersion::V2, |c| {
+                vec![
+                    HashAlgorithm::MD5.hash_str(&format!("{}{}", SECRET, c)),
+                    HashAlgorithm::SHA256.hash_str(&format!("{}{}", SECRET, c)),
+                ]
+            })
+            .await;
+            assert_eq!(answer, AuthAnswer::Denied);
+        }
+
+        #[tokio::test]
+        async fn test_checker_legacy() {
+            let version = ChallengeVersion::Legacy(HashAlgorithm::MD5);
+            let (answer, challenges) =
+                run_checker(version, move |c| vec![version.answer(SECRET, c)]).await;
+            assert_eq!(answer, AuthAnswer::Allowed);
+            assert!(!challenges[0].starts_with("v2:"));
+        }
+    }
 }
 
 lazy_static::lazy_static! {
@@ -394,8 +787,13 @@ pub struct TFAManager {
 }
 
 impl TFAManager {
+    // approval through the web page
     pub fn is_2fa_enabled() -> bool {
-        utils::is_2fa_enabled()
+        utils::is_2fa_enabled() && !utils::is_totp_enabled()
+    }
+
+    pub fn is_totp_enabled() -> bool {
+        utils::is_totp_enabled()
     }
 
     fn new() -> Self {
@@ -485,7 +883,7 @@ pub mod ui {
                 "is_2fa_enabled" => Some(Value::from(is_2fa_enabled())),
                 "enable_2fa" => {
                     let secret = random_alphanum(32);
-                    let qr = make_qr_code(&secret);
+                    let qr = make_qr_code(&make_2fa_link(&secret));
                     let qr_base64_img = qr_code_to_raw_img(qr);
 
                     let mut out = Value::map();
@@ -493,12 +891,29 @@ pub mod ui {
                     out.set_item("link", make_2fa_link(&secret));
 
                     Config::set_option("2fa-secret".to_owned(), secret);
+                    Config::set_option("2fa-mode".to_owned(), "".to_owned());
+
+                    Some(out)
+                }
+                "is_totp_enabled" => Some(Value::from(is_totp_enabled())),
+                "enable_totp" => {
+                    let secret = super::totp::generate_secret();
+                    let qr = make_qr_code(&super::totp::make_uri(&secret));
+                    let qr_base64_img = qr_code_to_raw_img(qr);
+
+                    let mut out = Value::map();
+                    out.set_item("img_base64", qr_base64_img);
+                    out.set_item("secret", secret.clone());
+
+                    Config::set_option("2fa-secret".to_owned(), secret);
+                    Config::set_option("2fa-mode".to_owned(), "totp".to_owned());
 
                     Some(out)
                 }
                 "disable_2fa" => {
                     // forget secret
                     Config::set_option("2fa-secret".to_owned(), "".to_owned());
+                    Config::set_option("2fa-mode".to_owned(), "".to_owned());
 
                     Some(Value::null())
                 }
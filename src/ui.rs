@@ -217,6 +217,16 @@ impl UI {
         }
     }
 
+    fn new_one_time_password(&mut self, minutes: i32) -> String {
+        match ipc::new_one_time_password(std::cmp::max(minutes, 0) as _) {
+            Ok(password) => password,
+            Err(err) => {
+                log::error!("Failed to generate one-time password: {}", err);
+                "".to_owned()
+            }
+        }
+    }
+
     fn get_remote_id(&mut self) -> String {
         LocalConfig::get_remote_id()
     }
@@ -332,6 +342,27 @@ impl UI {
         hbb_common::socket_client::test_if_valid_server(&host)
     }
 
+    fn test_if_valid_access_control(&self, rules: String) -> String {
+        match crate::server::acl::parse(&rules) {
+            Ok(_) => "".to_owned(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn test_if_valid_approval_rules(&self, rules: String) -> String {
+        match crate::server::approval::parse(&rules) {
+            Ok(_) => "".to_owned(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn test_if_valid_access_schedule(&self, schedule: String) -> String {
+        match crate::server::schedule::parse(&schedule) {
+            Ok(_) => "".to_owned(),
+            Err(err) => err.to_string(),
+        }
+    }
+
     fn get_sound_inputs(&self) -> Value {
         let mut a = Value::array(0);
         #[cfg(windows)]
@@ -424,6 +455,29 @@ impl UI {
         .ok();
     }
 
+    fn get_http_proxy(&self) -> Value {
+        let s = ipc::get_http_proxy();
+        match s {
+            None => Value::null(),
+            Some(s) => {
+                let mut v = Value::array(0);
+                v.push(s.proxy);
+                v.push(s.username);
+                v.push(s.password);
+                v
+            }
+        }
+    }
+
+    fn set_http_proxy(&self, proxy: String, username: String, password: String) {
+        ipc::set_http_proxy(config::HttpProxyServer {
+            proxy,
+            username,
+            password,
+        })
+        .ok();
+    }
+
     fn is_installed(&self) -> bool {
         crate::platform::is_installed()
     }
@@ -745,6 +799,10 @@ impl UI {
     fn is_2fa_enabled(&self) -> bool {
         crate::two_factor_auth::utils::is_2fa_enabled()
     }
+
+    fn is_totp_enabled(&self) -> bool {
+        crate::two_factor_auth::utils::is_totp_enabled()
+    }
 }
 
 impl sciter::EventHandler for UI {
@@ -754,6 +812,7 @@ impl sciter::EventHandler for UI {
         fn get_id();
         fn get_password();
         fn update_password(String);
+        fn new_one_time_password(i32);
         fn get_remote_id();
         fn set_remote_id(String);
         fn closing(i32, i32, i32, i32);
@@ -773,6 +832,8 @@ impl sciter::EventHandler for UI {
         fn is_installed();
         fn set_socks(String, String, String);
         fn get_socks();
+        fn set_http_proxy(String, String, String);
+        fn get_http_proxy();
         fn is_rdp_service_open();
         fn is_share_rdp();
         fn set_share_rdp(bool);
@@ -796,6 +857,9 @@ impl sciter::EventHandler for UI {
         fn forget_password(String);
         fn set_peer_option(String, String, String);
         fn test_if_valid_server(String);
+        fn test_if_valid_access_control(String);
+        fn test_if_valid_access_schedule(String);
+        fn test_if_valid_approval_rules(String);
         fn get_sound_inputs();
         fn set_options(Value);
         fn set_option(String, String);
@@ -816,6 +880,7 @@ impl sciter::EventHandler for UI {
         fn get_config_option(String);
         fn set_config_option(String, String);
 		fn is_2fa_enabled();
+		fn is_totp_enabled();
     }
 }
 
@@ -102,6 +102,7 @@ impl ConnectionManager {
         clipboard: bool,
         audio: bool,
         file: bool,
+        credential: String,
         tx: mpsc::UnboundedSender<Data>,
     ) {
         self.call(
@@ -116,7 +117,8 @@ impl ConnectionManager {
                 keyboard,
                 clipboard,
                 audio,
-                file
+                file,
+                credential
             ),
         );
         self.write().unwrap().senders.insert(id, tx);
@@ -153,6 +155,9 @@ impl ConnectionManager {
             Data::ClickTime(ms) => {
                 self.write().unwrap().click_time = ms;
             }
+            Data::RequestControl(None) => {
+                self.call("requestControl", &make_args!(id));
+            }
             Data::FS(v) => match v {
                 ipc::FS::ReadDir {
                     dir,
@@ -405,6 +410,13 @@ impl ConnectionManager {
         }
     }
 
+    fn respond_control(&self, id: i32, approved: bool) {
+        let lock = self.read().unwrap();
+        if let Some(s) = lock.senders.get(&id) {
+            allow_err!(s.send(Data::RequestControl(Some(approved))));
+        }
+    }
+
     fn close(&self, id: i32) {
         let lock = self.read().unwrap();
         if let Some(s) = lock.senders.get(&id) {
@@ -431,7 +443,8 @@ impl ConnectionManager {
     }
 	#[cfg(not(any(target_os = "android", target_os = "ios")))]
     fn is_2fa_enabled(&self) -> bool {
-        two_factor_auth::utils::is_2fa_enabled()
+        // TOTP codes are checked at login, only web approval needs the CM
+        TFAManager::is_2fa_enabled()
     }
 
 	#[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -483,6 +496,7 @@ impl sciter::EventHandler for ConnectionManager {
         fn close(i32);
         fn authorize(i32);
         fn switch_permission(i32, String, bool);
+        fn respond_control(i32, bool);
         fn send_msg(i32, String);
         fn is_2fa_enabled();
         fn get_2fa_answer(Value);
@@ -540,12 +554,12 @@ async fn start_ipc(cm: ConnectionManager) {
                                                     Data::TFA { id, answer } => {
                                                         log::info!("bad TFA: conn_id_set: {conn_id_set}, conn_id: {conn_id}, id: {id}, answer: {answer}");
                                                     }
-                                                    Data::Login{id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, file_transfer_enabled} => {
+                                                    Data::Login{id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, file_transfer_enabled, credential} => {
                                                         log::debug!("conn_id: {}", id);
                                                         conn_id = id;
                                                         conn_id_set = true;														
                                                         tx_file.send(ClipboardFileData::Enable((id, file_transfer_enabled))).ok();
-                                                        cm.add_connection(id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, tx.clone());
+                                                        cm.add_connection(id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, credential, tx.clone());
                                                         if let Some(answer) = tfas.get(&conn_id) {
                                                             log::info!("found tfa: {conn_id} - {answer}");
                                                             cm.update_2fa_answer(*answer);
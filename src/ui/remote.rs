@@ -48,9 +48,14 @@ use hbb_common::{config::TransferSerde, fs::TransferJobMeta};
 use crate::clipboard_file::*;
 use crate::{
     client::*,
-    common::{self, check_clipboard, update_clipboard, ClipboardContext, CLIPBOARD_INTERVAL}
+    common::{
+        self, check_clipboard, update_clipboard, ClipboardContext, SessionStats,
+        CLIPBOARD_INTERVAL,
+    },
 };
 
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
 type Video = AssetPtr<video_destination>;
 
 lazy_static::lazy_static! {
@@ -179,6 +184,7 @@ impl sciter::EventHandler for Handler {
         fn is_port_forward();
         fn is_rdp();
         fn login(String, bool);
+        fn login_2fa(String);
         fn new_rdp();
         fn send_mouse(i32, i32, i32, bool, bool, bool, bool);
         fn enter();
@@ -221,9 +227,13 @@ impl sciter::EventHandler for Handler {
         fn save_view_style(String);
         fn save_image_quality(String);
         fn save_custom_image_quality(i32, i32);
+        fn save_custom_fps(i32);
+        fn save_image_scale(i32);
         fn refresh_video();
+        fn request_control();
         fn get_toggle_option(String);
         fn is_privacy_mode_supported();
+        fn is_all_displays_supported();
         fn toggle_option(String);
         fn get_remember();
         fn peer_platform();
@@ -516,6 +526,9 @@ impl Handler {
         if let Some(msg) = msg {
             self.send(Data::Message(msg));
         }
+        if name == "show-all-displays" {
+            self.send(Data::ToggleAllDisplays);
+        }
     }
 
     fn get_toggle_option(&mut self, name: String) -> bool {
@@ -526,10 +539,22 @@ impl Handler {
         self.lc.read().unwrap().is_privacy_mode_supported()
     }
 
+    fn is_all_displays_supported(&self) -> bool {
+        self.lc.read().unwrap().is_all_displays_supported()
+    }
+
     fn refresh_video(&mut self) {
         self.send(Data::Message(LoginConfigHandler::refresh()));
     }
 
+    fn request_control(&mut self) {
+        let mut misc = Misc::new();
+        misc.set_request_control(true);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
     fn save_custom_image_quality(&mut self, bitrate: i32, quantizer: i32) {
         let msg = self
             .lc
@@ -539,6 +564,16 @@ impl Handler {
         self.send(Data::Message(msg));
     }
 
+    fn save_custom_fps(&mut self, fps: i32) {
+        let msg = self.lc.write().unwrap().save_custom_fps(fps);
+        self.send(Data::Message(msg));
+    }
+
+    fn save_image_scale(&mut self, scale: i32) {
+        let msg = self.lc.write().unwrap().save_image_scale(scale);
+        self.send(Data::Message(msg));
+    }
+
     fn save_image_quality(&mut self, value: String) {
         let msg = self.lc.write().unwrap().save_image_quality(value);
         if let Some(msg) = msg {
@@ -840,6 +875,10 @@ impl Handler {
         self.send(Data::Login((password, remember)));
     }
 
+    fn login_2fa(&mut self, code: String) {
+        self.send(Data::Login2FA(code));
+    }
+
     fn new_rdp(&mut self) {
         self.send(Data::NewRDP);
     }
@@ -1278,6 +1317,10 @@ async fn io_loop(handler: Handler) {
         timer: time::interval(SEC30),
         last_update_jobs_status: (Instant::now(), Default::default()),
         first_frame: false,
+        stats: Default::default(),
+        stats_timer: time::interval(STATS_INTERVAL),
+        displays: Default::default(),
+        current_display: 0,
         #[cfg(windows)]
         clipboard_file_context: None,
     };
@@ -1328,6 +1371,10 @@ struct Remote {
     timer: Interval,
     last_update_jobs_status: (Instant, HashMap<i32, u64>),
     first_frame: bool,
+    stats: SessionStats,
+    stats_timer: Interval,
+    displays: Vec<DisplayInfo>,
+    current_display: usize,
     #[cfg(windows)]
     clipboard_file_context: Option<Box<CliprdrClientContext>>,
 }
@@ -1348,6 +1395,10 @@ impl Remote {
                 SERVER_FILE_TRANSFER_ENABLED.store(true, Ordering::SeqCst);
                 self.handler
                     .call("setConnectionType", &make_args!(peer.is_secured(), direct));
+                self.stats.conn_id = std::process::id() as _;
+                self.stats.peer_id = self.handler.id.clone();
+                self.stats.controlling = true;
+                self.stats.direct = direct;
 
                 // just build for now
                 #[cfg(not(windows))]
@@ -1411,6 +1462,9 @@ impl Remote {
                                 self.timer = time::interval_at(Instant::now() + SEC30, SEC30);
                             }
                         }
+                        _ = self.stats_timer.tick() => {
+                            self.update_stats(&peer);
+                        }
                     }
                 }
                 log::debug!("Exit io_loop of id={}", self.handler.id);
@@ -1428,6 +1482,25 @@ impl Remote {
         SERVER_FILE_TRANSFER_ENABLED.store(false, Ordering::SeqCst);
     }
 
+    fn update_stats(&mut self, peer: &Stream) {
+        self.stats.update(peer.bytes_sent(), peer.bytes_received());
+        if self
+            .handler
+            .lc
+            .read()
+            .unwrap()
+            .get_toggle_option("show-quality-monitor")
+        {
+            let stats = serde_json::to_string(&self.stats).unwrap_or_default();
+            self.handler.call("updateQualityStatus", &make_args!(stats));
+        }
+        // for --session-stats, the server process keeps them
+        let stats = self.stats.clone();
+        tokio::spawn(async move {
+            crate::ipc::send_client_session_stats(stats).await.ok();
+        });
+    }
+
     fn handle_job_status(&mut self, id: i32, file_num: i32, err: Option<String>) {
         if let Some(job) = self.remove_jobs.get_mut(&id) {
             if job.no_confirm {
@@ -1555,9 +1628,17 @@ impl Remote {
                     .handle_login_from_ui(password, remember, peer)
                     .await;
             }
+            Data::Login2FA(code) => {
+                handle_login_2fa(self.handler.lc.clone(), code, peer).await;
+            }
             Data::ToggleClipboardFile => {
                 self.check_clipboard_file_context();
             }
+            Data::ToggleAllDisplays => {
+                self.update_all_displays();
+                // the decoders start over
+                allow_err!(peer.send(&LoginConfigHandler::refresh()).await);
+            }
             Data::Message(msg) => {
                 allow_err!(peer.send(&msg).await);
             }
@@ -1933,6 +2014,7 @@ impl Remote {
                         self.handler.call2("closeSuccess", &make_args!());
                         self.handler.call("adaptSize", &make_args!());
                     }
+                    self.stats.on_video_frame(&vf);
                     self.video_sender.send(MediaData::VideoFrame(vf)).ok();
                 }
                 Some(message::Union::hash(hash)) => {
@@ -1945,7 +2027,12 @@ impl Remote {
                         }
                     }
                     Some(login_response::Union::peer_info(pi)) => {
+                        self.displays = pi.displays.to_vec();
+                        self.current_display = pi.current_display as _;
                         self.handler.handle_peer_info(pi);
+                        if self.is_showing_all_displays() {
+                            self.update_all_displays();
+                        }
                         self.check_clipboard_file_context();
                         if !(self.handler.is_file_transfer()
                             || self.handler.is_port_forward()
@@ -2181,6 +2268,11 @@ impl Remote {
                     }
                     Some(misc::Union::switch_display(s)) => {
                         self.handler.call("switchDisplay", &make_args!(s.display));
+                        self.current_display = s.display as _;
+                        if self.is_showing_all_displays() {
+                            // every display is already on the screen
+                            return true;
+                        }
                         self.video_sender.send(MediaData::Reset).ok();
                         if s.width > 0 && s.height > 0 {
                             VIDEO.lock().unwrap().as_mut().map(|v| {
@@ -2199,6 +2291,9 @@ impl Remote {
                         self.handler.msgbox("error", "Connection Error", &c);
                         return false;
                     }
+                    Some(misc::Union::control_response(approved)) => {
+                        self.handler.call2("controlResponse", &make_args!(approved));
+                    }
                     Some(misc::Union::back_notification(notification)) => {
                         if !self.handle_back_notification(notification).await {
                             return false;
@@ -2207,6 +2302,9 @@ impl Remote {
                     _ => {}
                 },
                 Some(message::Union::test_delay(t)) => {
+                    if !t.from_client {
+                        self.stats.rtt = t.last_delay;
+                    }
                     self.handler.handle_test_delay(t, peer).await;
                 }
                 Some(message::Union::audio_frame(frame)) => {
@@ -2349,6 +2447,32 @@ impl Remote {
         true
     }
 
+    fn is_showing_all_displays(&self) -> bool {
+        let lc = self.handler.lc.read().unwrap();
+        self.displays.len() > 1
+            && lc.is_all_displays_supported()
+            && lc.get_toggle_option("show-all-displays")
+    }
+
+    // show all the displays side by side in the view, or the current one only
+    fn update_all_displays(&mut self) {
+        let (displays, (x, y, w, h)) = if self.is_showing_all_displays() {
+            (self.displays.clone(), displays_bounds(&self.displays))
+        } else {
+            match self.displays.get(self.current_display) {
+                Some(d) => (Vec::new(), (d.x, d.y, d.width, d.height)),
+                None => return,
+            }
+        };
+        self.video_sender.send(MediaData::AllDisplays(displays)).ok();
+        VIDEO.lock().unwrap().as_mut().map(|v| {
+            v.stop_streaming().ok();
+            let ok = v.start_streaming((w, h), COLOR_SPACE::Rgb32, None);
+            log::info!("[video] reinitialized: {:?}", ok);
+        });
+        self.handler.set_display(x, y, w, h);
+    }
+
     fn check_clipboard_file_context(&mut self) {
         #[cfg(windows)]
         {
@@ -2429,6 +2553,7 @@ impl Interface for Handler {
         pi_sciter.set_item("hostname", pi.hostname.clone());
         pi_sciter.set_item("platform", pi.platform.clone());
         pi_sciter.set_item("sas_enabled", pi.sas_enabled);
+        pi_sciter.set_item("view_only", pi.view_only);
         if self.is_file_transfer() {
             if pi.username.is_empty() {
                 self.on_error("No active console user logged on, please connect and logon first.");
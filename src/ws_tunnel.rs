@@ -0,0 +1,344 @@
+// Session stream tunneled in a websocket through a relay, the last resort for networks
+// where only HTTP(S) egress is allowed. Both sides connect to the same relay uri,
+// the relay pairs them by the session query and forwards binary messages between them.
+// Only wss relays are used, and a uri given by the rendezvous server is joined only if it
+// is on one of them, not to let it make us connect anywhere.
+
+use futures::{ready, Sink, Stream, StreamExt};
+use hbb_common::{
+    allow_err, bail,
+    config::{Config, CONNECT_TIMEOUT},
+    log, socket_client,
+    tcp::FramedStream,
+    timeout,
+    tokio::{
+        self,
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+    },
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+pub const WS_RELAY_PORT: u16 = 21119;
+const PAIR_TIMEOUT: u64 = 30_000;
+
+type PendingMap = Arc<Mutex<HashMap<String, oneshot::Sender<WebSocketStream<TcpStream>>>>>;
+
+/// Byte stream over the binary messages of a websocket
+pub struct WsStream<S> {
+    ws: WebSocketStream<S>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(ws: WebSocketStream<S>) -> Self {
+        Self {
+            ws,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+fn to_io_error(err: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.pos < self.buf.len() {
+                let n = std::cmp::min(buf.remaining(), self.buf.len() - self.pos);
+                let pos = self.pos;
+                buf.put_slice(&self.buf[pos..pos + n]);
+                self.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut self.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                // eof
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(to_io_error(err))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.ws).poll_ready(cx)).map_err(to_io_error)?;
+        Pin::new(&mut self.ws)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws).poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws).poll_close(cx).map_err(to_io_error)
+    }
+}
+
+async fn connect(
+    uri: &str,
+) -> ResultType<(
+    WebSocketStream<MaybeTlsStream<TcpStream>>,
+    SocketAddr,
+    SocketAddr,
+)> {
+    let parsed: tokio_tungstenite::tungstenite::http::Uri = uri.parse()?;
+    let host = parsed.host().unwrap_or_default();
+    let port = parsed
+        .port_u16()
+        .unwrap_or(if parsed.scheme_str() == Some("wss") { 443 } else { 80 });
+    let stream =
+        socket_client::connect_tcp_stream(&format!("{}:{}", host, port), CONNECT_TIMEOUT).await?;
+    let local_addr = stream.local_addr()?;
+    let peer_addr = stream.peer_addr()?;
+    let (ws, _) = timeout(
+        CONNECT_TIMEOUT,
+        tokio_tungstenite::client_async_tls(uri, stream),
+    )
+    .await??;
+    Ok((ws, local_addr, peer_addr))
+}
+
+/// Websocket client connection, through the proxy resolved for the uri.
+pub async fn connect_websocket(
+    uri: &str,
+) -> ResultType<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    Ok(connect(uri).await?.0)
+}
+
+/// "ws-relay-server" option (separated by ;) first, then the ones from the api, wss only
+async fn get_relay_servers() -> Vec<String> {
+    let mut servers: Vec<String> = Config::get_option("ws-relay-server")
+        .split(';')
+        .map(|x| x.trim().trim_end_matches('/').to_owned())
+        .filter(|x| !x.is_empty())
+        .collect();
+    if let Ok(map) = hbb_common::api::call_api().await {
+        if let Some(v) = map["wsrelayservers"].as_array() {
+            for x in v.iter().filter_map(|x| x.as_str()) {
+                servers.push(x.trim().trim_end_matches('/').to_owned());
+            }
+        }
+    }
+    servers.retain(|x| {
+        let ok = x.starts_with("wss://");
+        if !ok {
+            log::warn!("Ignore websocket relay {}, only wss is supported", x);
+        }
+        ok
+    });
+    servers
+}
+
+fn is_tunnel_uri(uri: &str, servers: &[String]) -> bool {
+    servers.iter().any(|server| {
+        uri.strip_prefix(server.as_str())
+            .and_then(|x| x.strip_prefix("/?session="))
+            .map(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+            .unwrap_or(false)
+    })
+}
+
+/// Connect to the tunnel `uri`, returns the stream and the relay address.
+async fn connect_tunnel(uri: &str) -> ResultType<(FramedStream, SocketAddr)> {
+    let (ws, local_addr, peer_addr) = connect(uri).await?;
+    Ok((FramedStream::from(WsStream::new(ws), local_addr), peer_addr))
+}
+
+/// Join the tunnel `uri` opened by the peer, if it is on one of our relays.
+pub async fn join_tunnel(uri: &str) -> ResultType<(FramedStream, SocketAddr)> {
+    if !is_tunnel_uri(uri, &get_relay_servers().await) {
+        bail!("Not on a known websocket relay");
+    }
+    connect_tunnel(uri).await
+}
+
+/// Open a new tunnel on the first reachable relay, returns its uri for the peer to join.
+pub async fn new_tunnel() -> ResultType<(String, FramedStream)> {
+    let session = uuid::Uuid::new_v4().to_string();
+    for server in get_relay_servers().await {
+        let uri = format!("{}/?session={}", server, session);
+        match connect_tunnel(&uri).await {
+            Ok((stream, _)) => return Ok((uri, stream)),
+            Err(err) => log::warn!("Failed to connect websocket relay {}: {}", server, err),
+        }
+    }
+    bail!("No websocket relay server available");
+}
+
+#[tokio::main]
+pub async fn run_relay(port: u16) -> ResultType<()> {
+    start_relay(port).await
+}
+
+/// Serve as the websocket relay, e.g. behind a TLS terminating reverse proxy.
+pub async fn start_relay(port: u16) -> ResultType<()> {
+    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    log::info!("websocket relay listening on {}", port);
+    let pending: PendingMap = Default::default();
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let pending = pending.clone();
+        tokio::spawn(async move {
+            allow_err!(handle_relay_connection(stream, addr, pending).await);
+        });
+    }
+}
+
+async fn handle_relay_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    pending: PendingMap,
+) -> ResultType<()> {
+    stream.set_nodelay(true).ok();
+    let mut session = String::new();
+    let ws = timeout(
+        CONNECT_TIMEOUT,
+        tokio_tungstenite::accept_hdr_async(
+            stream,
+            |req: &Request, res: Response| -> Result<Response, ErrorResponse> {
+                session = req
+                    .uri()
+                    .query()
+                    .unwrap_or_default()
+                    .split('&')
+                    .filter_map(|x| x.strip_prefix("session="))
+                    .next()
+                    .unwrap_or_default()
+                    .to_owned();
+                Ok(res)
+            },
+        ),
+    )
+    .await??;
+    if session.is_empty() {
+        bail!("Websocket relay connection from {} without session", addr);
+    }
+    let rx = {
+        let mut lock = pending.lock().unwrap();
+        match lock.remove(&session) {
+            Some(tx) => Err(tx),
+            None => {
+                let (tx, rx) = oneshot::channel();
+                lock.insert(session.clone(), tx);
+                Ok(rx)
+            }
+        }
+    };
+    // the other side is waiting, hand over and let it do the forwarding
+    let rx = match rx {
+        Ok(rx) => rx,
+        Err(tx) => {
+            log::info!("websocket relay session {} paired by {}", session, addr);
+            if tx.send(ws).is_err() {
+                bail!("Websocket relay session {} already closed", session);
+            }
+            return Ok(());
+        }
+    };
+    let other = match timeout(PAIR_TIMEOUT, rx).await {
+        Ok(Ok(other)) => other,
+        _ => {
+            pending.lock().unwrap().remove(&session);
+            bail!("Websocket relay session {} not paired", session);
+        }
+    };
+    let (a_tx, a_rx) = ws.split();
+    let (b_tx, b_rx) = other.split();
+    tokio::select! {
+        res = a_rx.forward(b_tx) => allow_err!(res),
+        res = b_rx.forward(a_tx) => allow_err!(res),
+    }
+    log::info!("websocket relay session {} closed", session);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_is_tunnel_uri() {
+        let servers = vec!["wss://relay.example.com".to_owned()];
+        let ok = |uri: &str| is_tunnel_uri(uri, &servers);
+        assert!(ok("wss://relay.example.com/?session=8e5f-11"));
+        assert!(!ok("ws://relay.example.com/?session=1"));
+        assert!(!ok("wss://relay.example.com.evil.org/?session=1"));
+        assert!(!ok("wss://relay.example.com@evil.org/?session=1"));
+        assert!(!ok("wss://relay.example.com/?session="));
+        assert!(!ok("wss://relay.example.com/?session=1&x=@evil.org"));
+    }
+
+    #[tokio::test]
+    async fn test_relay_pairing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let pending: PendingMap = Default::default();
+            loop {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let pending = pending.clone();
+                tokio::spawn(async move {
+                    allow_err!(handle_relay_connection(stream, addr, pending).await);
+                });
+            }
+        });
+        let uri = format!("ws://127.0.0.1:{}/?session=test", port);
+        let mut sides = Vec::new();
+        for _ in 0..2 {
+            let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let (ws, _) = tokio_tungstenite::client_async(uri.as_str(), stream)
+                .await
+                .unwrap();
+            sides.push(WsStream::new(ws));
+        }
+        let mut b = sides.pop().unwrap();
+        let mut a = sides.pop().unwrap();
+        a.write_all(b"hello").await.unwrap();
+        a.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        b.write_all(b"world").await.unwrap();
+        b.flush().await.unwrap();
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+}